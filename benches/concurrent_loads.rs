@@ -0,0 +1,138 @@
+//! Throughput of concurrent requests for one prefix, with the loader behind
+//! a per-prefix write lock, the way requests used to be serialised, and with
+//! the loader shared between requests directly. Measured for local images,
+//! which are bound by decoding and so gain with the number of cores, and for
+//! a proxy prefix whose upstream is slow to respond.
+//!
+//! Run with `cargo bench --bench concurrent_loads`.
+
+use axum::{Router, http::header, response::IntoResponse, routing::get};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use iiirs::image_loader::{
+    GenericImageLoader, ImageLoader, LocalLoader, ProxyConfig, ProxyLoader,
+    UpstreamPolicy,
+};
+use image::{DynamicImage, ImageBuffer, ImageFormat, Rgb};
+use std::{
+    io::Cursor,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{sync::RwLock, task::JoinSet};
+
+const IMAGES: usize = 8;
+const TASKS: usize = 32;
+const REQUESTS_PER_TASK: usize = 8;
+const UPSTREAM_LATENCY: Duration = Duration::from_millis(20);
+
+fn local_loader(dir: &Path) -> (ImageLoader, Vec<String>) {
+    for i in 0..IMAGES {
+        ImageBuffer::from_fn(1024, 1024, |x, y| {
+            Rgb([(x ^ y) as u8, (x * y) as u8, (x + y + i as u32) as u8])
+        })
+        .save_with_format(dir.join(format!("{i}.png")), ImageFormat::Png)
+        .unwrap();
+    }
+    let mut local = LocalLoader::new();
+    local.insert_dir("bench", dir);
+    let identifiers = (0..IMAGES).map(|i| i.to_string()).collect();
+    (ImageLoader::Local(local), identifiers)
+}
+
+/// A proxy for an upstream that takes a while to answer, and forbids caching
+/// so that every request goes to it.
+async fn proxy_loader(cache_dir: &Path) -> (ImageLoader, Vec<String>) {
+    async fn image() -> impl IntoResponse {
+        tokio::time::sleep(UPSTREAM_LATENCY).await;
+        let mut png = Cursor::new(vec![]);
+        DynamicImage::new_rgb8(64, 64)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        (
+            [
+                (header::CONTENT_TYPE, "image/png"),
+                (header::CACHE_CONTROL, "no-store"),
+            ],
+            png.into_inner(),
+        )
+    }
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new().route("/{n}", get(image));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let proxy = ProxyLoader::new(ProxyConfig {
+        allow: UpstreamPolicy {
+            private_addresses: true,
+            ..Default::default()
+        },
+        ..ProxyConfig::new(cache_dir)
+    })
+    .unwrap();
+    let identifiers = (0..IMAGES)
+        .map(|i| {
+            let uri = format!("http://{addr}/{i}.png");
+            Base64UrlUnpadded::encode_string(uri.as_bytes())
+        })
+        .collect();
+    (ImageLoader::Proxy(proxy), identifiers)
+}
+
+/// Run all requests from `TASKS` concurrent tasks, each request holding
+/// `lock` for writing if given, and return how long they took.
+async fn run(
+    loader: &Arc<ImageLoader>,
+    identifiers: &Arc<Vec<String>>,
+    lock: Option<Arc<RwLock<()>>>,
+) -> Duration {
+    let start = Instant::now();
+    let mut tasks = JoinSet::new();
+    for task in 0..TASKS {
+        let loader = Arc::clone(loader);
+        let identifiers = Arc::clone(identifiers);
+        let lock = lock.clone();
+        tasks.spawn(async move {
+            for request in 0..REQUESTS_PER_TASK {
+                let identifier =
+                    &identifiers[(task + request) % identifiers.len()];
+                let _guard = match &lock {
+                    Some(lock) => Some(lock.write().await),
+                    None => None,
+                };
+                loader.get_image("bench", identifier).await.unwrap();
+            }
+        });
+    }
+    tasks.join_all().await;
+    start.elapsed()
+}
+
+async fn compare(name: &str, loader: ImageLoader, identifiers: Vec<String>) {
+    let loader = Arc::new(loader);
+    let identifiers = Arc::new(identifiers);
+    let requests = TASKS * REQUESTS_PER_TASK;
+    for (mode, lock) in [
+        ("serialised", Some(Arc::new(RwLock::new(())))),
+        ("concurrent", None),
+    ] {
+        let elapsed = run(&loader, &identifiers, lock).await;
+        println!(
+            "{name} {mode}: {requests} requests in {:.2}s, {:.1} requests/s",
+            elapsed.as_secs_f64(),
+            requests as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    let (loader, identifiers) = local_loader(dir.path());
+    compare("local", loader, identifiers).await;
+
+    let cache_dir = tempfile::tempdir().unwrap();
+    let (loader, identifiers) = proxy_loader(cache_dir.path()).await;
+    compare("proxy", loader, identifiers).await;
+}
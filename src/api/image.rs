@@ -7,7 +7,7 @@ use nom::{
     combinator::{all_consuming, map, map_res, opt, recognize},
     sequence::{preceded, separated_pair, terminated},
 };
-use std::{num::NonZeroU32, str::FromStr};
+use std::{fmt, num::NonZeroU32, str::FromStr};
 
 #[derive(Debug, PartialEq)]
 pub struct ImageRequest {
@@ -79,6 +79,23 @@ impl FromStr for ImageRequest {
     }
 }
 
+/// The request in canonical form, in which requests that differ only in how
+/// they are spelled, such as a rotation of 360 and one of 0, are equal.
+impl fmt::Display for ImageRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}/{}/{}.{}",
+            self.identifier,
+            self.region,
+            self.size,
+            self.rotation,
+            self.quality,
+            self.format.extensions_str()[0]
+        )
+    }
+}
+
 fn parse_image_request(input: &str) -> IResult<&str, ImageRequest> {
     let (i, identifier) =
         terminated(parse_identifier, tag("/")).parse(input)?;
@@ -177,6 +194,17 @@ impl FromStr for Region {
     }
 }
 
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => f.write_str("full"),
+            Self::Square => f.write_str("square"),
+            Self::Absolute { x, y, w, h } => write!(f, "{x},{y},{w},{h}"),
+            Self::Percent { x, y, w, h } => write!(f, "pct:{x},{y},{w},{h}"),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Default)]
 pub enum SizeKind {
     #[default]
@@ -205,8 +233,8 @@ fn parse_sizekind(input: &str) -> IResult<&str, SizeKind> {
             separated_pair(parse_nonzerou32, tag(","), parse_nonzerou32),
             |(w, h)| SizeKind::WidthHeight { w, h },
         ),
-        map(terminated(parse_nonzerou32, tag(",")), SizeKind::Height),
-        map(preceded(tag(","), parse_nonzerou32), SizeKind::Width),
+        map(terminated(parse_nonzerou32, tag(",")), SizeKind::Width),
+        map(preceded(tag(","), parse_nonzerou32), SizeKind::Height),
         map(preceded(tag("pct:"), parse_iiif_float), |pct| {
             SizeKind::Percent(pct)
         }),
@@ -238,6 +266,24 @@ impl FromStr for Size {
     }
 }
 
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.allow_upscale {
+            f.write_str("^")?;
+        }
+        if self.maintain_ratio {
+            f.write_str("!")?;
+        }
+        match self.kind {
+            SizeKind::Max => f.write_str("max"),
+            SizeKind::Width(w) => write!(f, "{w},"),
+            SizeKind::Height(h) => write!(f, ",{h}"),
+            SizeKind::Percent(pct) => write!(f, "pct:{pct}"),
+            SizeKind::WidthHeight { w, h } => write!(f, "{w},{h}"),
+        }
+    }
+}
+
 fn parse_quality(input: &str) -> IResult<&str, Quality> {
     alt((
         map(tag("color"), |_| Quality::Color),
@@ -257,6 +303,17 @@ impl FromStr for Quality {
     }
 }
 
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Color => "color",
+            Self::Gray => "gray",
+            Self::Bitonal => "bitonal",
+            Self::Default => "default",
+        })
+    }
+}
+
 fn parse_rotation_deg(input: &str) -> IResult<&str, RotationDeg> {
     alt((
         map(alt((tag("0"), tag("360"))), |_| RotationDeg::Deg0),
@@ -278,6 +335,20 @@ fn parse_rotation(input: &str) -> IResult<&str, Rotation> {
     .parse(input)
 }
 
+impl fmt::Display for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.mirror {
+            f.write_str("!")?;
+        }
+        f.write_str(match self.deg {
+            RotationDeg::Deg0 => "0",
+            RotationDeg::Deg90 => "90",
+            RotationDeg::Deg180 => "180",
+            RotationDeg::Deg270 => "270",
+        })
+    }
+}
+
 pub fn parse_format(input: &str) -> IResult<&str, ImageFormat> {
     map_res(alphanumeric1, |ext| {
         ImageFormat::from_extension(ext)
@@ -304,7 +375,7 @@ mod tests {
         );
 
         assert_eq!(
-            parse_rotation("!25"),
+            parse_rotation("!90"),
             Ok((
                 "",
                 Rotation {
@@ -318,4 +389,40 @@ mod tests {
         assert!(parse_rotation("-180").is_err());
         assert!(parse_rotation("45").is_err());
     }
+    #[test]
+    fn test_parse_size() {
+        let kind = |input| parse_size(input).unwrap().1.kind;
+        let n = |n: u32| NonZeroU32::new(n).unwrap();
+        assert_eq!(kind("200,"), SizeKind::Width(n(200)));
+        assert_eq!(kind(",100"), SizeKind::Height(n(100)));
+        assert_eq!(
+            kind("200,100"),
+            SizeKind::WidthHeight {
+                w: n(200),
+                h: n(100)
+            }
+        );
+
+        let size = parse_size("^!200,100").unwrap().1;
+        assert!(size.allow_upscale && size.maintain_ratio);
+    }
+
+    #[test]
+    fn test_canonical_request() {
+        let canonical =
+            |input: &str| input.parse::<ImageRequest>().unwrap().to_string();
+        for input in [
+            "a/full/max/0/default.jpg",
+            "a;2/square/^!200,100/!90/gray.png",
+            "a/0,10,20,30/pct:12.5/270/bitonal.webp",
+            "a/pct:0,0.5,50,50/,100/180/color.gif",
+            "a/full/200,/0/default.jpg",
+        ] {
+            assert_eq!(canonical(input), input);
+        }
+        assert_eq!(
+            canonical("a/pct:0.0,0,50.00,50/pct:50.0/360/default.jpeg"),
+            "a/pct:0,0,50,50/pct:50/0/default.jpg"
+        );
+    }
 }
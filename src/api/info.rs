@@ -1,6 +1,7 @@
-use image::DynamicImage;
 use serde::Serialize;
 
+use crate::image_loader::PAGE_SEPARATOR;
+
 static TYPE: &str = "ImageService3";
 static IMAGE_3_CONTEXT: &str = "http://iiif.io/api/image/3/context.json";
 static PROTOCOL: &str = "http://iiif.io/api/image";
@@ -37,19 +38,51 @@ enum ComplianceLevel {
 }
 
 impl ImageInfo {
-    pub fn new(prefix: &str, id: &str, image: &DynamicImage) -> Self {
-        let id = ["http://localhost:3000", prefix, id].join("/");
+    pub fn new(prefix: &str, id: &str, width: u32, height: u32) -> Self {
+        let id = image_id(prefix, id);
         Self {
             context: vec![IMAGE_3_CONTEXT.into()],
             id,
             type_: TYPE,
             protocol: PROTOCOL,
             profile: ComplianceLevel::Level2,
-            width: image.width(),
-            height: image.height(),
+            width,
+            height,
             max_width: MAX_WIDTH,
             max_height: MAX_HEIGHT,
             max_area: MAX_AREA,
         }
     }
 }
+
+/// The pages of a file, each with an image service of its own.
+#[derive(Serialize)]
+pub struct PageList {
+    pages: Vec<Page>,
+}
+
+#[derive(Serialize)]
+struct Page {
+    id: String,
+    width: u32,
+    height: u32,
+}
+
+impl PageList {
+    pub fn new(prefix: &str, id: &str, pages: &[(u32, u32)]) -> Self {
+        let pages = pages
+            .iter()
+            .zip(1..)
+            .map(|(&(width, height), page)| Page {
+                id: image_id(prefix, &format!("{id}{PAGE_SEPARATOR}{page}")),
+                width,
+                height,
+            })
+            .collect();
+        Self { pages }
+    }
+}
+
+fn image_id(prefix: &str, id: &str) -> String {
+    ["http://localhost:3000", prefix, id].join("/")
+}
@@ -0,0 +1,350 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use crate::image_loader::{
+    DEFAULT_EXTENSIONS, DerivativeCacheConfig, ProxyConfig, ResolverConfig,
+    S3Config,
+};
+
+const DEFAULT_CONFIG_FILE: &str = "iiirs.toml";
+const CONFIG_ENV_VAR: &str = "IIIRS_CONFIG";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_listen")]
+    pub listen: SocketAddr,
+    /// Where to serve the admin routes, which are not authenticated and
+    /// must not be reachable from the public. They are not served at all
+    /// if unset.
+    pub admin_listen: Option<SocketAddr>,
+    /// Most bytes of decoded images to keep in memory, shared by all
+    /// prefixes. 0 keeps none.
+    #[serde(default = "default_image_cache_bytes")]
+    pub image_cache_bytes: u64,
+    /// Where to keep encoded responses, if anywhere
+    pub derivative_cache: Option<DerivativeCacheConfig>,
+    /// Watch the directories local images are served from, including those
+    /// of chain tiers and resolved prefixes, so that images that change get
+    /// new entity tags and are not served from the caches any more
+    #[serde(default = "default_watch_local_dirs")]
+    pub watch_local_dirs: bool,
+    #[serde(default)]
+    pub prefixes: HashMap<String, PrefixConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum PrefixConfig {
+    Local {
+        dir: PathBuf,
+        /// File extensions to try, in order of priority, when an identifier
+        /// does not name a file directly.
+        #[serde(default = "default_extensions")]
+        extensions: Vec<String>,
+    },
+    Proxy(Box<ProxyConfig>),
+    S3(Box<S3Config>),
+    /// ZIP or TAR archives in `dir`, one per volume, whose members are
+    /// addressed as `volume~member`
+    Archive {
+        dir: PathBuf,
+        /// File extensions to try, in order of priority, when a member is
+        /// not named in full.
+        #[serde(default = "default_extensions")]
+        extensions: Vec<String>,
+    },
+    /// Other prefix configurations, tried in order until one of them has
+    /// the image
+    Chain {
+        tiers: Vec<PrefixConfig>,
+        /// Copy images found in a later tier into the first, which must be
+        /// local.
+        #[serde(default)]
+        promote: bool,
+    },
+    /// Another prefix configuration, serving images under identifiers that
+    /// are looked up in a table to find the keys it knows them by
+    Resolved {
+        resolver: ResolverConfig,
+        loader: Box<PrefixConfig>,
+    },
+    /// A loader registered in the `LoaderRegistry` the server is started
+    /// with, such as one defined by another crate
+    Plugin {
+        /// The name the loader is registered under
+        loader: String,
+        /// Passed to the loader as is
+        #[serde(default)]
+        options: toml::Table,
+    },
+}
+
+fn default_listen() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 3000))
+}
+
+fn default_image_cache_bytes() -> u64 {
+    512 * 1024 * 1024
+}
+
+fn default_watch_local_dirs() -> bool {
+    true
+}
+
+fn default_extensions() -> Vec<String> {
+    DEFAULT_EXTENSIONS.iter().map(|&ext| ext.into()).collect()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: default_listen(),
+            admin_listen: None,
+            image_cache_bytes: default_image_cache_bytes(),
+            derivative_cache: None,
+            watch_local_dirs: default_watch_local_dirs(),
+            prefixes: HashMap::from([
+                (
+                    String::from("test"),
+                    PrefixConfig::Local {
+                        dir: "./".into(),
+                        extensions: default_extensions(),
+                    },
+                ),
+                (
+                    String::from("proxy"),
+                    PrefixConfig::Proxy(Box::new(ProxyConfig::new(
+                        "./proxy_cache",
+                    ))),
+                ),
+            ]),
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from the file named by the first command line
+    /// argument or the `IIIRS_CONFIG` environment variable, falling back to
+    /// `iiirs.toml` in the working directory. If none of these exist, the
+    /// built-in defaults are used.
+    pub fn load() -> Result<Self> {
+        let explicit = std::env::args_os()
+            .nth(1)
+            .or_else(|| std::env::var_os(CONFIG_ENV_VAR))
+            .map(PathBuf::from);
+        match explicit {
+            Some(path) => Self::from_file(&path),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))
+            }
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid config file {path:?}: {e}"),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_loader::SecretSource;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = toml::from_str(
+            r#"
+            listen = "127.0.0.1:8080"
+            admin_listen = "127.0.0.1:8081"
+            image_cache_bytes = 1073741824
+            watch_local_dirs = false
+
+            [derivative_cache]
+            dir = "/var/cache/iiirs/derivatives"
+            max_cache_bytes = 10737418240
+
+            [prefixes.books]
+            type = "local"
+            dir = "/srv/books"
+            extensions = ["jpg", "png"]
+
+            [prefixes.remote]
+            type = "proxy"
+            cache_dir = "/var/cache/iiirs"
+            url_template = "https://images.example.org/masters/{id}.jpg"
+            read_timeout_ms = 30000
+
+            [prefixes.remote.retry]
+            max_retries = 4
+
+            [prefixes.remote.auth]
+            headers = { X-Api-Key = { env = "ARCHIVE_KEY" } }
+            basic = { username = "iiirs", password = { file = "/run/secrets/archive" } }
+
+            [prefixes.remote.allow]
+            schemes = ["https"]
+            hosts = ["images.example.org"]
+          
+            [prefixes.cascade]
+            type = "proxy"
+            cache_dir = "/var/cache/iiirs-cascade"
+            iiif_base_url = "https://iiif.example.org/iiif/3"
+
+            [prefixes.masters]
+            type = "s3"
+            endpoint = "http://localhost:9000"
+            bucket = "masters"
+            key_prefix = "tiff/"
+            access_key_id = { env = "S3_ACCESS_KEY_ID" }
+            secret_access_key = { file = "/run/secrets/s3" }
+            cache_dir = "/var/cache/iiirs-s3"
+
+            [prefixes.scans]
+            type = "archive"
+            dir = "/srv/scans"
+
+            [prefixes.tiered]
+            type = "chain"
+            promote = true
+
+            [[prefixes.tiered.tiers]]
+            type = "local"
+            dir = "/srv/fast"
+
+            [[prefixes.tiered.tiers]]
+            type = "proxy"
+            cache_dir = "/var/cache/iiirs-tiered"
+            url_template = "https://images.example.org/{id}"
+
+            [prefixes.vault]
+            type = "plugin"
+            loader = "institutional"
+            options = { endpoint = "https://vault.example.org", retries = 2 }
+
+            [prefixes.catalogue]
+            type = "resolved"
+
+            [prefixes.catalogue.resolver]
+            table = "/srv/identifiers.tsv"
+            header = true
+
+            [prefixes.catalogue.loader]
+            type = "local"
+            dir = "/srv/masters"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen, SocketAddr::from(([127, 0, 0, 1], 8080)));
+        assert_eq!(
+            config.admin_listen,
+            Some(SocketAddr::from(([127, 0, 0, 1], 8081)))
+        );
+        assert_eq!(config.image_cache_bytes, 1 << 30);
+        assert!(!config.watch_local_dirs);
+        let derivative_cache = config.derivative_cache.as_ref().unwrap();
+        assert_eq!(derivative_cache.max_cache_bytes, Some(10 << 30));
+        assert_eq!(derivative_cache.max_entry_age_secs, None);
+        match &config.prefixes["books"] {
+            PrefixConfig::Local { dir, extensions } => {
+                assert_eq!(dir, Path::new("/srv/books"));
+                assert_eq!(extensions, &["jpg", "png"]);
+            }
+            other => panic!("unexpected prefix config {other:?}"),
+        }
+        match &config.prefixes["remote"] {
+            PrefixConfig::Proxy(proxy) => {
+                assert_eq!(proxy.allow.schemes, ["https"]);
+                assert_eq!(proxy.allow.hosts, ["images.example.org"]);
+                assert!(!proxy.allow.private_addresses);
+                assert_eq!(
+                    proxy.url_template.as_ref().unwrap().expand("a").unwrap(),
+                    "https://images.example.org/masters/a.jpg".parse().unwrap()
+                );
+                assert_eq!(proxy.read_timeout_ms, 30000);
+                assert_eq!(proxy.connect_timeout_ms, 2000);
+                assert_eq!(proxy.retry.max_retries, 4);
+                assert_eq!(proxy.retry.initial_backoff_ms, 200);
+                assert!(matches!(
+                    &proxy.auth.headers["X-Api-Key"],
+                    SecretSource::Env(name) if name == "ARCHIVE_KEY"
+                ));
+                assert!(matches!(
+                    &proxy.auth.basic.as_ref().unwrap().password,
+                    SecretSource::File(path) if path == Path::new("/run/secrets/archive")
+                ));
+            }
+            other => panic!("unexpected prefix config {other:?}"),
+        }
+        match &config.prefixes["cascade"] {
+            PrefixConfig::Proxy(proxy) => {
+                assert!(proxy.url_template.is_none());
+                assert_eq!(
+                    proxy.iiif_base_url.as_ref().unwrap().as_str(),
+                    "https://iiif.example.org/iiif/3"
+                );
+            }
+            other => panic!("unexpected prefix config {other:?}"),
+        }
+        match &config.prefixes["masters"] {
+            PrefixConfig::S3(s3) => {
+                assert_eq!(s3.endpoint.as_str(), "http://localhost:9000/");
+                assert_eq!(s3.bucket, "masters");
+                assert_eq!(s3.region, "us-east-1");
+                assert_eq!(s3.key_prefix.as_deref(), Some("tiff/"));
+                assert!(matches!(
+                    &s3.access_key_id,
+                    SecretSource::Env(name) if name == "S3_ACCESS_KEY_ID"
+                ));
+                assert_eq!(s3.max_age_secs, 300);
+            }
+            other => panic!("unexpected prefix config {other:?}"),
+        }
+        match &config.prefixes["scans"] {
+            PrefixConfig::Archive { dir, extensions } => {
+                assert_eq!(dir, Path::new("/srv/scans"));
+                assert_eq!(extensions, &default_extensions());
+            }
+            other => panic!("unexpected prefix config {other:?}"),
+        }
+        match &config.prefixes["tiered"] {
+            PrefixConfig::Chain { tiers, promote } => {
+                assert!(promote);
+                assert!(matches!(
+                    tiers.as_slice(),
+                    [PrefixConfig::Local { .. }, PrefixConfig::Proxy(_)]
+                ));
+            }
+            other => panic!("unexpected prefix config {other:?}"),
+        }
+        match &config.prefixes["vault"] {
+            PrefixConfig::Plugin { loader, options } => {
+                assert_eq!(loader, "institutional");
+                assert_eq!(options["retries"].as_integer(), Some(2));
+            }
+            other => panic!("unexpected prefix config {other:?}"),
+        }
+        match &config.prefixes["catalogue"] {
+            PrefixConfig::Resolved { resolver, loader } => {
+                assert_eq!(resolver.table, Path::new("/srv/identifiers.tsv"));
+                assert!(resolver.header);
+                assert_eq!(resolver.check_interval_secs, 10);
+                assert!(matches!(**loader, PrefixConfig::Local { .. }));
+            }
+            other => panic!("unexpected prefix config {other:?}"),
+        }
+    }
+}
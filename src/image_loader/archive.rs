@@ -0,0 +1,770 @@
+use flate2::{Crc, read::DeflateDecoder};
+use image::{DynamicImage, ImageFormat, ImageReader};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime},
+};
+
+use super::{
+    GenericImageLoader, LoaderError, Result, file_version, spawn_decode,
+};
+
+/// Separates the volume from the member in identifiers, as in
+/// `volume~page0003`
+const MEMBER_SEPARATOR: char = '~';
+/// Archive extensions tried, in order, for a volume
+const ARCHIVE_EXTENSIONS: &[(&str, ArchiveKind)] =
+    &[("zip", ArchiveKind::Zip), ("tar", ArchiveKind::Tar)];
+/// Archives whose member index is kept in memory
+const MAX_INDEXED_ARCHIVES: usize = 1024;
+/// Largest member to read into memory
+const MAX_MEMBER_BYTES: u64 = 512 * 1024 * 1024;
+/// Most memory set aside for a member before any of it is read, since its
+/// size is only what the archive claims
+const MEMBER_BUFFER_BYTES: u64 = 1024 * 1024;
+/// Largest ZIP central directory to read
+const MAX_CENTRAL_DIRECTORY_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Serves images stored inside ZIP or uncompressed TAR archives in a
+/// directory, one archive per volume, without unpacking them. The offsets of
+/// the members of recently used archives are kept in memory, so that a
+/// member is read with a seek and a single read, plus inflating it if it is
+/// compressed.
+#[derive(Debug)]
+pub struct ArchiveLoader {
+    dir: PathBuf,
+    extensions: Vec<String>,
+    indexes: Mutex<HashMap<PathBuf, CachedIndex>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+}
+
+#[derive(Debug)]
+struct CachedIndex {
+    index: Arc<ArchiveIndex>,
+    last_used: Instant,
+}
+
+/// Where the members of an archive are, by name.
+#[derive(Debug)]
+struct ArchiveIndex {
+    /// Size and modification time of the archive the index was built from
+    len: u64,
+    modified: Option<SystemTime>,
+    members: HashMap<String, Member>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Member {
+    /// Offset of the ZIP local header or the start of the TAR member data
+    offset: u64,
+    compression: Compression,
+    /// Size as stored in the archive
+    size: u64,
+    uncompressed_size: u64,
+    crc32: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Stored,
+    Deflated,
+    /// A compression method or encryption that cannot be read
+    Unsupported,
+}
+
+impl ArchiveLoader {
+    /// Serve the archives in `dir`, resolving members named without a file
+    /// extension by trying each of `extensions` in turn.
+    pub fn new<T, E>(dir: T, extensions: impl IntoIterator<Item = E>) -> Self
+    where
+        T: Into<PathBuf>,
+        E: AsRef<str>,
+    {
+        Self {
+            dir: dir.into(),
+            extensions: extensions
+                .into_iter()
+                .map(|ext| ext.as_ref().trim_start_matches('.').to_lowercase())
+                .collect(),
+            indexes: Mutex::default(),
+        }
+    }
+
+    /// Find the archive for `volume`, which may be in a subdirectory.
+    fn resolve_volume(&self, volume: &str) -> Result<(PathBuf, ArchiveKind)> {
+        let relative = Path::new(volume);
+        if volume.is_empty()
+            || !relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(LoaderError::InvalidIdentifier);
+        }
+        ARCHIVE_EXTENSIONS
+            .iter()
+            .map(|&(ext, kind)| {
+                (self.dir.join(format!("{volume}.{ext}")), kind)
+            })
+            .find(|(path, _)| path.is_file())
+            .ok_or(LoaderError::NotFound)
+    }
+
+    /// The member index of the archive at `path`, built anew if the archive
+    /// has changed since it was last indexed.
+    async fn index(
+        &self,
+        path: &Path,
+        kind: ArchiveKind,
+    ) -> Result<Arc<ArchiveIndex>> {
+        let metadata = tokio::fs::metadata(path).await?;
+        let (len, modified) = (metadata.len(), metadata.modified().ok());
+        {
+            let mut indexes = self.indexes.lock().unwrap();
+            if let Some(cached) = indexes.get_mut(path)
+                && cached.index.len == len
+                && cached.index.modified == modified
+            {
+                cached.last_used = Instant::now();
+                return Ok(Arc::clone(&cached.index));
+            }
+        }
+
+        let index = {
+            let path = path.to_owned();
+            tokio::task::spawn_blocking(move || {
+                let mut file = File::open(&path)?;
+                let members = match kind {
+                    ArchiveKind::Zip => index_zip(&mut file, len),
+                    ArchiveKind::Tar => index_tar(&mut file, len),
+                }
+                .map_err(|e| invalid_archive(&path, e))?;
+                Ok::<_, LoaderError>(ArchiveIndex {
+                    len,
+                    modified,
+                    members: strip_common_dir(members),
+                })
+            })
+            .await
+            .map_err(|e| {
+                LoaderError::CorruptImage(format!("indexer panicked: {e}"))
+            })??
+        };
+
+        let index = Arc::new(index);
+        let mut indexes = self.indexes.lock().unwrap();
+        if indexes.len() >= MAX_INDEXED_ARCHIVES
+            && let Some(oldest) = indexes
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(path, _)| path.clone())
+        {
+            indexes.remove(&oldest);
+        }
+        indexes.insert(
+            path.to_owned(),
+            CachedIndex {
+                index: Arc::clone(&index),
+                last_used: Instant::now(),
+            },
+        );
+        Ok(index)
+    }
+}
+
+impl ArchiveIndex {
+    /// Find the member `name` refers to: the member of that name, or else
+    /// that name with each of `extensions` in turn.
+    fn find(
+        &self,
+        name: &str,
+        extensions: &[String],
+    ) -> Option<(&str, &Member)> {
+        let candidates = extensions.iter().flat_map(|ext| {
+            [
+                format!("{name}.{ext}"),
+                format!("{name}.{}", ext.to_uppercase()),
+            ]
+        });
+        [name.to_owned()]
+            .into_iter()
+            .chain(candidates)
+            .find_map(|name| self.members.get_key_value(&name))
+            .map(|(name, member)| (name.as_str(), member))
+    }
+}
+
+impl GenericImageLoader for ArchiveLoader {
+    async fn get_image(
+        &self,
+        _prefix: &str,
+        identifier: &str,
+    ) -> Result<DynamicImage> {
+        let (volume, name) = split_identifier(identifier)?;
+        let (path, kind) = self.resolve_volume(volume)?;
+        let index = self.index(&path, kind).await?;
+        let (name, member) = index
+            .find(name, &self.extensions)
+            .ok_or(LoaderError::NotFound)?;
+        let format = ImageFormat::from_path(name).ok();
+        let member = member.clone();
+
+        spawn_decode(move || {
+            let mut file = File::open(&path)?;
+            let data = read_member(&mut file, kind, &member)
+                .map_err(|e| invalid_archive(&path, e))?;
+            let mut reader = ImageReader::new(Cursor::new(data));
+            match format {
+                Some(format) => reader.set_format(format),
+                None => reader = reader.with_guessed_format()?,
+            }
+            Ok(reader.decode()?)
+        })
+        .await
+    }
+
+    /// The version of the archive, as members are only changed by writing
+    /// it anew.
+    async fn get_version(
+        &self,
+        _prefix: &str,
+        identifier: &str,
+    ) -> Result<Option<String>> {
+        let (volume, _) = split_identifier(identifier)?;
+        let (path, _) = self.resolve_volume(volume)?;
+        let metadata = tokio::fs::metadata(&path).await?;
+        Ok(Some(file_version(&metadata)))
+    }
+}
+
+/// The volume and member an identifier addresses.
+fn split_identifier(identifier: &str) -> Result<(&str, &str)> {
+    identifier
+        .split_once(MEMBER_SEPARATOR)
+        .filter(|(_, name)| !name.is_empty())
+        .ok_or(LoaderError::InvalidIdentifier)
+}
+
+fn invalid_archive(path: &Path, e: io::Error) -> LoaderError {
+    match e.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+            LoaderError::CorruptImage(format!("{}: {e}", path.display()))
+        }
+        io::ErrorKind::Unsupported => LoaderError::UnsupportedFormat,
+        _ => e.into(),
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read and, if need be, inflate `member`, checking it against its CRC.
+fn read_member(
+    file: &mut File,
+    kind: ArchiveKind,
+    member: &Member,
+) -> io::Result<Vec<u8>> {
+    if member.uncompressed_size > MAX_MEMBER_BYTES {
+        return Err(invalid_data("member too large"));
+    }
+    let offset = match kind {
+        ArchiveKind::Zip => {
+            // The name and extra field in the local header may differ in
+            // length from those in the central directory
+            let mut header = [0; 30];
+            file.seek(SeekFrom::Start(member.offset))?;
+            file.read_exact(&mut header)?;
+            if u32_at(&header, 0) != 0x0403_4b50 {
+                return Err(invalid_data("bad local file header"));
+            }
+            member
+                .offset
+                .checked_add(
+                    30 + u64::from(u16_at(&header, 26))
+                        + u64::from(u16_at(&header, 28)),
+                )
+                .ok_or_else(|| invalid_data("bad local file header"))?
+        }
+        ArchiveKind::Tar => member.offset,
+    };
+    file.seek(SeekFrom::Start(offset))?;
+
+    let stored = file.take(member.size);
+    let capacity = member.uncompressed_size.min(MEMBER_BUFFER_BYTES);
+    let mut data = Vec::with_capacity(capacity as usize);
+    match member.compression {
+        Compression::Stored => stored
+            .take(member.uncompressed_size)
+            .read_to_end(&mut data)?,
+        Compression::Deflated => DeflateDecoder::new(stored)
+            .take(member.uncompressed_size + 1)
+            .read_to_end(&mut data)?,
+        Compression::Unsupported => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unsupported compression",
+            ));
+        }
+    };
+    if data.len() as u64 != member.uncompressed_size {
+        return Err(invalid_data("member size mismatch"));
+    }
+    if let Some(crc32) = member.crc32 {
+        let mut crc = Crc::new();
+        crc.update(&data);
+        if crc.sum() != crc32 {
+            return Err(invalid_data("member CRC mismatch"));
+        }
+    }
+    Ok(data)
+}
+
+/// Key members by their path below the directory all of them are in, if
+/// there is one, as well as by their full path.
+fn strip_common_dir(
+    mut members: HashMap<String, Member>,
+) -> HashMap<String, Member> {
+    let common = members
+        .keys()
+        .next()
+        .and_then(|name| name.split_once('/'))
+        .map(|(dir, _)| format!("{dir}/"));
+    if let Some(common) = common
+        && members.keys().all(|name| name.starts_with(&common))
+    {
+        let stripped: Vec<_> = members
+            .iter()
+            .map(|(name, member)| {
+                (name[common.len()..].to_owned(), member.clone())
+            })
+            .collect();
+        for (name, member) in stripped {
+            members.entry(name).or_insert(member);
+        }
+    }
+    members
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Index the files in a ZIP archive of `len` bytes from its central
+/// directory, including ZIP64 archives.
+fn index_zip(file: &mut File, len: u64) -> io::Result<HashMap<String, Member>> {
+    // The end of central directory record is followed by a comment of up to
+    // 64 KiB
+    let tail_len = len.min(22 + 0xffff);
+    let tail = read_at(file, len - tail_len, tail_len as usize)?;
+    let eocd = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&at| u32_at(&tail, at) == 0x0605_4b50)
+        .ok_or_else(|| invalid_data("no end of central directory record"))?;
+    let mut entries = u64::from(u16_at(&tail, eocd + 10));
+    let mut cd_size = u64::from(u32_at(&tail, eocd + 12));
+    let mut cd_offset = u64::from(u32_at(&tail, eocd + 16));
+
+    let eocd_offset = len - tail_len + eocd as u64;
+    if (entries == 0xffff || cd_size == 0xffff_ffff || cd_offset == 0xffff_ffff)
+        && eocd_offset >= 20
+    {
+        let locator = read_at(file, eocd_offset - 20, 20)?;
+        if u32_at(&locator, 0) == 0x0706_4b50 {
+            let eocd64 = read_at(file, u64_at(&locator, 8), 56)?;
+            if u32_at(&eocd64, 0) != 0x0606_4b50 {
+                return Err(invalid_data("bad ZIP64 end of central directory"));
+            }
+            entries = u64_at(&eocd64, 32);
+            cd_size = u64_at(&eocd64, 40);
+            cd_offset = u64_at(&eocd64, 48);
+        }
+    }
+    if cd_size > MAX_CENTRAL_DIRECTORY_BYTES
+        || cd_offset.checked_add(cd_size).is_none_or(|end| end > len)
+    {
+        return Err(invalid_data("bad central directory size"));
+    }
+
+    let cd = read_at(file, cd_offset, cd_size as usize)?;
+    let mut members = HashMap::new();
+    let mut at = 0;
+    for _ in 0..entries {
+        if at + 46 > cd.len() || u32_at(&cd, at) != 0x0201_4b50 {
+            return Err(invalid_data("bad central directory entry"));
+        }
+        let flags = u16_at(&cd, at + 8);
+        let method = u16_at(&cd, at + 10);
+        let crc32 = u32_at(&cd, at + 16);
+        let mut size = u64::from(u32_at(&cd, at + 20));
+        let mut uncompressed_size = u64::from(u32_at(&cd, at + 24));
+        let name_len = usize::from(u16_at(&cd, at + 28));
+        let extra_len = usize::from(u16_at(&cd, at + 30));
+        let comment_len = usize::from(u16_at(&cd, at + 32));
+        let mut offset = u64::from(u32_at(&cd, at + 42));
+        let name_start = at + 46;
+        let extra_start = name_start + name_len;
+        let next = extra_start + extra_len + comment_len;
+        if next > cd.len() {
+            return Err(invalid_data("bad central directory entry"));
+        }
+
+        // ZIP64 sizes and offset, for those that don't fit in 32 bits
+        let mut extra = &cd[extra_start..extra_start + extra_len];
+        while extra.len() >= 4 {
+            let (id, data_len) =
+                (u16_at(extra, 0), usize::from(u16_at(extra, 2)));
+            let data = &extra[4..(4 + data_len).min(extra.len())];
+            if id == 0x0001 {
+                let mut fields = data.chunks_exact(8).map(|f| u64_at(f, 0));
+                for value in [&mut uncompressed_size, &mut size, &mut offset] {
+                    if *value == 0xffff_ffff {
+                        *value = fields
+                            .next()
+                            .ok_or_else(|| invalid_data("bad ZIP64 field"))?;
+                    }
+                }
+            }
+            extra = &extra[(4 + data_len).min(extra.len())..];
+        }
+
+        let name = String::from_utf8_lossy(&cd[name_start..extra_start]);
+        if !name.ends_with('/') {
+            let encrypted = flags & 1 != 0;
+            let compression = match method {
+                _ if encrypted => Compression::Unsupported,
+                0 => Compression::Stored,
+                8 => Compression::Deflated,
+                _ => Compression::Unsupported,
+            };
+            members.insert(
+                name.into_owned(),
+                Member {
+                    offset,
+                    compression,
+                    size,
+                    uncompressed_size,
+                    crc32: Some(crc32),
+                },
+            );
+        }
+        at = next;
+    }
+    Ok(members)
+}
+
+/// Index the regular files in a TAR archive of `len` bytes from their
+/// headers, including GNU long names and PAX paths.
+fn index_tar(file: &mut File, len: u64) -> io::Result<HashMap<String, Member>> {
+    let mut members = HashMap::new();
+    let mut long_name = None;
+    let mut offset = 0;
+    let mut header = [0; 512];
+    while offset + 512 <= len {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        let checksum = header
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| {
+                if (148..156).contains(&i) {
+                    32
+                } else {
+                    u64::from(byte)
+                }
+            })
+            .sum::<u64>();
+        if tar_number(&header[148..156]) != Some(checksum) {
+            return Err(invalid_data("bad TAR header checksum"));
+        }
+        let size = tar_number(&header[124..136])
+            .ok_or_else(|| invalid_data("bad TAR member size"))?;
+        let data_offset = offset + 512;
+        // The padded end of the member, where the next header starts
+        let end = size
+            .checked_next_multiple_of(512)
+            .and_then(|padded| data_offset.checked_add(padded))
+            .ok_or_else(|| invalid_data("bad TAR member size"))?;
+        if data_offset + size > len {
+            return Err(invalid_data("truncated TAR member"));
+        }
+
+        match header[156] {
+            b'0' | 0 | b'7' => {
+                let name = long_name.take().unwrap_or_else(|| {
+                    let name = tar_str(&header[0..100]);
+                    let prefix = tar_str(&header[345..500]);
+                    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                        format!("{prefix}/{name}")
+                    } else {
+                        name
+                    }
+                });
+                members.insert(
+                    name.trim_start_matches("./").to_owned(),
+                    Member {
+                        offset: data_offset,
+                        compression: Compression::Stored,
+                        size,
+                        uncompressed_size: size,
+                        crc32: None,
+                    },
+                );
+            }
+            // The name of the next member, when too long for its header
+            b'L' | b'x' if size <= 64 * 1024 => {
+                let data = read_at(file, data_offset, size as usize)?;
+                long_name = if header[156] == b'L' {
+                    Some(tar_str(&data))
+                } else {
+                    pax_path(&data).or(long_name)
+                };
+            }
+            _ => long_name = None,
+        }
+        offset = end;
+    }
+    Ok(members)
+}
+
+/// A NUL-terminated string field of a TAR header.
+fn tar_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// A numeric field of a TAR header: octal, or base-256 for large values.
+fn tar_number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        return field[1..]
+            .iter()
+            .try_fold(u64::from(field[0] & 0x7f), |n, &byte| {
+                n.checked_mul(256)?.checked_add(u64::from(byte))
+            });
+    }
+    let digits = tar_str(field);
+    let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// The `path` record of a PAX extended header.
+fn pax_path(data: &[u8]) -> Option<String> {
+    let mut data = data;
+    while let Some(space) = data.iter().position(|&b| b == b' ') {
+        let len: usize =
+            std::str::from_utf8(&data[..space]).ok()?.parse().ok()?;
+        let record = data.get(space + 1..len)?;
+        if let Some(path) = record.strip_prefix(b"path=") {
+            let path = path.strip_suffix(b"\n").unwrap_or(path);
+            return Some(String::from_utf8_lossy(path).into_owned());
+        }
+        data = &data[len..];
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::DeflateEncoder;
+    use image::GenericImageView;
+    use std::io::Write;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = Cursor::new(vec![]);
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+
+    /// A ZIP archive of `members`, deflated where asked for.
+    fn zip(members: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let (mut out, mut cd) = (vec![], vec![]);
+        for &(name, data, deflate) in members {
+            let mut crc = Crc::new();
+            crc.update(data);
+            let (method, stored) = if deflate {
+                let mut encoder =
+                    DeflateEncoder::new(vec![], flate2::Compression::best());
+                encoder.write_all(data).unwrap();
+                (8u16, encoder.finish().unwrap())
+            } else {
+                (0, data.to_vec())
+            };
+            let mut fields = vec![];
+            fields.extend_from_slice(&20u16.to_le_bytes());
+            fields.extend_from_slice(&0u16.to_le_bytes());
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]);
+            fields.extend_from_slice(&crc.sum().to_le_bytes());
+            fields.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&0u16.to_le_bytes());
+
+            cd.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            cd.extend_from_slice(&20u16.to_le_bytes());
+            cd.extend_from_slice(&fields);
+            cd.extend_from_slice(&[0; 10]);
+            cd.extend_from_slice(&(out.len() as u32).to_le_bytes());
+            cd.extend_from_slice(name.as_bytes());
+
+            out.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+            out.extend_from_slice(&fields);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&stored);
+        }
+        let cd_offset = out.len() as u32;
+        out.extend_from_slice(&cd);
+        out.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(cd.len() as u32).to_le_bytes());
+        out.extend_from_slice(&cd_offset.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out
+    }
+
+    /// A ustar archive of `members`.
+    fn tar(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = vec![];
+        for &(name, data) in members {
+            let mut header = [0u8; 512];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[100..108].copy_from_slice(b"0000644\0");
+            header[124..136]
+                .copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+            header[136..148].copy_from_slice(b"00000000000\0");
+            header[156] = b'0';
+            header[257..263].copy_from_slice(b"ustar\0");
+            header[263..265].copy_from_slice(b"00");
+            header[148..156].fill(b' ');
+            let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+            header[148..156]
+                .copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+            out.extend_from_slice(&header);
+            out.extend_from_slice(data);
+            out.resize(out.len().next_multiple_of(512), 0);
+        }
+        out.resize(out.len() + 1024, 0);
+        out
+    }
+
+    #[tokio::test]
+    async fn test_archive_loader() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("volume.zip"),
+            zip(&[
+                ("volume/page0001.png", &png(3, 2), false),
+                ("volume/page0002.png", &png(5, 4), true),
+            ]),
+        )
+        .unwrap();
+        std::fs::create_dir(dir.path().join("shelf")).unwrap();
+        std::fs::write(
+            dir.path().join("shelf/letters.tar"),
+            tar(&[("letter1.png", &png(7, 6))]),
+        )
+        .unwrap();
+        let loader = ArchiveLoader::new(dir.path(), ["png"]);
+
+        for (identifier, dimensions) in [
+            ("volume~page0001", (3, 2)),
+            ("volume~page0002.png", (5, 4)),
+            ("volume~volume/page0002", (5, 4)),
+            ("shelf/letters~letter1", (7, 6)),
+        ] {
+            let image = loader.get_image("scans", identifier).await.unwrap();
+            assert_eq!(image.dimensions(), dimensions, "{identifier}");
+        }
+        for identifier in ["volume~page0003", "missing~page0001"] {
+            assert!(matches!(
+                loader.get_image("scans", identifier).await,
+                Err(LoaderError::NotFound)
+            ));
+        }
+        for identifier in ["volume", "volume~", "../volume~page0001"] {
+            assert!(matches!(
+                loader.get_image("scans", identifier).await,
+                Err(LoaderError::InvalidIdentifier)
+            ));
+        }
+
+        // A changed archive is indexed again
+        std::fs::write(
+            dir.path().join("volume.zip"),
+            zip(&[("page0003.png", &png(1, 1), true)]),
+        )
+        .unwrap();
+        let image = loader.get_image("scans", "volume~page0003").await.unwrap();
+        assert_eq!(image.dimensions(), (1, 1));
+        assert!(matches!(
+            loader.get_image("scans", "volume~page0001").await,
+            Err(LoaderError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_tar_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("volume.tar");
+        let mut data = tar(&[("page.png", &png(2, 2))]);
+        // A base-256 size as large as can be
+        data[124..136].copy_from_slice(&[
+            0x80, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        ]);
+        data[148..156].fill(b' ');
+        let checksum: u32 = data[..512].iter().map(|&b| u32::from(b)).sum();
+        data[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+        std::fs::write(&path, &data).unwrap();
+
+        let mut file = File::open(&path).unwrap();
+        let error = index_tar(&mut file, data.len() as u64).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_corrupt_member() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("volume.zip");
+        let mut data = zip(&[("page.png", &png(2, 2), false)]);
+        data[40] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+
+        let mut file = File::open(&path).unwrap();
+        let members = index_zip(&mut file, data.len() as u64).unwrap();
+        let error =
+            read_member(&mut file, ArchiveKind::Zip, &members["page.png"])
+                .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
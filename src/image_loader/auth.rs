@@ -0,0 +1,152 @@
+use base64ct::{Base64, Encoding};
+use reqwest::{
+    ClientBuilder, Identity,
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+};
+use serde::Deserialize;
+use std::{collections::HashMap, path::PathBuf};
+
+use super::{LoaderError, Result};
+
+/// Credentials a proxy prefix sends to its upstream.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamAuth {
+    /// Headers added to every request, such as an API key
+    pub headers: HashMap<String, SecretSource>,
+    pub bearer: Option<SecretSource>,
+    pub basic: Option<BasicAuth>,
+    pub client_cert: Option<ClientCert>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: SecretSource,
+}
+
+/// A TLS client certificate to present to the upstream.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientCert {
+    /// PEM file with the certificate and any intermediates
+    pub cert: PathBuf,
+    /// PEM-encoded PKCS #8 private key
+    pub key: SecretSource,
+}
+
+/// Where to read a secret from, so that it does not have to be written into
+/// the configuration file: `{ env = "NAME" }` or `{ file = "/path" }`.
+/// Surrounding whitespace, such as a trailing newline, is ignored.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum SecretSource {
+    Env(String),
+    File(PathBuf),
+}
+
+impl UpstreamAuth {
+    /// Whether any credentials are sent in headers that the HTTP client
+    /// doesn't know to drop when redirected to another host.
+    pub fn has_custom_headers(&self) -> bool {
+        !self.headers.is_empty()
+    }
+
+    /// Read the secrets and configure `client` to send the credentials.
+    /// Credentials that would be sent in the same header are rejected
+    /// rather than have one silently replace the other.
+    pub fn apply(&self, client: ClientBuilder) -> Result<ClientBuilder> {
+        let mut names = Vec::with_capacity(self.headers.len());
+        for name in self.headers.keys() {
+            let name = HeaderName::try_from(name).map_err(|_| {
+                LoaderError::Config(format!("invalid header name {name}"))
+            })?;
+            if names.contains(&name) {
+                return Err(LoaderError::Config(format!(
+                    "header {name} is configured more than once"
+                )));
+            }
+            names.push(name);
+        }
+        let schemes = [
+            self.bearer.is_some().then_some("bearer"),
+            self.basic.is_some().then_some("basic"),
+            names
+                .contains(&header::AUTHORIZATION)
+                .then_some("Authorization header"),
+        ];
+        if let [first, second, ..] =
+            schemes.iter().flatten().collect::<Vec<_>>()[..]
+        {
+            return Err(LoaderError::Config(format!(
+                "both {first} and {second} credentials are configured, \
+                 but only one can be sent"
+            )));
+        }
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in names.into_iter().zip(self.headers.values()) {
+            headers.insert(name, sensitive_header(value.read()?)?);
+        }
+        if let Some(token) = &self.bearer {
+            let value = format!("Bearer {}", token.read()?);
+            headers.insert(header::AUTHORIZATION, sensitive_header(value)?);
+        }
+        if let Some(BasicAuth { username, password }) = &self.basic {
+            let credentials = format!("{username}:{}", password.read()?);
+            let value = format!(
+                "Basic {}",
+                Base64::encode_string(credentials.as_bytes())
+            );
+            headers.insert(header::AUTHORIZATION, sensitive_header(value)?);
+        }
+
+        let mut client = client.default_headers(headers);
+        if let Some(ClientCert { cert, key }) = &self.client_cert {
+            let cert = std::fs::read(cert).map_err(|e| {
+                LoaderError::Config(format!(
+                    "cannot read client certificate {}: {e}",
+                    cert.display()
+                ))
+            })?;
+            let identity =
+                Identity::from_pkcs8_pem(&cert, key.read()?.as_bytes())
+                    .map_err(|e| {
+                        LoaderError::Config(format!(
+                            "invalid client certificate: {e}"
+                        ))
+                    })?;
+            client = client.identity(identity);
+        }
+        Ok(client)
+    }
+}
+
+impl SecretSource {
+    pub(super) fn read(&self) -> Result<String> {
+        let secret = match self {
+            Self::Env(name) => std::env::var(name).map_err(|e| {
+                LoaderError::Config(format!(
+                    "cannot read secret from ${name}: {e}"
+                ))
+            })?,
+            Self::File(path) => std::fs::read_to_string(path).map_err(|e| {
+                LoaderError::Config(format!(
+                    "cannot read secret from {}: {e}",
+                    path.display()
+                ))
+            })?,
+        };
+        Ok(secret.trim().to_owned())
+    }
+}
+
+/// A header value that is redacted from the HTTP client's debug output.
+fn sensitive_header(value: String) -> Result<HeaderValue> {
+    let mut value = HeaderValue::try_from(value).map_err(|_| {
+        LoaderError::Config("secret is not a valid header value".into())
+    })?;
+    value.set_sensitive(true);
+    Ok(value)
+}
@@ -0,0 +1,496 @@
+use image::ImageFormat;
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::ContentCacheKey;
+
+/// Schema migrations, applied in order. The index of the last applied
+/// migration is kept in SQLite's `user_version` pragma.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE proxy_index (
+        uri TEXT PRIMARY KEY NOT NULL,
+        content_hash BLOB NOT NULL,
+        format TEXT NOT NULL,
+        fetched_at INTEGER NOT NULL,
+        etag TEXT,
+        last_modified TEXT
+    );
+    CREATE INDEX proxy_index_content_hash ON proxy_index (content_hash);",
+    // Entries from before expiry was tracked are stale
+    "ALTER TABLE proxy_index
+        ADD COLUMN expires_at INTEGER NOT NULL DEFAULT 0;",
+    // Files already in the cache are added by CacheIndex users on startup
+    "CREATE TABLE proxy_content (
+        content_hash BLOB PRIMARY KEY NOT NULL,
+        size INTEGER NOT NULL,
+        stored_at INTEGER NOT NULL,
+        last_access INTEGER NOT NULL
+    );
+    CREATE INDEX proxy_content_last_access ON proxy_content (last_access);",
+    "CREATE TABLE proxy_failures (
+        uri TEXT PRIMARY KEY NOT NULL,
+        kind TEXT NOT NULL,
+        message TEXT NOT NULL,
+        expires_at INTEGER NOT NULL
+    );
+    CREATE INDEX proxy_failures_expires_at ON proxy_failures (expires_at);",
+    // What an entry was made from, for caches of derived content
+    "ALTER TABLE proxy_index ADD COLUMN source TEXT;
+    CREATE INDEX proxy_index_source ON proxy_index (source);",
+];
+
+/// Access times are only written when they have moved by at least this much,
+/// so that a burst of tile requests doesn't turn into a burst of writes.
+const ACCESS_TIME_RESOLUTION_SECS: i64 = 60;
+
+/// Durable mapping from upstream URIs to the content cached for them.
+#[derive(Debug)]
+pub struct CacheIndex {
+    conn: Mutex<Connection>,
+}
+
+/// HTTP validators sent by the upstream along with an image.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub content_hash: ContentCacheKey,
+    pub format: ImageFormat,
+    pub fetched_at: SystemTime,
+    /// Until when the entry may be served without revalidation
+    pub expires_at: SystemTime,
+    pub validators: Validators,
+}
+
+/// How fetching a URI from its upstream failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The upstream said there is no such image
+    NotFound,
+    /// The upstream errored or sent something unusable
+    Upstream,
+    /// The upstream did not answer in time
+    Timeout,
+}
+
+/// A remembered failure to fetch a URI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub message: String,
+    /// Until when to fail requests for the URI without asking the upstream
+    pub expires_at: SystemTime,
+}
+
+impl FailureKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::Upstream => "upstream",
+            Self::Timeout => "timeout",
+        }
+    }
+
+    fn from_str(kind: &str) -> Option<Self> {
+        match kind {
+            "not_found" => Some(Self::NotFound),
+            "upstream" => Some(Self::Upstream),
+            "timeout" => Some(Self::Timeout),
+            _ => None,
+        }
+    }
+}
+
+impl CacheIndex {
+    pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Run `f` against the index on the blocking thread pool, so that
+    /// waiting for the connection or for SQLite does not hold up an async
+    /// worker.
+    pub async fn run<T, F>(self: &Arc<Self>, f: F) -> rusqlite::Result<T>
+    where
+        F: FnOnce(&Self) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let index = Arc::clone(self);
+        tokio::task::spawn_blocking(move || f(&index))
+            .await
+            .expect("cache index call panicked")
+    }
+
+    pub fn get(&self, uri: &str) -> rusqlite::Result<Option<IndexEntry>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT content_hash, format, fetched_at, expires_at, etag,
+                last_modified FROM proxy_index WHERE uri = ?1",
+                [uri],
+                entry_from_row,
+            )
+            .optional()
+            .map(Option::flatten)
+    }
+
+    pub fn insert(
+        &self,
+        uri: &str,
+        entry: &IndexEntry,
+    ) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO proxy_index
+            (uri, content_hash, format, fetched_at, expires_at, etag,
+            last_modified)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                uri,
+                entry.content_hash,
+                format_to_str(entry.format),
+                to_unix_secs(entry.fetched_at),
+                to_unix_secs(entry.expires_at),
+                entry.validators.etag,
+                entry.validators.last_modified,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Forget what `uri` maps to. The content stays until it is evicted.
+    pub fn remove(&self, uri: &str) -> rusqlite::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM proxy_index WHERE uri = ?1", [uri])?;
+        Ok(())
+    }
+
+    /// Note that what `uri` maps to was made from `source`.
+    pub fn set_source(&self, uri: &str, source: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE proxy_index SET source = ?2 WHERE uri = ?1",
+            [uri, source],
+        )?;
+        Ok(())
+    }
+
+    /// Forget what every URI made from `source` maps to, and return the
+    /// content they were mapped to.
+    pub fn remove_by_source(
+        &self,
+        source: &str,
+    ) -> rusqlite::Result<Vec<ContentCacheKey>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "DELETE FROM proxy_index WHERE source = ?1 RETURNING content_hash",
+        )?;
+        stmt.query_map([source], |row| row.get(0))?.collect()
+    }
+
+    /// Record that the file for `content_hash`, `size` bytes long, was
+    /// stored in the cache directory at `now`.
+    pub fn insert_content(
+        &self,
+        content_hash: &ContentCacheKey,
+        size: u64,
+        now: SystemTime,
+    ) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO proxy_content
+            (content_hash, size, stored_at, last_access)
+            VALUES (?1, ?2, ?3, ?3)
+            ON CONFLICT (content_hash) DO UPDATE SET
+            size = excluded.size,
+            stored_at = excluded.stored_at,
+            last_access = excluded.last_access",
+            params![content_hash, to_sql_size(size), to_unix_secs(now)],
+        )?;
+        Ok(())
+    }
+
+    pub fn touch_content(
+        &self,
+        content_hash: &ContentCacheKey,
+        now: SystemTime,
+    ) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE proxy_content SET last_access = ?2
+            WHERE content_hash = ?1 AND last_access < ?2 - ?3",
+            params![
+                content_hash,
+                to_unix_secs(now),
+                ACCESS_TIME_RESOLUTION_SECS
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn content_hashes(&self) -> rusqlite::Result<Vec<ContentCacheKey>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT content_hash FROM proxy_content")?;
+        stmt.query_map([], |row| row.get(0))?.collect()
+    }
+
+    pub fn has_content(
+        &self,
+        content_hash: &ContentCacheKey,
+    ) -> rusqlite::Result<bool> {
+        self.conn.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM proxy_content WHERE content_hash = ?1)",
+            [content_hash],
+            |row| row.get(0),
+        )
+    }
+
+    /// Whether any URI maps to `content_hash`.
+    pub fn is_referenced(
+        &self,
+        content_hash: &ContentCacheKey,
+    ) -> rusqlite::Result<bool> {
+        self.conn.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM proxy_index WHERE content_hash = ?1)",
+            [content_hash],
+            |row| row.get(0),
+        )
+    }
+
+    /// Content to evict so that none of it was stored before
+    /// `stored_before`, and it takes up at most `max_size` bytes in total.
+    /// Beyond the content that is too old, the least recently used goes
+    /// first.
+    pub fn eviction_candidates(
+        &self,
+        max_size: Option<u64>,
+        stored_before: Option<SystemTime>,
+    ) -> rusqlite::Result<Vec<ContentCacheKey>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT content_hash, size, stored_at FROM proxy_content
+            ORDER BY last_access",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, ContentCacheKey>(0)?,
+                from_sql_size(row.get(1)?),
+                from_unix_secs(row.get(2)?),
+            ))
+        })?;
+
+        let total = from_sql_size(conn.query_row(
+            "SELECT COALESCE(SUM(size), 0) FROM proxy_content",
+            [],
+            |row| row.get(0),
+        )?);
+        let mut excess = max_size.map_or(0, |max| total.saturating_sub(max));
+        let mut candidates = vec![];
+        for row in rows {
+            let (content_hash, size, stored_at) = row?;
+            let too_old = stored_before.is_some_and(|t| stored_at < t);
+            if too_old || excess > 0 {
+                excess = excess.saturating_sub(size);
+                candidates.push(content_hash);
+            }
+        }
+        Ok(candidates)
+    }
+
+    /// The failure remembered for `uri`, unless it expired before `now`.
+    pub fn get_failure(
+        &self,
+        uri: &str,
+        now: SystemTime,
+    ) -> rusqlite::Result<Option<Failure>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT kind, message, expires_at FROM proxy_failures
+                WHERE uri = ?1 AND expires_at > ?2",
+                params![uri, to_unix_secs(now)],
+                |row| {
+                    let kind: String = row.get(0)?;
+                    let Some(kind) = FailureKind::from_str(&kind) else {
+                        return Ok(None);
+                    };
+                    Ok(Some(Failure {
+                        kind,
+                        message: row.get(1)?,
+                        expires_at: from_unix_secs(row.get(2)?),
+                    }))
+                },
+            )
+            .optional()
+            .map(Option::flatten)
+    }
+
+    /// Remember that fetching `uri` failed, replacing any earlier failure,
+    /// and forget failures that have expired by `now`.
+    pub fn insert_failure(
+        &self,
+        uri: &str,
+        failure: &Failure,
+        now: SystemTime,
+    ) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM proxy_failures WHERE expires_at <= ?1",
+            [to_unix_secs(now)],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO proxy_failures
+            (uri, kind, message, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                uri,
+                failure.kind.as_str(),
+                failure.message,
+                to_unix_secs(failure.expires_at),
+            ],
+        )?;
+        tx.commit()
+    }
+
+    /// Forget the failure remembered for `uri`, or for every URI if `None`.
+    /// Returns the number of failures forgotten.
+    pub fn remove_failures(
+        &self,
+        uri: Option<&str>,
+    ) -> rusqlite::Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        match uri {
+            Some(uri) => {
+                conn.execute("DELETE FROM proxy_failures WHERE uri = ?1", [uri])
+            }
+            None => conn.execute("DELETE FROM proxy_failures", []),
+        }
+    }
+
+    /// Forget the failures of every URI that starts with `prefix`.
+    pub fn remove_failures_under(
+        &self,
+        prefix: &str,
+    ) -> rusqlite::Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM proxy_failures WHERE substr(uri, 1, length(?1)) = ?1",
+            [prefix],
+        )
+    }
+
+    /// Forget `content_hash` and every URI mapped to it.
+    pub fn remove_content(
+        &self,
+        content_hash: &ContentCacheKey,
+    ) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM proxy_index WHERE content_hash = ?1",
+            [content_hash],
+        )?;
+        tx.execute(
+            "DELETE FROM proxy_content WHERE content_hash = ?1",
+            [content_hash],
+        )?;
+        tx.commit()
+    }
+}
+
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version: u32 =
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (version, migration) in (1..).zip(MIGRATIONS).skip(version as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Rows with an unknown format (e.g. written by a build with more image
+/// codecs enabled) are treated as missing.
+fn entry_from_row(row: &Row) -> rusqlite::Result<Option<IndexEntry>> {
+    let format: String = row.get(1)?;
+    let Some(format) = ImageFormat::from_extension(format) else {
+        return Ok(None);
+    };
+    Ok(Some(IndexEntry {
+        content_hash: row.get(0)?,
+        format,
+        fetched_at: from_unix_secs(row.get(2)?),
+        expires_at: from_unix_secs(row.get(3)?),
+        validators: Validators {
+            etag: row.get(4)?,
+            last_modified: row.get(5)?,
+        },
+    }))
+}
+
+fn format_to_str(format: ImageFormat) -> &'static str {
+    format.extensions_str()[0]
+}
+
+fn to_unix_secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs().try_into().unwrap_or(i64::MAX))
+}
+
+fn from_unix_secs(secs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs.max(0).unsigned_abs())
+}
+
+fn to_sql_size(size: u64) -> i64 {
+    size.try_into().unwrap_or(i64::MAX)
+}
+
+fn from_sql_size(size: i64) -> u64 {
+    size.max(0).unsigned_abs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.sqlite3");
+        let entry = IndexEntry {
+            content_hash: [7; 32],
+            format: ImageFormat::Jpeg,
+            fetched_at: from_unix_secs(1_700_000_000),
+            expires_at: from_unix_secs(1_700_003_600),
+            validators: Validators {
+                etag: Some("\"abc\"".into()),
+                last_modified: None,
+            },
+        };
+
+        CacheIndex::open(&path)
+            .unwrap()
+            .insert("https://example.org/a.jpg", &entry)
+            .unwrap();
+
+        let index = CacheIndex::open(&path).unwrap();
+        assert_eq!(
+            index.get("https://example.org/a.jpg").unwrap(),
+            Some(entry)
+        );
+        assert_eq!(index.get("https://example.org/b.jpg").unwrap(), None);
+    }
+}
@@ -0,0 +1,419 @@
+use serde::Deserialize;
+use url::Url;
+
+use super::url_template::encode_identifier;
+use super::{LoaderError, Result};
+
+/// Most tiles to fetch for one request. Beyond this the region is asked for
+/// as a whole instead.
+const MAX_TILES: u32 = 64;
+
+/// The parts of the info.json of an image on an upstream IIIF image server
+/// that are needed to fetch from it, for versions 2 and 3 of the Image API.
+#[derive(Debug, Deserialize)]
+pub struct RemoteInfo {
+    #[serde(rename = "@context", default)]
+    context: serde_json::Value,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default)]
+    tiles: Vec<Tiles>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Tiles {
+    width: u32,
+    height: Option<u32>,
+    #[serde(default)]
+    scale_factors: Vec<u32>,
+}
+
+/// A part of an upstream image to fetch.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Piece {
+    /// Region of the full image, as `(x, y, w, h)`
+    pub region: (u32, u32, u32, u32),
+    /// Size to ask for the region at
+    pub size: (u32, u32),
+    /// Where the piece goes on the canvas
+    pub offset: (u32, u32),
+}
+
+/// How to put together a region of an upstream image from pieces of it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Plan {
+    pub pieces: Vec<Piece>,
+    pub canvas: (u32, u32),
+    /// The part of the canvas covered by the region, as `(x, y, w, h)`
+    pub crop: (u32, u32, u32, u32),
+}
+
+impl RemoteInfo {
+    fn is_v3(&self) -> bool {
+        self.context.to_string().contains("iiif.io/api/image/3")
+    }
+
+    /// Check that the tiles the server describes can be fetched: that they
+    /// have a size, and that their scale factors are powers of two at which
+    /// the image is still at least a pixel across.
+    pub fn check_tiles(&self) -> Result<()> {
+        let largest = self.width.max(self.height);
+        for tiles in &self.tiles {
+            if tiles.width == 0 || tiles.height == Some(0) {
+                return Err(LoaderError::Upstream(
+                    "info.json describes tiles without a size".into(),
+                ));
+            }
+            if let Some(scale) = tiles
+                .scale_factors
+                .iter()
+                .find(|&&scale| !scale.is_power_of_two() || scale > largest)
+            {
+                return Err(LoaderError::Upstream(format!(
+                    "info.json describes tiles at a scale factor of {scale}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the tiles the server describes, so that regions are asked
+    /// for as a whole.
+    pub fn clear_tiles(&mut self) {
+        self.tiles.clear();
+    }
+
+    /// Plan how to fetch the region `rect` of the full image at a
+    /// resolution of at least `out`, from tiles at the smallest scale that
+    /// has enough detail if the server offers them.
+    pub fn plan(&self, rect: (u32, u32, u32, u32), out: (u32, u32)) -> Plan {
+        if let Some(plan) = self.plan_tiles(rect, out) {
+            return plan;
+        }
+
+        // Ask for the region as a whole, at full size if it is to be
+        // upscaled
+        let (w, h) = (rect.2, rect.3);
+        let size = if out.0 <= w && out.1 <= h {
+            out
+        } else {
+            (w, h)
+        };
+        Plan {
+            pieces: vec![Piece {
+                region: rect,
+                size,
+                offset: (0, 0),
+            }],
+            canvas: size,
+            crop: (0, 0, size.0, size.1),
+        }
+    }
+
+    /// Plan fetching `rect` from tiles, unless the server offers none, it
+    /// would take more than [`MAX_TILES`], or the tiles are too large to
+    /// put together.
+    fn plan_tiles(
+        &self,
+        rect: (u32, u32, u32, u32),
+        out: (u32, u32),
+    ) -> Option<Plan> {
+        let tiles = self.tiles.first()?;
+        let (x, y, w, h) = rect;
+        if tiles.width == 0 || w == 0 || h == 0 {
+            return None;
+        }
+        let scale = tiles
+            .scale_factors
+            .iter()
+            .copied()
+            .filter(|&scale| {
+                scale > 0
+                    && u64::from(w) >= u64::from(out.0) * u64::from(scale)
+                    && u64::from(h) >= u64::from(out.1) * u64::from(scale)
+            })
+            .max()
+            .unwrap_or(1);
+
+        // Tile sizes and scale factors come from the upstream, so work in
+        // u64 and give up on tiles where even that overflows
+        let narrow = |n: u64| u32::try_from(n).ok();
+        let (x, y, w, h) =
+            (u64::from(x), u64::from(y), u64::from(w), u64::from(h));
+        let scale = u64::from(scale);
+        let (tile_w, tile_h) = (
+            u64::from(tiles.width),
+            u64::from(tiles.height.unwrap_or(tiles.width)),
+        );
+        let (span_w, span_h) =
+            (tile_w.checked_mul(scale)?, tile_h.checked_mul(scale)?);
+        if span_h == 0 {
+            return None;
+        }
+        let (cols, rows) = (
+            x / span_w..=(x + w - 1) / span_w,
+            y / span_h..=(y + h - 1) / span_h,
+        );
+        let count = (cols.end() - cols.start() + 1)
+            .checked_mul(rows.end() - rows.start() + 1)?;
+        if count > u64::from(MAX_TILES) {
+            return None;
+        }
+
+        let mut pieces = vec![];
+        for row in rows.clone() {
+            for col in cols.clone() {
+                let (tx, ty) = (col * span_w, row * span_h);
+                let (tw, th) = (
+                    span_w.min(u64::from(self.width).checked_sub(tx)?),
+                    span_h.min(u64::from(self.height).checked_sub(ty)?),
+                );
+                pieces.push(Piece {
+                    region: (
+                        narrow(tx)?,
+                        narrow(ty)?,
+                        narrow(tw)?,
+                        narrow(th)?,
+                    ),
+                    size: (
+                        narrow(tw.div_ceil(scale))?,
+                        narrow(th.div_ceil(scale))?,
+                    ),
+                    offset: (
+                        narrow((col - cols.start()) * tile_w)?,
+                        narrow((row - rows.start()) * tile_h)?,
+                    ),
+                });
+            }
+        }
+        let last = pieces.last()?;
+        let canvas = (
+            last.offset.0.checked_add(last.size.0)?,
+            last.offset.1.checked_add(last.size.1)?,
+        );
+        let (cx, cy) = (
+            narrow((x - cols.start() * span_w) / scale)?,
+            narrow((y - rows.start() * span_h) / scale)?,
+        );
+        let crop = (
+            cx,
+            cy,
+            narrow(w.div_ceil(scale))?.min(canvas.0.checked_sub(cx)?),
+            narrow(h.div_ceil(scale))?.min(canvas.1.checked_sub(cy)?),
+        );
+        Some(Plan {
+            pieces,
+            canvas,
+            crop,
+        })
+    }
+
+    /// The URL of `piece` of `identifier` on the server at `base`, in the
+    /// canonical form that level 0 servers can serve too.
+    pub fn piece_url(
+        &self,
+        base: &Url,
+        identifier: &str,
+        piece: &Piece,
+    ) -> Result<Url> {
+        let (x, y, w, h) = piece.region;
+        let region = if piece.region == (0, 0, self.width, self.height) {
+            "full".to_owned()
+        } else {
+            format!("{x},{y},{w},{h}")
+        };
+        let size = match (self.is_v3(), piece.size == (w, h)) {
+            (true, true) => "max".to_owned(),
+            (true, false) => format!("{},{}", piece.size.0, piece.size.1),
+            (false, true) => "full".to_owned(),
+            (false, false) => format!("{},", piece.size.0),
+        };
+        image_url(base, identifier, &format!("{region}/{size}/0/default.jpg"))
+    }
+}
+
+/// The URL of `path` under image `identifier` on the server at `base`.
+pub fn image_url(base: &Url, identifier: &str, path: &str) -> Result<Url> {
+    format!(
+        "{}/{}/{path}",
+        base.as_str().trim_end_matches('/'),
+        encode_identifier(identifier)?
+    )
+    .parse()
+    .map_err(|_| LoaderError::InvalidIdentifier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_info(json: &str) -> RemoteInfo {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_plan() {
+        let info = parse_info(
+            r#"{
+                "@context": "http://iiif.io/api/image/3/context.json",
+                "width": 1000, "height": 600,
+                "tiles": [{"width": 256, "scaleFactors": [1, 2, 4, 8]}]
+            }"#,
+        );
+
+        // A thumbnail comes from a single tile at the smallest scale
+        let plan = info.plan((0, 0, 1000, 600), (120, 72));
+        assert_eq!(
+            plan.pieces,
+            [Piece {
+                region: (0, 0, 1000, 600),
+                size: (125, 75),
+                offset: (0, 0),
+            }]
+        );
+        assert_eq!(plan.crop, (0, 0, 125, 75));
+
+        // Full resolution regions from the tiles they overlap
+        let plan = info.plan((200, 100, 100, 200), (100, 200));
+        let regions: Vec<_> = plan.pieces.iter().map(|p| p.region).collect();
+        assert_eq!(
+            regions,
+            [
+                (0, 0, 256, 256),
+                (256, 0, 256, 256),
+                (0, 256, 256, 256),
+                (256, 256, 256, 256)
+            ]
+        );
+        assert_eq!(plan.pieces[3].offset, (256, 256));
+        assert_eq!(plan.canvas, (512, 512));
+        assert_eq!(plan.crop, (200, 100, 100, 200));
+
+        // Half resolution, with the tiles at the edge cut short
+        let plan = info.plan((600, 300, 400, 300), (200, 150));
+        assert_eq!(
+            plan.pieces,
+            [
+                Piece {
+                    region: (512, 0, 488, 512),
+                    size: (244, 256),
+                    offset: (0, 0),
+                },
+                Piece {
+                    region: (512, 512, 488, 88),
+                    size: (244, 44),
+                    offset: (0, 256),
+                },
+            ]
+        );
+        assert_eq!(plan.canvas, (244, 300));
+        assert_eq!(plan.crop, (44, 150, 200, 150));
+
+        // Too many tiles, so the region is asked for directly, at full
+        // size to be upscaled
+        let info = parse_info(
+            r#"{
+                "width": 1000, "height": 600,
+                "tiles": [{"width": 64, "scaleFactors": [1]}]
+            }"#,
+        );
+        let plan = info.plan((0, 0, 1000, 600), (2000, 1200));
+        assert_eq!(plan.pieces.len(), 1);
+        assert_eq!(plan.pieces[0].size, (1000, 600));
+    }
+
+    #[test]
+    fn test_check_tiles() {
+        let info = |tiles: &str| {
+            parse_info(&format!(
+                r#"{{"width": 1000, "height": 600, "tiles": [{tiles}]}}"#
+            ))
+        };
+
+        assert!(
+            info(r#"{"width": 256, "scaleFactors": [1, 2, 4]}"#)
+                .check_tiles()
+                .is_ok()
+        );
+        assert!(
+            info(r#"{"width": 256, "scaleFactors": [512]}"#)
+                .check_tiles()
+                .is_ok()
+        );
+        for tiles in [
+            r#"{"width": 0, "scaleFactors": [1]}"#,
+            r#"{"width": 256, "height": 0, "scaleFactors": [1]}"#,
+            r#"{"width": 256, "scaleFactors": [0]}"#,
+            r#"{"width": 256, "scaleFactors": [1, 3]}"#,
+            r#"{"width": 256, "scaleFactors": [2048]}"#,
+        ] {
+            let result = info(tiles).check_tiles();
+            assert!(matches!(result, Err(LoaderError::Upstream(_))), "{tiles}");
+        }
+    }
+
+    #[test]
+    fn test_plan_overflow() {
+        // Tiles too large to be put together are not fetched
+        let info = parse_info(
+            r#"{
+                "width": 4294967295, "height": 4294967295,
+                "tiles": [{"width": 4294967295, "scaleFactors": [2147483648]}]
+            }"#,
+        );
+        let rect = (0, 0, u32::MAX, u32::MAX);
+        let plan = info.plan(rect, (1, 1));
+        assert_eq!(plan.pieces.len(), 1);
+        assert_eq!(plan.pieces[0].region, rect);
+
+        let info = parse_info(
+            r#"{
+                "width": 4294967295, "height": 4294967295,
+                "tiles": [{"width": 65536, "scaleFactors": [65536]}]
+            }"#,
+        );
+        let plan = info.plan(rect, (u32::MAX, u32::MAX));
+        assert_eq!(plan.pieces.len(), 1);
+    }
+
+    #[test]
+    fn test_piece_url() {
+        let base: Url = "https://iiif.example.org/iiif/3/".parse().unwrap();
+        let v3 = parse_info(
+            r#"{"@context": "http://iiif.io/api/image/3/context.json",
+                "width": 1000, "height": 600}"#,
+        );
+        let v2 = parse_info(
+            r#"{"@context": "http://iiif.io/api/image/2/context.json",
+                "width": 1000, "height": 600}"#,
+        );
+        let piece = |region, size| Piece {
+            region,
+            size,
+            offset: (0, 0),
+        };
+        let url = |info: &RemoteInfo, region, size| {
+            info.piece_url(&base, "a/b", &piece(region, size))
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(
+            url(&v3, (0, 0, 1000, 600), (1000, 600)),
+            "https://iiif.example.org/iiif/3/a%2Fb/full/max/0/default.jpg"
+        );
+        assert_eq!(
+            url(&v3, (0, 0, 512, 512), (256, 256)),
+            "https://iiif.example.org/iiif/3/a%2Fb/0,0,512,512/256,256/0/default.jpg"
+        );
+        assert_eq!(
+            url(&v2, (0, 0, 512, 512), (256, 256)),
+            "https://iiif.example.org/iiif/3/a%2Fb/0,0,512,512/256,/0/default.jpg"
+        );
+        assert_eq!(
+            url(&v2, (0, 0, 1000, 600), (1000, 600)),
+            "https://iiif.example.org/iiif/3/a%2Fb/full/full/0/default.jpg"
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use image::DynamicImage;
+use std::{future::Future, pin::Pin};
+
+use super::{
+    GenericImageLoader, ImageLoader, LoaderError, Result, crop_and_resize,
+    pages,
+};
+use crate::api::image::{Region, Size};
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Serves a prefix from an ordered list of loaders, such as local disk, then
+/// a network share, then a remote proxy. Each image comes from the first
+/// loader that has it: a loader failing for any other reason than not
+/// having the image ends the search, so that an outage of a faster tier
+/// does not silently shift its load onto the slower ones.
+#[derive(Debug)]
+pub struct ChainLoader {
+    tiers: Vec<ImageLoader>,
+    promote: bool,
+}
+
+impl ChainLoader {
+    /// Try `tiers` in order. With `promote`, images found in a later tier
+    /// are copied into the first, which must then be local.
+    pub fn new(tiers: Vec<ImageLoader>, promote: bool) -> Result<Self> {
+        match tiers.first() {
+            None => {
+                return Err(LoaderError::Config("a chain needs a tier".into()));
+            }
+            Some(ImageLoader::Local(local)) if promote => {
+                local.check_writable()?;
+            }
+            Some(_) if promote => {
+                return Err(LoaderError::Config(
+                    "only a local first tier can be promoted to".into(),
+                ));
+            }
+            Some(_) => (),
+        }
+        Ok(Self { tiers, promote })
+    }
+
+    pub fn tiers(&self) -> &[ImageLoader] {
+        &self.tiers
+    }
+
+    /// The outcome of `load` from the first tier that has the image, and
+    /// which tier that is.
+    async fn first_found<'a, T, F>(&'a self, load: F) -> Result<(usize, T)>
+    where
+        F: Fn(&'a ImageLoader) -> BoxFuture<'a, T>,
+    {
+        for (tier, loader) in self.tiers.iter().enumerate() {
+            match load(loader).await {
+                Err(LoaderError::NotFound) => continue,
+                result => return result.map(|found| (tier, found)),
+            }
+        }
+        Err(LoaderError::NotFound)
+    }
+
+    /// Copy `image`, found in a later tier, into the first, in the
+    /// background. A failure to do so only costs the next request a trip to
+    /// the slower tier again, so it is reported but not returned.
+    fn promote(&self, prefix: &str, identifier: &str, image: &DynamicImage) {
+        let Some(ImageLoader::Local(local)) = self.tiers.first() else {
+            return;
+        };
+        // Pages are not stored on their own
+        if !self.promote
+            || !matches!(pages::split_page(identifier), Ok((_, None)))
+        {
+            return;
+        }
+        let Ok(path) = local.promotion_path(prefix, identifier) else {
+            return;
+        };
+        let image = image.clone();
+        let identifier = identifier.to_owned();
+        let prefix = prefix.to_owned();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = super::store_image(&path, &image) {
+                eprintln!("{prefix}/{identifier}: promoting: {e}");
+            }
+        });
+    }
+}
+
+// A chain is an ImageLoader whose futures await those of other
+// ImageLoaders, so they are boxed, and named rather than left opaque, to
+// keep the types of either from containing themselves
+impl ChainLoader {
+    fn image<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+    ) -> BoxFuture<'a, DynamicImage> {
+        Box::pin(async move {
+            let (tier, image) = self
+                .first_found(|loader| {
+                    Box::pin(loader.get_image(prefix, identifier))
+                })
+                .await?;
+            if tier > 0 {
+                self.promote(prefix, identifier, &image);
+            }
+            Ok(image)
+        })
+    }
+
+    fn dimensions<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+    ) -> BoxFuture<'a, (u32, u32)> {
+        Box::pin(async move {
+            let (_, dimensions) = self
+                .first_found(|loader| {
+                    Box::pin(loader.get_dimensions(prefix, identifier))
+                })
+                .await?;
+            Ok(dimensions)
+        })
+    }
+
+    fn pages<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+    ) -> BoxFuture<'a, Vec<(u32, u32)>> {
+        Box::pin(async move {
+            let (_, pages) = self
+                .first_found(|loader| {
+                    Box::pin(loader.get_pages(prefix, identifier))
+                })
+                .await?;
+            Ok(pages)
+        })
+    }
+
+    fn version<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+    ) -> BoxFuture<'a, Option<String>> {
+        Box::pin(async move {
+            let (_, version) = self
+                .first_found(|loader| {
+                    Box::pin(loader.get_version(prefix, identifier))
+                })
+                .await?;
+            Ok(version)
+        })
+    }
+
+    fn region<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+        region: &'a Region,
+        size: &'a Size,
+    ) -> BoxFuture<'a, DynamicImage> {
+        Box::pin(async move {
+            // Promoting takes the whole image, rather than just the region
+            if self.promote {
+                let image = self.image(prefix, identifier).await?;
+                return crop_and_resize(image, region, size);
+            }
+            let (_, image) = self
+                .first_found(|loader| {
+                    Box::pin(
+                        loader.get_region(prefix, identifier, region, size),
+                    )
+                })
+                .await?;
+            Ok(image)
+        })
+    }
+}
+
+impl GenericImageLoader for ChainLoader {
+    async fn get_image(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<DynamicImage> {
+        self.image(prefix, identifier).await
+    }
+
+    async fn get_dimensions(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<(u32, u32)> {
+        self.dimensions(prefix, identifier).await
+    }
+
+    async fn get_pages(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<Vec<(u32, u32)>> {
+        self.pages(prefix, identifier).await
+    }
+
+    async fn get_version(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<Option<String>> {
+        self.version(prefix, identifier).await
+    }
+
+    async fn get_region(
+        &self,
+        prefix: &str,
+        identifier: &str,
+        region: &Region,
+        size: &Size,
+    ) -> Result<DynamicImage> {
+        self.region(prefix, identifier, region, size).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_loader::{ArchiveLoader, LocalLoader};
+    use image::{GenericImageView, ImageFormat};
+    use std::{path::Path, time::Duration};
+
+    fn local(prefix: &str, dir: &Path) -> ImageLoader {
+        let mut local = LocalLoader::new();
+        local.insert_dir_with_extensions(prefix, dir, ["png"]);
+        ImageLoader::Local(local)
+    }
+
+    #[tokio::test]
+    async fn test_chain_loader() {
+        let (fast, slow) =
+            (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        DynamicImage::new_rgb8(3, 2)
+            .save_with_format(slow.path().join("a.png"), ImageFormat::Png)
+            .unwrap();
+        std::fs::write(fast.path().join("broken.png"), b"not an image")
+            .unwrap();
+        DynamicImage::new_rgb8(1, 1)
+            .save_with_format(slow.path().join("broken.png"), ImageFormat::Png)
+            .unwrap();
+
+        let tiers = vec![local("p", fast.path()), local("p", slow.path())];
+        let chain = ChainLoader::new(tiers, true).unwrap();
+
+        let image = chain.get_image("p", "a").await.unwrap();
+        assert_eq!(image.dimensions(), (3, 2));
+        assert!(matches!(
+            chain.get_image("p", "missing").await,
+            Err(LoaderError::NotFound)
+        ));
+        // Only a missing image falls through to the next tier
+        assert!(chain.get_image("p", "broken").await.is_err());
+        assert!(matches!(
+            chain.get_image("p", "../a").await,
+            Err(LoaderError::InvalidIdentifier)
+        ));
+
+        // The image found in the slow tier is copied into the fast one
+        let promoted = fast.path().join("a.png");
+        for _ in 0..100 {
+            if promoted.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let image = image::open(&promoted).unwrap();
+        assert_eq!(image.dimensions(), (3, 2));
+    }
+
+    #[test]
+    fn test_chain_config() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            ChainLoader::new(vec![], false),
+            Err(LoaderError::Config(_))
+        ));
+        let archive =
+            ImageLoader::Archive(ArchiveLoader::new(dir.path(), ["png"]));
+        assert!(matches!(
+            ChainLoader::new(vec![archive], true),
+            Err(LoaderError::Config(_))
+        ));
+        let mut jpeg_only = LocalLoader::new();
+        jpeg_only.insert_dir_with_extensions("p", dir.path(), ["jpg"]);
+        assert!(matches!(
+            ChainLoader::new(vec![ImageLoader::Local(jpeg_only)], true),
+            Err(LoaderError::Config(_))
+        ));
+        assert!(ChainLoader::new(vec![local("p", dir.path())], true).is_ok());
+    }
+}
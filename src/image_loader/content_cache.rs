@@ -0,0 +1,341 @@
+use axum::http::header;
+use image::{DynamicImage, ImageFormat, ImageReader};
+use sha2::{Digest, Sha256};
+use std::{
+    ffi::OsStr,
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+
+use super::cache_index::{CacheIndex, IndexEntry, Validators};
+use super::eviction::remove_file;
+use super::{ContentCacheKey, LoaderError, Result, spawn_decode};
+
+/// Prefix of the temporary files downloads are written to
+const DOWNLOAD_PREFIX: &str = ".download-";
+/// How much of a download to look at to recognise its format
+pub const SNIFF_LEN: usize = 32;
+
+/// A response body, exactly as sent by the upstream, or other content to
+/// cache, in a temporary file in the cache directory. The file is removed if
+/// dropped before being moved into the cache.
+pub struct Download {
+    file: NamedTempFile,
+    content_hash: ContentCacheKey,
+    size: u64,
+}
+
+impl Download {
+    /// Write `bytes` to a temporary file in `cache_dir`.
+    pub fn from_bytes(cache_dir: &Path, bytes: &[u8]) -> Result<Self> {
+        let mut file = tempfile::Builder::new()
+            .prefix(DOWNLOAD_PREFIX)
+            .tempfile_in(cache_dir)?;
+        file.write_all(bytes)?;
+        file.as_file().sync_all()?;
+        Ok(Self {
+            file,
+            content_hash: Sha256::digest(bytes).into(),
+            size: bytes.len() as u64,
+        })
+    }
+
+    /// Decode the downloaded image without moving it into the cache, as for
+    /// a response the upstream doesn't allow to be stored.
+    pub async fn decode(self, format: ImageFormat) -> Result<DynamicImage> {
+        spawn_decode(move || {
+            let mut reader = ImageReader::open(self.file.path())?;
+            reader.set_format(format);
+            Ok(reader.decode()?)
+        })
+        .await
+    }
+}
+
+/// Stream the body of `response` into a temporary file in `cache_dir`,
+/// hashing it on the way, and work out its image format. Bodies over
+/// `max_bytes` or that turn out not to be images are given up on as soon as
+/// that is known.
+pub async fn download(
+    response: &mut reqwest::Response,
+    cache_dir: &Path,
+    max_bytes: u64,
+) -> Result<(Download, ImageFormat)> {
+    let uri = response.url().clone();
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let hint = content_type
+        .as_deref()
+        .and_then(ImageFormat::from_mime_type)
+        .or_else(|| {
+            let filename = uri.path_segments()?.next_back()?;
+            let (_, ext) = filename.rsplit_once('.')?;
+            ImageFormat::from_extension(ext)
+        });
+    let detect_format = |head: &[u8]| {
+        let format = detect_format(head, hint).ok_or_else(|| {
+            LoaderError::Upstream(format!(
+                "{uri} did not return an image (Content-Type: {})",
+                content_type.as_deref().unwrap_or("none")
+            ))
+        })?;
+        if format.reading_enabled() {
+            Ok(format)
+        } else {
+            Err(LoaderError::UnsupportedFormat)
+        }
+    };
+
+    let too_large = || {
+        LoaderError::Upstream(format!("{uri} is larger than {max_bytes} bytes"))
+    };
+    if response.content_length().is_some_and(|len| len > max_bytes) {
+        return Err(too_large());
+    }
+
+    let file = tempfile::Builder::new()
+        .prefix(DOWNLOAD_PREFIX)
+        .tempfile_in(cache_dir)?;
+    let mut writer = tokio::fs::File::from_std(file.as_file().try_clone()?);
+    let mut sha256 = Sha256::new();
+    let mut size = 0;
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    let mut format = None;
+    while let Some(chunk) = response.chunk().await? {
+        size += chunk.len() as u64;
+        if size > max_bytes {
+            return Err(too_large());
+        }
+        // Give up on content that isn't an image as soon as possible
+        if format.is_none() {
+            let missing = SNIFF_LEN - head.len();
+            head.extend_from_slice(&chunk[..chunk.len().min(missing)]);
+            if head.len() == SNIFF_LEN {
+                format = Some(detect_format(&head)?);
+            }
+        }
+        sha256.update(&chunk);
+        writer.write_all(&chunk).await?;
+    }
+    let format = match format {
+        Some(format) => format,
+        None => detect_format(&head)?,
+    };
+    writer.flush().await?;
+    writer.sync_all().await?;
+
+    let download = Download {
+        file,
+        content_hash: sha256.finalize().into(),
+        size,
+    };
+    Ok((download, format))
+}
+
+/// Move `download` of `uri` into the cache, keyed by its hash, and return
+/// the key.
+pub fn store(
+    cache_dir: &Path,
+    index: &CacheIndex,
+    download: Download,
+    uri: &str,
+    format: ImageFormat,
+    validators: Validators,
+    expires_at: SystemTime,
+) -> Result<ContentCacheKey> {
+    let Download {
+        file,
+        content_hash,
+        size,
+    } = download;
+
+    let cache_path = cached_img_path(cache_dir, &content_hash);
+
+    // Identical content may already be cached under another URI, in which
+    // case the download is dropped and removed
+    if !cache_path.exists() {
+        let leaf_dir = cache_path.parent().unwrap();
+        std::fs::create_dir_all(leaf_dir)?;
+        file.persist(&cache_path).map_err(|e| e.error)?;
+    }
+
+    let now = SystemTime::now();
+    let entry = IndexEntry {
+        content_hash,
+        format,
+        fetched_at: now,
+        expires_at,
+        validators,
+    };
+    index.insert(uri, &entry)?;
+    index.insert_content(&content_hash, size, now)?;
+    Ok(content_hash)
+}
+
+/// Stop serving `uri` from the cache, and remove what it mapped to unless
+/// another URI maps to the same content. This blocks, so run it on the
+/// blocking thread pool.
+pub fn forget(cache_dir: &Path, index: &CacheIndex, uri: &str) -> Result<()> {
+    if let Some(entry) = index.get(uri)? {
+        index.remove(uri)?;
+        remove_unreferenced(cache_dir, index, &entry.content_hash)?;
+    }
+    Ok(())
+}
+
+/// Forget the content `content_hash` and remove its file, unless some URI
+/// still maps to it.
+pub fn remove_unreferenced(
+    cache_dir: &Path,
+    index: &CacheIndex,
+    content_hash: &ContentCacheKey,
+) -> Result<()> {
+    if !index.is_referenced(content_hash)? {
+        index.remove_content(content_hash)?;
+        remove_file(&cached_img_path(cache_dir, content_hash))?;
+    }
+    Ok(())
+}
+
+/// [`store`], run on the blocking thread pool.
+pub async fn spawn_store(
+    cache_dir: &Path,
+    index: &Arc<CacheIndex>,
+    download: Download,
+    uri: &str,
+    format: ImageFormat,
+    validators: Validators,
+    expires_at: SystemTime,
+) -> Result<ContentCacheKey> {
+    let cache_dir = cache_dir.to_owned();
+    let index = Arc::clone(index);
+    let uri = uri.to_owned();
+    tokio::task::spawn_blocking(move || {
+        store(
+            &cache_dir, &index, download, &uri, format, validators, expires_at,
+        )
+    })
+    .await
+    .expect("storing in the cache panicked")
+}
+
+/// Decode the cached content `key` and note that it was used.
+pub async fn decode_cached(
+    cache_dir: &Path,
+    index: &Arc<CacheIndex>,
+    key: &ContentCacheKey,
+    format: ImageFormat,
+) -> Result<DynamicImage> {
+    let path = cached_img_path(cache_dir, key);
+    let image = spawn_decode(move || {
+        let mut reader = ImageReader::open(&path)?;
+        reader.set_format(format);
+        Ok(reader.decode()?)
+    })
+    .await?;
+    let key = *key;
+    index
+        .run(move |index| index.touch_content(&key, SystemTime::now()))
+        .await?;
+    Ok(image)
+}
+
+/// Work out the format of an upstream image from the first bytes of its body.
+/// The format `hint`ed at by the response headers or URL is only trusted for
+/// formats that have no signature to recognise them by.
+pub fn detect_format(
+    head: &[u8],
+    hint: Option<ImageFormat>,
+) -> Option<ImageFormat> {
+    image::guess_format(head)
+        .ok()
+        .or(hint.filter(|format| *format == ImageFormat::Tga))
+}
+
+/// Remove temporary files left behind by downloads that were under way when
+/// a previous run stopped.
+pub fn remove_interrupted_downloads(cache_dir: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .as_bytes()
+            .starts_with(DOWNLOAD_PREFIX.as_bytes())
+        {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// The version of the content cached for `entry`: the hash of the content,
+/// as long as the entry is fresh at `now` and the content is still on disk.
+/// Stale entries have no version, as the next load revalidates them.
+pub fn cached_version(
+    cache_dir: &Path,
+    entry: &IndexEntry,
+    now: SystemTime,
+) -> Option<String> {
+    (entry.expires_at > now
+        && cached_img_path(cache_dir, &entry.content_hash).is_file())
+    .then(|| {
+        let mut hex = [0; size_of::<ContentCacheKey>() * 2];
+        base16ct::lower::encode_str(&entry.content_hash, &mut hex)
+            .expect("buffer fits the hash")
+            .to_owned()
+    })
+}
+
+pub fn cached_img_path(cache: &Path, key: &ContentCacheKey) -> PathBuf {
+    const HEX_STR_LEN: usize = size_of::<ContentCacheKey>() * 2;
+    let mut key_str: [u8; HEX_STR_LEN] = [0; HEX_STR_LEN];
+    base16ct::lower::encode(key, &mut key_str).unwrap();
+    let sub1 = OsStr::from_bytes(&key_str[0..2]);
+    let sub2 = OsStr::from_bytes(&key_str[2..4]);
+    let mut path = PathBuf::with_capacity(
+        cache.as_os_str().len() + sub1.len() + sub2.len() + key_str.len(),
+    );
+    path.push(cache);
+    path.push(sub1);
+    path.push(sub2);
+    path.push(OsStr::from_bytes(&key_str));
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_detect_format() {
+        let mut png = Cursor::new(vec![]);
+        DynamicImage::new_rgb8(1, 1)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let png = &png.get_ref()[..SNIFF_LEN];
+
+        assert_eq!(detect_format(png, None), Some(ImageFormat::Png));
+        assert_eq!(
+            detect_format(png, Some(ImageFormat::Jpeg)),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(detect_format(b"<!doctype html>", None), None);
+        assert_eq!(
+            detect_format(b"<!doctype html>", Some(ImageFormat::Jpeg)),
+            None
+        );
+        assert_eq!(
+            detect_format(b"\0\0\x02", Some(ImageFormat::Tga)),
+            Some(ImageFormat::Tga)
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use image::ImageFormat;
+use serde::Deserialize;
+use std::{
+    io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use super::cache_index::{CacheIndex, Validators};
+use super::content_cache::{
+    Download, cached_img_path, remove_interrupted_downloads,
+    remove_unreferenced, store,
+};
+use super::eviction::{self, EvictionPolicy};
+use super::pages::split_page;
+use super::{LoaderError, Result};
+
+const CACHE_INDEX_FILE: &str = "index.sqlite3";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DerivativeCacheConfig {
+    /// Where to keep encoded responses, and the index that maps requests to
+    /// them
+    pub dir: PathBuf,
+    /// Evict least recently used responses once the cache grows past this
+    pub max_cache_bytes: Option<u64>,
+    /// Evict responses stored longer ago than this, however recently used
+    pub max_entry_age_secs: Option<u64>,
+    /// How often to check the cache against the limits above
+    #[serde(default = "default_sweep_interval_secs")]
+    pub sweep_interval_secs: u64,
+}
+
+fn default_sweep_interval_secs() -> u64 {
+    5 * 60
+}
+
+impl DerivativeCacheConfig {
+    /// Configuration for caching into `dir` without limits.
+    pub fn new<T: Into<PathBuf>>(dir: T) -> Self {
+        Self {
+            dir: dir.into(),
+            max_cache_bytes: None,
+            max_entry_age_secs: None,
+            sweep_interval_secs: default_sweep_interval_secs(),
+        }
+    }
+}
+
+/// Encoded responses to image requests, kept on disk so that a request
+/// made before is answered without decoding its image again. Responses are
+/// looked up by the canonical form of their request, and only served while
+/// the image they were made from is at the same version.
+#[derive(Debug)]
+pub struct DerivativeCache {
+    dir: PathBuf,
+    index: Arc<CacheIndex>,
+    eviction: EvictionPolicy,
+    sweep_interval: Duration,
+}
+
+impl DerivativeCache {
+    /// Create a cache in `config.dir`, serving the responses stored there
+    /// by earlier runs.
+    pub fn new(config: DerivativeCacheConfig) -> Result<Self> {
+        let DerivativeCacheConfig {
+            dir,
+            max_cache_bytes,
+            max_entry_age_secs,
+            sweep_interval_secs,
+        } = config;
+        std::fs::create_dir_all(&dir)?;
+        remove_interrupted_downloads(&dir)?;
+        let index = Arc::new(CacheIndex::open(dir.join(CACHE_INDEX_FILE))?);
+        Ok(Self {
+            dir,
+            index,
+            eviction: EvictionPolicy {
+                max_size: max_cache_bytes,
+                max_age: max_entry_age_secs.map(Duration::from_secs),
+            },
+            sweep_interval: Duration::from_secs(sweep_interval_secs),
+        })
+    }
+
+    /// Periodically evict responses from the cache directory in the
+    /// background, if the cache has a size or age limit.
+    pub fn spawn_sweeper(&self) {
+        eviction::spawn_sweeper(
+            self.dir.clone(),
+            Arc::clone(&self.index),
+            self.eviction,
+            self.sweep_interval,
+        );
+    }
+
+    /// The response stored for `request` of `prefix`, if it was made from
+    /// `version` of its image. One made from another version is forgotten.
+    pub async fn get(
+        &self,
+        prefix: &str,
+        request: &str,
+        version: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        let key = cache_key(prefix, request);
+        let version = version.to_owned();
+        let dir = self.dir.clone();
+        let index = Arc::clone(&self.index);
+        let entry = tokio::task::spawn_blocking(move || -> Result<_> {
+            let Some(entry) = index.get(&key)? else {
+                return Ok(None);
+            };
+            if entry.validators.etag.as_deref() != Some(&version) {
+                index.remove(&key)?;
+                remove_unreferenced(&dir, &index, &entry.content_hash)?;
+                return Ok(None);
+            }
+            Ok(Some(entry))
+        })
+        .await
+        .expect("derivative cache lookup panicked")?;
+        let Some(entry) = entry else {
+            return Ok(None);
+        };
+        let path = cached_img_path(&self.dir, &entry.content_hash);
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(bytes) => bytes,
+            // Evicted since it was looked up
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(None);
+            }
+            Err(e) => return Err(LoaderError::from(e)),
+        };
+        self.index
+            .run(move |index| {
+                index.touch_content(&entry.content_hash, SystemTime::now())
+            })
+            .await?;
+        Ok(Some(bytes))
+    }
+
+    /// Store `bytes`, the response to `request` for the image `identifier`
+    /// of `prefix` made from `version` of it, replacing any stored before.
+    /// This blocks, so run it on the blocking thread pool.
+    pub fn insert(
+        &self,
+        prefix: &str,
+        identifier: &str,
+        request: &str,
+        version: &str,
+        format: ImageFormat,
+        bytes: &[u8],
+    ) -> Result<()> {
+        let download = Download::from_bytes(&self.dir, bytes)?;
+        // The version of the source plays the part of an upstream's ETag
+        let validators = Validators {
+            etag: Some(version.to_owned()),
+            last_modified: None,
+        };
+        let key = cache_key(prefix, request);
+        let now = SystemTime::now();
+        let replaced = self.index.get(&key)?;
+        let content_hash = store(
+            &self.dir,
+            &self.index,
+            download,
+            &key,
+            format,
+            validators,
+            now,
+        )?;
+        self.index.set_source(&key, &source(prefix, identifier))?;
+        if let Some(replaced) = replaced
+            && replaced.content_hash != content_hash
+        {
+            remove_unreferenced(
+                &self.dir,
+                &self.index,
+                &replaced.content_hash,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Evict the responses to requests for every page of the image
+    /// `identifier` of `prefix`. This blocks, so run it on the blocking
+    /// thread pool.
+    pub fn remove(&self, prefix: &str, identifier: &str) -> Result<()> {
+        let removed =
+            self.index.remove_by_source(&source(prefix, identifier))?;
+        for content_hash in removed {
+            remove_unreferenced(&self.dir, &self.index, &content_hash)?;
+        }
+        Ok(())
+    }
+}
+
+fn cache_key(prefix: &str, request: &str) -> String {
+    format!("{prefix}/{request}")
+}
+
+/// What responses for `identifier` of `prefix` are made from: the file
+/// behind all of its pages. Prefixes have no slashes, so this is unique.
+fn source(prefix: &str, identifier: &str) -> String {
+    let file = split_page(identifier).map_or(identifier, |(file, _)| file);
+    format!("{prefix}/{file}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::image::ImageRequest;
+
+    #[tokio::test]
+    async fn test_derivative_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache =
+            DerivativeCache::new(DerivativeCacheConfig::new(dir.path()))
+                .unwrap();
+        let request: ImageRequest =
+            "a/full/max/360/default.jpeg".parse().unwrap();
+        let request = request.to_string();
+
+        assert_eq!(cache.get("p", &request, "v1").await.unwrap(), None);
+        cache
+            .insert("p", "a", &request, "v1", ImageFormat::Jpeg, b"jpeg")
+            .unwrap();
+        // Equivalent requests share the response
+        let same: ImageRequest = "a/full/max/0/default.jpg".parse().unwrap();
+        assert_eq!(
+            cache.get("p", &same.to_string(), "v1").await.unwrap(),
+            Some(b"jpeg".to_vec())
+        );
+        assert_eq!(cache.get("q", &request, "v1").await.unwrap(), None);
+
+        // Responses made from another version of the image are not served,
+        // even once it changes back, and are removed
+        assert_eq!(cache.get("p", &request, "v2").await.unwrap(), None);
+        assert_eq!(cache.get("p", &request, "v1").await.unwrap(), None);
+        assert!(cache.index.content_hashes().unwrap().is_empty());
+        // As are responses replaced by another
+        for bytes in [b"old jpeg", b"new jpeg"] {
+            cache
+                .insert("p", "a", &request, "v2", ImageFormat::Jpeg, bytes)
+                .unwrap();
+        }
+        assert_eq!(cache.index.content_hashes().unwrap().len(), 1);
+
+        // Responses survive a restart
+        drop(cache);
+        let cache =
+            DerivativeCache::new(DerivativeCacheConfig::new(dir.path()))
+                .unwrap();
+        assert_eq!(
+            cache.get("p", &request, "v2").await.unwrap(),
+            Some(b"new jpeg".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_derivative_cache_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache =
+            DerivativeCache::new(DerivativeCacheConfig::new(dir.path()))
+                .unwrap();
+        for (request, bytes) in [("a", [1; 100]), ("b", [2; 100])] {
+            cache
+                .insert("p", request, request, "v", ImageFormat::Png, &bytes)
+                .unwrap();
+        }
+        let policy = EvictionPolicy {
+            max_size: Some(150),
+            max_age: None,
+        };
+        let now = SystemTime::now();
+        let evicted =
+            eviction::sweep(&cache.dir, &cache.index, policy, now).unwrap();
+        assert_eq!(evicted, 1);
+        let kept = [
+            cache.get("p", "a", "v").await.unwrap(),
+            cache.get("p", "b", "v").await.unwrap(),
+        ];
+        assert_eq!(kept.iter().flatten().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_derivative_cache_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache =
+            DerivativeCache::new(DerivativeCacheConfig::new(dir.path()))
+                .unwrap();
+        let requests = [
+            ("books", "books/full/max/0/default.png"),
+            ("books;2", "books;2/full/max/0/default.png"),
+            ("books/1", "books/1/full/max/0/default.png"),
+            ("books.tif", "books.tif/full/max/0/default.png"),
+        ];
+        for (identifier, request) in requests {
+            cache
+                .insert("p", identifier, request, "v", ImageFormat::Png, b"png")
+                .unwrap();
+        }
+
+        // Every page of the image goes, but nothing that only shares the
+        // start of its identifier
+        cache.remove("p", "books").unwrap();
+        let mut kept = vec![];
+        for (identifier, request) in requests {
+            if cache.get("p", request, "v").await.unwrap().is_some() {
+                kept.push(identifier);
+            }
+        }
+        assert_eq!(kept, ["books/1", "books.tif"]);
+    }
+}
@@ -0,0 +1,217 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use walkdir::WalkDir;
+
+use super::cache_index::CacheIndex;
+use super::content_cache::cached_img_path;
+use super::{ContentCacheKey, Result};
+
+/// Bounds on what a cache directory may hold.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EvictionPolicy {
+    pub max_size: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+impl EvictionPolicy {
+    pub fn is_unbounded(&self) -> bool {
+        self.max_size.is_none() && self.max_age.is_none()
+    }
+}
+
+/// Periodically evict content from `cache_dir` in the background, if
+/// `policy` bounds it. The first run also reconciles the index with the
+/// files on disk.
+pub fn spawn_sweeper(
+    cache_dir: PathBuf,
+    index: Arc<CacheIndex>,
+    policy: EvictionPolicy,
+    interval: Duration,
+) {
+    if policy.is_unbounded() {
+        return;
+    }
+    let mut interval = tokio::time::interval(interval);
+    tokio::spawn(async move {
+        let mut reconciled = false;
+        loop {
+            interval.tick().await;
+            let result = tokio::task::spawn_blocking({
+                let cache_dir = cache_dir.clone();
+                let index = Arc::clone(&index);
+                move || {
+                    if !reconciled {
+                        reconcile(&cache_dir, &index)?;
+                    }
+                    sweep(&cache_dir, &index, policy, SystemTime::now())
+                }
+            })
+            .await
+            .expect("cache sweeper panicked");
+            match result {
+                Ok(_) => reconciled = true,
+                Err(e) => {
+                    eprintln!(
+                        "{}: cache sweep failed: {e}",
+                        cache_dir.display()
+                    )
+                }
+            }
+        }
+    });
+}
+
+/// Delete cached content that is too old or, least recently used first,
+/// that doesn't fit in the cache, along with the URIs mapped to it. Returns
+/// the number of files removed.
+pub fn sweep(
+    cache_dir: &Path,
+    index: &CacheIndex,
+    policy: EvictionPolicy,
+    now: SystemTime,
+) -> Result<usize> {
+    let stored_before = policy.max_age.and_then(|age| now.checked_sub(age));
+    let candidates =
+        index.eviction_candidates(policy.max_size, stored_before)?;
+    for content_hash in &candidates {
+        // Forget the content before deleting it, so that no request is
+        // pointed at a file that is about to disappear
+        index.remove_content(content_hash)?;
+        remove_file(&cached_img_path(cache_dir, content_hash))?;
+    }
+    Ok(candidates.len())
+}
+
+/// Bring the index in line with the files in the cache directory: content
+/// whose file is gone is forgotten, files that are not yet tracked but are
+/// mapped to by a URI are added, and files nothing refers to are deleted.
+pub fn reconcile(cache_dir: &Path, index: &CacheIndex) -> Result<()> {
+    for content_hash in index.content_hashes()? {
+        let path = cached_img_path(cache_dir, &content_hash);
+        if !path.is_file() {
+            index.remove_content(&content_hash)?;
+        }
+    }
+
+    let files = WalkDir::new(cache_dir)
+        .min_depth(3)
+        .max_depth(3)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file());
+    for entry in files {
+        let Some(content_hash) =
+            entry.file_name().to_str().and_then(parse_content_hash)
+        else {
+            continue;
+        };
+        if index.has_content(&content_hash)? {
+            continue;
+        }
+        if index.is_referenced(&content_hash)? {
+            let metadata = entry.metadata().map_err(io::Error::from)?;
+            let stored_at = metadata.modified().unwrap_or(SystemTime::now());
+            index.insert_content(&content_hash, metadata.len(), stored_at)?;
+        } else {
+            remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_content_hash(file_name: &str) -> Option<ContentCacheKey> {
+    let mut content_hash = ContentCacheKey::default();
+    let decoded = base16ct::lower::decode(file_name, &mut content_hash).ok()?;
+    (decoded.len() == size_of::<ContentCacheKey>()).then_some(content_hash)
+}
+
+/// Remove the file at `path`, if it is still there.
+pub fn remove_file(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_loader::cache_index::{IndexEntry, Validators};
+    use image::ImageFormat;
+
+    fn store(
+        cache_dir: &Path,
+        index: &CacheIndex,
+        content_hash: ContentCacheKey,
+        stored_at: SystemTime,
+    ) {
+        let path = cached_img_path(cache_dir, &content_hash);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, [0; 100]).unwrap();
+        let uri = format!("https://example.org/{}", content_hash[0]);
+        let entry = IndexEntry {
+            content_hash,
+            format: ImageFormat::Png,
+            fetched_at: stored_at,
+            expires_at: stored_at,
+            validators: Validators::default(),
+        };
+        index.insert(&uri, &entry).unwrap();
+        index.insert_content(&content_hash, 100, stored_at).unwrap();
+    }
+
+    #[test]
+    fn test_sweep() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = CacheIndex::open(dir.path().join("index.sqlite3")).unwrap();
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let minutes = |n: u64| t0 + Duration::from_secs(60 * n);
+        for (i, stored_at) in [minutes(0), minutes(10), minutes(20)]
+            .into_iter()
+            .enumerate()
+        {
+            store(dir.path(), &index, [i as u8; 32], stored_at);
+        }
+        // The oldest content is the most recently used one
+        index.touch_content(&[0; 32], minutes(30)).unwrap();
+
+        let policy = EvictionPolicy {
+            max_size: Some(250),
+            max_age: None,
+        };
+        assert_eq!(sweep(dir.path(), &index, policy, minutes(30)).unwrap(), 1);
+        assert!(!cached_img_path(dir.path(), &[1; 32]).exists());
+        assert_eq!(index.get("https://example.org/1").unwrap(), None);
+        assert!(index.get("https://example.org/0").unwrap().is_some());
+
+        let policy = EvictionPolicy {
+            max_size: None,
+            max_age: Some(Duration::from_secs(15 * 60)),
+        };
+        assert_eq!(sweep(dir.path(), &index, policy, minutes(30)).unwrap(), 1);
+        assert!(!cached_img_path(dir.path(), &[0; 32]).exists());
+        assert!(cached_img_path(dir.path(), &[2; 32]).exists());
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = CacheIndex::open(dir.path().join("index.sqlite3")).unwrap();
+        let now = SystemTime::now();
+        store(dir.path(), &index, [1; 32], now);
+        store(dir.path(), &index, [2; 32], now);
+        std::fs::remove_file(cached_img_path(dir.path(), &[1; 32])).unwrap();
+        let orphan = cached_img_path(dir.path(), &[3; 32]);
+        std::fs::create_dir_all(orphan.parent().unwrap()).unwrap();
+        std::fs::write(&orphan, b"").unwrap();
+
+        reconcile(dir.path(), &index).unwrap();
+        assert_eq!(index.content_hashes().unwrap(), [[2; 32]]);
+        assert_eq!(index.get("https://example.org/1").unwrap(), None);
+        assert!(!orphan.exists());
+    }
+}
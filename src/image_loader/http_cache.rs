@@ -0,0 +1,127 @@
+use reqwest::header::{self, HeaderMap};
+use std::time::{Duration, SystemTime};
+
+/// Work out until when a response from an upstream may be served from the
+/// cache without revalidation, as a shared cache would under RFC 9111, or
+/// `None` if it must not be stored at all. Responses that don't state a
+/// freshness lifetime are kept fresh for `default_max_age`.
+pub fn expires_at(
+    headers: &HeaderMap,
+    now: SystemTime,
+    default_max_age: Duration,
+) -> Option<SystemTime> {
+    let header_str =
+        |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let header_date = |name| header_str(name).and_then(parse_http_date);
+
+    let mut max_age = None;
+    let mut s_maxage = None;
+    let mut revalidate = false;
+    for directive in header_str(header::CACHE_CONTROL)
+        .into_iter()
+        .flat_map(|value| value.split(','))
+    {
+        let (name, value) = directive
+            .split_once('=')
+            .map_or((directive, None), |(name, value)| (name, Some(value)));
+        let seconds = || {
+            value?
+                .trim()
+                .trim_matches('"')
+                .parse()
+                .ok()
+                .map(Duration::from_secs)
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "no-store" => return None,
+            "no-cache" | "private" => revalidate = true,
+            "max-age" => max_age = seconds(),
+            "s-maxage" => s_maxage = seconds(),
+            _ => {}
+        }
+    }
+    if revalidate {
+        return Some(now);
+    }
+
+    let lifetime = s_maxage.or(max_age).or_else(|| {
+        // An invalid or past Expires means already stale
+        let expires = header_str(header::EXPIRES)?;
+        let Some(expires) = parse_http_date(expires) else {
+            return Some(Duration::ZERO);
+        };
+        let date = header_date(header::DATE).unwrap_or(now);
+        Some(expires.duration_since(date).unwrap_or_default())
+    });
+    let age = header_str(header::AGE)
+        .and_then(|age| age.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+
+    Some(now + lifetime.unwrap_or(default_max_age).saturating_sub(age))
+}
+
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expires_at() {
+        let now =
+            httpdate::parse_http_date("Sun, 18 Oct 2026 12:00:00 GMT").unwrap();
+        let default = Duration::from_secs(600);
+        let expires = |pairs: &[(header::HeaderName, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(name, value.parse().unwrap());
+            }
+            expires_at(&headers, now, default).map(|expires_at| {
+                expires_at.duration_since(now).unwrap_or_default().as_secs()
+            })
+        };
+
+        assert_eq!(expires(&[]), Some(600));
+        assert_eq!(expires(&[(header::CACHE_CONTROL, "max-age=60")]), Some(60));
+        assert_eq!(
+            expires(&[(
+                header::CACHE_CONTROL,
+                "public, max-age=60, s-maxage=30"
+            )]),
+            Some(30)
+        );
+        assert_eq!(
+            expires(&[(header::CACHE_CONTROL, "max-age=60, no-cache")]),
+            Some(0)
+        );
+        assert_eq!(
+            expires(&[
+                (header::CACHE_CONTROL, "max-age=60"),
+                (header::AGE, "20")
+            ]),
+            Some(40)
+        );
+        assert_eq!(
+            expires(&[
+                (header::DATE, "Sun, 18 Oct 2026 11:00:00 GMT"),
+                (header::EXPIRES, "Sun, 18 Oct 2026 11:05:00 GMT")
+            ]),
+            Some(300)
+        );
+        assert_eq!(expires(&[(header::EXPIRES, "0")]), Some(0));
+
+        // Responses that must not be stored are told apart from stale ones
+        assert_eq!(expires(&[(header::CACHE_CONTROL, "no-store")]), None);
+        assert_eq!(
+            expires(&[(header::CACHE_CONTROL, "no-cache, no-store")]),
+            None
+        );
+        assert_eq!(
+            expires(&[(header::CACHE_CONTROL, "private, max-age=60")]),
+            Some(0)
+        );
+    }
+}
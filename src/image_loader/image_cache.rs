@@ -0,0 +1,409 @@
+use image::{DynamicImage, GenericImageView};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::sync::OnceCell;
+
+use super::pages::split_page;
+use super::{FlightGuard, GenericImageLoader, LoaderError, Result};
+use crate::api::image::{Region, Size};
+use crate::image_ops::{region_rect, resize_image};
+
+type Key = (String, String);
+/// A decode of one version of an image, shared by all requests for it that
+/// arrive while it is under way.
+type Flight = Arc<OnceCell<Result<Arc<DynamicImage>>>>;
+
+/// Decoded images, kept in memory up to a total size so that requests for
+/// tiles of the same image decode it only once. Images are cached under
+/// their prefix and identifier along with the version their loader gives
+/// them, and only if it gives them one, so that a changed image is decoded
+/// afresh.
+#[derive(Debug)]
+pub struct ImageCache {
+    max_bytes: u64,
+    lru: Mutex<Lru>,
+    in_flight: Mutex<HashMap<(Key, String), Flight>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// How well an [`ImageCache`] is doing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImageCacheStats {
+    /// Images served from memory, including those decoded for another
+    /// request at the same time
+    pub hits: u64,
+    /// Images that were not in memory, and were decoded or had their
+    /// dimensions read by their loader
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<Key, Entry>,
+    /// Keys by when they were last used, least recently first
+    order: BTreeMap<u64, Key>,
+    clock: u64,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    version: String,
+    image: Arc<DynamicImage>,
+    bytes: u64,
+    last_used: u64,
+}
+
+impl Lru {
+    fn get(&mut self, key: &Key, version: &str) -> Option<Arc<DynamicImage>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        if entry.version != version {
+            self.remove(key);
+            return None;
+        }
+        self.order.remove(&entry.last_used);
+        entry.last_used = self.clock;
+        self.order.insert(self.clock, key.clone());
+        Some(Arc::clone(&entry.image))
+    }
+
+    fn insert(
+        &mut self,
+        key: Key,
+        version: String,
+        image: Arc<DynamicImage>,
+        max_bytes: u64,
+    ) {
+        let bytes = image.as_bytes().len() as u64;
+        self.remove(&key);
+        if bytes > max_bytes {
+            return;
+        }
+        while self.bytes + bytes > max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.bytes -= entry.bytes;
+            }
+        }
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        self.bytes += bytes;
+        let entry = Entry {
+            version,
+            image,
+            bytes,
+            last_used: self.clock,
+        };
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.last_used);
+            self.bytes -= entry.bytes;
+        }
+    }
+}
+
+impl ImageCache {
+    /// Keep up to `max_bytes` of decoded pixels. With 0, nothing is kept.
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            lru: Mutex::default(),
+            in_flight: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> ImageCacheStats {
+        let lru = self.lru.lock().unwrap();
+        ImageCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: lru.entries.len(),
+            bytes: lru.bytes,
+            max_bytes: self.max_bytes,
+        }
+    }
+
+    /// Evict every page of the image `identifier` of `prefix`.
+    pub fn remove(&self, prefix: &str, identifier: &str) {
+        let mut lru = self.lru.lock().unwrap();
+        let keys: Vec<Key> = lru
+            .entries
+            .keys()
+            .filter(|(p, id)| {
+                p == prefix
+                    && split_page(id).is_ok_and(|(id, _)| id == identifier)
+            })
+            .cloned()
+            .collect();
+        for key in &keys {
+            lru.remove(key);
+        }
+    }
+
+    /// The `region` of an image from `loader`, scaled to `size`, cropped
+    /// from the cached image if the loader gives it a version.
+    pub async fn get_region<L: GenericImageLoader>(
+        &self,
+        loader: &L,
+        prefix: &str,
+        identifier: &str,
+        region: &Region,
+        size: &Size,
+    ) -> Result<DynamicImage> {
+        let Some(image) = self.get_image(loader, prefix, identifier).await?
+        else {
+            return loader.get_region(prefix, identifier, region, size).await;
+        };
+        let (x, y, w, h) = region_rect(image.width(), image.height(), region);
+        resize_image(image.crop_imm(x, y, w, h), size)
+            .map_err(|_| LoaderError::InvalidSize)
+    }
+
+    /// The width and height of an image from `loader`, from the cache if it
+    /// is there.
+    pub async fn get_dimensions<L: GenericImageLoader>(
+        &self,
+        loader: &L,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<(u32, u32)> {
+        if self.max_bytes > 0
+            && let Some(version) =
+                loader.get_version(prefix, identifier).await?
+        {
+            let key = (prefix.to_owned(), identifier.to_owned());
+            let cached = self.lru.lock().unwrap().get(&key, &version);
+            if let Some(image) = cached {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(image.dimensions());
+            }
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        loader.get_dimensions(prefix, identifier).await
+    }
+
+    /// The whole image, from the cache or else decoded and cached, or None
+    /// if the loader gives it no version to cache it under.
+    async fn get_image<L: GenericImageLoader>(
+        &self,
+        loader: &L,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<Option<Arc<DynamicImage>>> {
+        if self.max_bytes == 0 {
+            return Ok(None);
+        }
+        let Some(version) = loader.get_version(prefix, identifier).await?
+        else {
+            return Ok(None);
+        };
+        let key = (prefix.to_owned(), identifier.to_owned());
+        let cached = self.lru.lock().unwrap().get(&key, &version);
+        if let Some(image) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(image));
+        }
+
+        // Requests for an image that is already being decoded wait for that
+        // decode instead of starting their own
+        let flight_key = (key, version);
+        let flight = FlightGuard::join(&self.in_flight, flight_key.clone());
+        let mut decoded = false;
+        let result = flight
+            .get_or_init(|| async {
+                decoded = true;
+                let image = loader.get_image(prefix, identifier).await?;
+                let image = Arc::new(image);
+                let ((prefix, identifier), version) = flight_key.clone();
+                self.lru.lock().unwrap().insert(
+                    (prefix, identifier),
+                    version,
+                    Arc::clone(&image),
+                    self.max_bytes,
+                );
+                Ok(image)
+            })
+            .await
+            .clone();
+        let counter = if decoded { &self.misses } else { &self.hits };
+        counter.fetch_add(1, Ordering::Relaxed);
+        result.map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_loader::LocalLoader;
+    use image::ImageFormat;
+    use std::{path::Path, sync::atomic::AtomicUsize, time::Duration};
+
+    /// Serves blank images, slowly, counting how many it decodes.
+    #[derive(Debug, Default)]
+    struct Counting {
+        decodes: AtomicUsize,
+        version: Option<String>,
+    }
+
+    impl GenericImageLoader for Counting {
+        async fn get_image(
+            &self,
+            _prefix: &str,
+            identifier: &str,
+        ) -> Result<DynamicImage> {
+            self.decodes.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            match identifier {
+                "missing" => Err(LoaderError::NotFound),
+                _ => Ok(DynamicImage::new_rgb8(10, 10)),
+            }
+        }
+
+        async fn get_version(
+            &self,
+            _prefix: &str,
+            _identifier: &str,
+        ) -> Result<Option<String>> {
+            Ok(self.version.clone())
+        }
+    }
+
+    fn save(path: &Path, width: u32, height: u32) {
+        DynamicImage::new_rgb8(width, height)
+            .save_with_format(path, ImageFormat::Png)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_image_cache() {
+        let loader = Counting {
+            version: Some("1".into()),
+            ..Counting::default()
+        };
+        let cache = ImageCache::new(1 << 20);
+        let (full, max): (Region, Size) =
+            ("full".parse().unwrap(), "max".parse().unwrap());
+
+        // Concurrent requests for the same image decode it once
+        let requests =
+            (0..4).map(|_| cache.get_region(&loader, "p", "a", &full, &max));
+        for image in futures_util::future::join_all(requests).await {
+            assert_eq!(image.unwrap().dimensions(), (10, 10));
+        }
+        assert_eq!(loader.decodes.load(Ordering::Relaxed), 1);
+        let region = "0,0,5,4".parse().unwrap();
+        let image = cache
+            .get_region(&loader, "p", "a", &region, &max)
+            .await
+            .unwrap();
+        assert_eq!(image.dimensions(), (5, 4));
+        assert_eq!(
+            cache.get_dimensions(&loader, "p", "a").await.unwrap(),
+            (10, 10)
+        );
+        assert_eq!(loader.decodes.load(Ordering::Relaxed), 1);
+
+        // Failures are not cached
+        for _ in 0..2 {
+            assert!(matches!(
+                cache.get_image(&loader, "p", "missing").await,
+                Err(LoaderError::NotFound)
+            ));
+        }
+        assert_eq!(loader.decodes.load(Ordering::Relaxed), 3);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (5, 3));
+        assert_eq!((stats.entries, stats.bytes), (1, 300));
+
+        // A request given up on part way leaves no decode behind
+        let request = cache.get_image(&loader, "p", "b");
+        let timeout = Duration::from_millis(5);
+        assert!(tokio::time::timeout(timeout, request).await.is_err());
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_image_cache_eviction() {
+        let loader = Counting {
+            version: Some("1".into()),
+            ..Counting::default()
+        };
+        // Room for two 10x10 RGB images, but not three
+        let cache = ImageCache::new(700);
+        for identifier in ["a", "b", "a", "c", "a", "b"] {
+            cache.get_image(&loader, "p", identifier).await.unwrap();
+        }
+        // b was the least recently used when c came in
+        assert_eq!(loader.decodes.load(Ordering::Relaxed), 4);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (2, 600));
+
+        // Images bigger than the whole cache are not kept
+        let cache = ImageCache::new(200);
+        cache.get_image(&loader, "p", "a").await.unwrap();
+        assert_eq!(cache.stats().entries, 0);
+
+        // Nor is anything without a version, or with a cache of no size
+        let unversioned = Counting::default();
+        let cache = ImageCache::new(1 << 20);
+        assert!(
+            cache
+                .get_image(&unversioned, "p", "a")
+                .await
+                .unwrap()
+                .is_none()
+        );
+        let cache = ImageCache::new(0);
+        assert!(cache.get_image(&loader, "p", "a").await.unwrap().is_none());
+        assert_eq!(cache.stats().misses, 0);
+    }
+
+    #[tokio::test]
+    async fn test_image_cache_invalidation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.png");
+        save(&path, 3, 2);
+        let mut loader = LocalLoader::new();
+        loader.insert_dir_with_extensions("p", dir.path(), ["png"]);
+        let cache = ImageCache::new(1 << 20);
+
+        assert_eq!(
+            cache.get_dimensions(&loader, "p", "a").await.unwrap(),
+            (3, 2)
+        );
+        cache.get_image(&loader, "p", "a").await.unwrap();
+        cache.get_image(&loader, "p", "a").await.unwrap();
+        assert_eq!((cache.stats().hits, cache.stats().misses), (1, 2));
+
+        // Replacing the file gives it a new version, which is decoded afresh
+        let replacement = dir.path().join("b.png");
+        save(&replacement, 5, 4);
+        std::fs::rename(&replacement, &path).unwrap();
+        assert_eq!(
+            cache.get_dimensions(&loader, "p", "a").await.unwrap(),
+            (5, 4)
+        );
+        let image = cache.get_image(&loader, "p", "a").await.unwrap();
+        assert_eq!(image.unwrap().dimensions(), (5, 4));
+        assert_eq!(cache.stats().misses, 4);
+    }
+}
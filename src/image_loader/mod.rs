@@ -0,0 +1,772 @@
+use image::{
+    DynamicImage, GenericImageView, ImageError, ImageFormat, ImageReader,
+};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::File,
+    hash::Hash,
+    io::{self, BufReader},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+use crate::api::image::{Region, Size};
+use crate::image_ops::{crop_image, resize_image};
+use watcher::ChangeCounts;
+
+mod archive;
+mod auth;
+mod cache_index;
+mod cascade;
+mod chain;
+mod content_cache;
+mod derivative_cache;
+mod eviction;
+mod http_cache;
+mod image_cache;
+mod pages;
+mod plugin;
+mod proxy;
+mod resolver;
+mod retry;
+mod s3;
+mod sigv4;
+mod upstream;
+mod url_template;
+mod watcher;
+pub use archive::ArchiveLoader;
+pub use auth::{BasicAuth, ClientCert, SecretSource, UpstreamAuth};
+pub use chain::ChainLoader;
+pub use derivative_cache::{DerivativeCache, DerivativeCacheConfig};
+pub use image_cache::{ImageCache, ImageCacheStats};
+pub use pages::PAGE_SEPARATOR;
+pub use plugin::{DynImageLoader, LoaderFactory, LoaderFuture, LoaderRegistry};
+pub use proxy::{ProxyConfig, ProxyLoader};
+pub use resolver::{IdentifierResolver, ResolverConfig, ResolvingLoader};
+pub use retry::{CircuitBreakerConfig, RetryPolicy};
+pub use s3::{S3Config, S3Loader};
+pub use upstream::UpstreamPolicy;
+use upstream::{RefusedRedirect, UpstreamBlocked};
+pub use url_template::UrlTemplate;
+pub use watcher::SourceWatcher;
+
+/// File extensions tried, in order, when a local prefix does not configure
+/// its own list.
+pub const DEFAULT_EXTENSIONS: &[&str] =
+    &["tif", "tiff", "jpg", "jpeg", "png", "webp"];
+/// Extensions of the formats that images copied into a local directory are
+/// stored in, which keep them as decoded.
+const LOSSLESS_EXTENSIONS: &[&str] = &["tif", "tiff", "png"];
+
+// The AppState contains a HashMap over all loaders, and because get_image() is
+// async, GenericImageLoader is not a dyn-compatible trait. This enum is a
+// work-around for that. Loaders from other crates implement the
+// dyn-compatible DynImageLoader instead, and are served through the Plugin
+// variant.
+//
+// Loaders are shared between requests without a lock around them, so any
+// state they mutate must be synchronised internally.
+//
+// Each loader is created once and kept behind an Arc, so the size of the
+// variants does not matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum ImageLoader {
+    Local(LocalLoader),
+    Proxy(ProxyLoader),
+    S3(S3Loader),
+    Archive(ArchiveLoader),
+    Chain(ChainLoader),
+    Resolved(ResolvingLoader),
+    Plugin(Box<dyn DynImageLoader>),
+}
+
+pub type Result<T, E = LoaderError> = std::result::Result<T, E>;
+
+// Clone so that the outcome of a shared upstream fetch can be handed to every
+// request waiting on it
+#[derive(Debug, Clone)]
+pub enum LoaderError {
+    /// No image exists for the given prefix and identifier
+    NotFound,
+    /// The identifier is malformed for this loader
+    InvalidIdentifier,
+    /// The upstream URI is refused by the prefix's upstream policy
+    Forbidden(String),
+    /// An upstream server redirected in a way that is not followed
+    BadRedirect(String),
+    /// An upstream server failed or returned an unusable response
+    Upstream(String),
+    /// An upstream server did not respond in time
+    Timeout,
+    /// The image data could not be decoded
+    CorruptImage(String),
+    /// The image is not in a format we can decode
+    UnsupportedFormat,
+    Io(Arc<io::Error>),
+    CacheIndex(Arc<rusqlite::Error>),
+    /// The requested size would take upscaling that wasn't asked for
+    InvalidSize,
+    /// The loader's configuration cannot be used
+    Config(String),
+}
+
+pub trait GenericImageLoader: Sync {
+    fn get_image(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> impl Future<Output = Result<DynamicImage>> + Send;
+
+    /// The width and height of an image.
+    fn get_dimensions(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> impl Future<Output = Result<(u32, u32)>> + Send {
+        async move { Ok(self.get_image(prefix, identifier).await?.dimensions()) }
+    }
+
+    /// The width and height of each page of an image, in order. Loaders
+    /// that know of multi-page files override this; to the others, every
+    /// image is a single page.
+    fn get_pages(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> impl Future<Output = Result<Vec<(u32, u32)>>> + Send {
+        async move { Ok(vec![self.get_dimensions(prefix, identifier).await?]) }
+    }
+
+    /// A token that changes whenever the image does, such as the time its
+    /// file was last modified, or None if the loader cannot tell cheaply.
+    /// Only images with a version are kept in memory once decoded.
+    fn get_version(
+        &self,
+        _prefix: &str,
+        _identifier: &str,
+    ) -> impl Future<Output = Result<Option<String>>> + Send {
+        async { Ok(None) }
+    }
+
+    /// The `region` of an image, scaled to `size`. Loaders that can get
+    /// part of an image more cheaply than the whole of it override this.
+    fn get_region(
+        &self,
+        prefix: &str,
+        identifier: &str,
+        region: &Region,
+        size: &Size,
+    ) -> impl Future<Output = Result<DynamicImage>> + Send {
+        async move {
+            let image = self.get_image(prefix, identifier).await?;
+            crop_and_resize(image, region, size)
+        }
+    }
+}
+
+/// The `region` of a whole `image`, scaled to `size`.
+fn crop_and_resize(
+    image: DynamicImage,
+    region: &Region,
+    size: &Size,
+) -> Result<DynamicImage> {
+    resize_image(crop_image(image, region), size)
+        .map_err(|_| LoaderError::InvalidSize)
+}
+
+#[derive(Debug, Default)]
+pub struct LocalLoader {
+    image_dirs: HashMap<String, LocalDir>,
+    changes: Arc<ChangeCounts>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LocalDir {
+    path: PathBuf,
+    extensions: Vec<String>,
+}
+
+type Sha256Bytes = [u8; 32];
+type ContentCacheKey = Sha256Bytes;
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "image not found"),
+            Self::InvalidIdentifier => write!(f, "invalid identifier"),
+            Self::Forbidden(msg) => write!(f, "upstream refused: {msg}"),
+            Self::BadRedirect(msg) => write!(f, "bad upstream redirect: {msg}"),
+            Self::Upstream(msg) => write!(f, "upstream error: {msg}"),
+            Self::Timeout => write!(f, "upstream timed out"),
+            Self::CorruptImage(msg) => write!(f, "corrupt image: {msg}"),
+            Self::UnsupportedFormat => write!(f, "unsupported image format"),
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::CacheIndex(e) => write!(f, "cache index error: {e}"),
+            Self::InvalidSize => write!(f, "invalid size for the region"),
+            Self::Config(msg) => write!(f, "invalid configuration: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for LoaderError {}
+
+impl From<io::Error> for LoaderError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => Self::NotFound,
+            _ => Self::Io(Arc::new(e)),
+        }
+    }
+}
+
+impl From<ImageError> for LoaderError {
+    fn from(e: ImageError) -> Self {
+        match e {
+            ImageError::Unsupported(_) => Self::UnsupportedFormat,
+            ImageError::IoError(e) => e.into(),
+            e => Self::CorruptImage(e.to_string()),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for LoaderError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::CacheIndex(Arc::new(e))
+    }
+}
+
+impl From<UpstreamBlocked> for LoaderError {
+    fn from(e: UpstreamBlocked) -> Self {
+        Self::Forbidden(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for LoaderError {
+    fn from(e: reqwest::Error) -> Self {
+        // Redirects and DNS lookups refused by the upstream policy, and
+        // redirects that are not followed, surface as errors somewhere down
+        // the source chain
+        let mut source = std::error::Error::source(&e);
+        while let Some(err) = source {
+            if let Some(blocked) = err.downcast_ref::<UpstreamBlocked>() {
+                return Self::Forbidden(blocked.to_string());
+            }
+            if let Some(refused) = err.downcast_ref::<RefusedRedirect>() {
+                return Self::BadRedirect(refused.to_string());
+            }
+            source = err.source();
+        }
+        if e.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::Upstream(e.to_string())
+        }
+    }
+}
+
+impl GenericImageLoader for ImageLoader {
+    async fn get_image(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<DynamicImage> {
+        match self {
+            Self::Local(local) => local.get_image(prefix, identifier).await,
+            Self::Proxy(proxy) => proxy.get_image(prefix, identifier).await,
+            Self::S3(s3) => s3.get_image(prefix, identifier).await,
+            Self::Archive(archive) => {
+                archive.get_image(prefix, identifier).await
+            }
+            Self::Chain(chain) => chain.get_image(prefix, identifier).await,
+            Self::Resolved(resolved) => {
+                resolved.get_image(prefix, identifier).await
+            }
+            Self::Plugin(plugin) => plugin.get_image(prefix, identifier).await,
+        }
+    }
+
+    async fn get_dimensions(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<(u32, u32)> {
+        match self {
+            Self::Local(local) => {
+                local.get_dimensions(prefix, identifier).await
+            }
+            Self::Proxy(proxy) => {
+                proxy.get_dimensions(prefix, identifier).await
+            }
+            Self::S3(s3) => s3.get_dimensions(prefix, identifier).await,
+            Self::Archive(archive) => {
+                archive.get_dimensions(prefix, identifier).await
+            }
+            Self::Chain(chain) => {
+                chain.get_dimensions(prefix, identifier).await
+            }
+            Self::Resolved(resolved) => {
+                resolved.get_dimensions(prefix, identifier).await
+            }
+            Self::Plugin(plugin) => {
+                plugin.get_dimensions(prefix, identifier).await
+            }
+        }
+    }
+
+    async fn get_pages(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<Vec<(u32, u32)>> {
+        match self {
+            Self::Local(local) => local.get_pages(prefix, identifier).await,
+            Self::Proxy(proxy) => proxy.get_pages(prefix, identifier).await,
+            Self::S3(s3) => s3.get_pages(prefix, identifier).await,
+            Self::Archive(archive) => {
+                archive.get_pages(prefix, identifier).await
+            }
+            Self::Chain(chain) => chain.get_pages(prefix, identifier).await,
+            Self::Resolved(resolved) => {
+                resolved.get_pages(prefix, identifier).await
+            }
+            Self::Plugin(plugin) => plugin.get_pages(prefix, identifier).await,
+        }
+    }
+
+    async fn get_version(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<Option<String>> {
+        match self {
+            Self::Local(local) => local.get_version(prefix, identifier).await,
+            Self::Proxy(proxy) => proxy.get_version(prefix, identifier).await,
+            Self::S3(s3) => s3.get_version(prefix, identifier).await,
+            Self::Archive(archive) => {
+                archive.get_version(prefix, identifier).await
+            }
+            Self::Chain(chain) => chain.get_version(prefix, identifier).await,
+            Self::Resolved(resolved) => {
+                resolved.get_version(prefix, identifier).await
+            }
+            Self::Plugin(plugin) => {
+                plugin.get_version(prefix, identifier).await
+            }
+        }
+    }
+
+    async fn get_region(
+        &self,
+        prefix: &str,
+        identifier: &str,
+        region: &Region,
+        size: &Size,
+    ) -> Result<DynamicImage> {
+        match self {
+            Self::Local(local) => {
+                local.get_region(prefix, identifier, region, size).await
+            }
+            Self::Proxy(proxy) => {
+                proxy.get_region(prefix, identifier, region, size).await
+            }
+            Self::S3(s3) => {
+                s3.get_region(prefix, identifier, region, size).await
+            }
+            Self::Archive(archive) => {
+                archive.get_region(prefix, identifier, region, size).await
+            }
+            Self::Chain(chain) => {
+                chain.get_region(prefix, identifier, region, size).await
+            }
+            Self::Resolved(resolved) => {
+                resolved.get_region(prefix, identifier, region, size).await
+            }
+            Self::Plugin(plugin) => {
+                plugin.get_region(prefix, identifier, region, size).await
+            }
+        }
+    }
+}
+
+impl LocalLoader {
+    pub fn new() -> Self {
+        Self {
+            ..Default::default()
+        }
+    }
+
+    pub fn insert_dir<S, T>(&mut self, prefix: S, dir: T)
+    where
+        S: Into<String>,
+        T: Into<PathBuf>,
+    {
+        self.insert_dir_with_extensions(prefix, dir, DEFAULT_EXTENSIONS);
+    }
+
+    /// Serve images for `prefix` from `dir`, resolving identifiers without a
+    /// file extension by trying each of `extensions` in turn.
+    pub fn insert_dir_with_extensions<S, T, E>(
+        &mut self,
+        prefix: S,
+        dir: T,
+        extensions: impl IntoIterator<Item = E>,
+    ) where
+        S: Into<String>,
+        T: Into<PathBuf>,
+        E: AsRef<str>,
+    {
+        let extensions = extensions
+            .into_iter()
+            .map(|ext| ext.as_ref().trim_start_matches('.').to_lowercase())
+            .collect();
+        self.image_dirs.insert(
+            prefix.into(),
+            LocalDir {
+                path: dir.into(),
+                extensions,
+            },
+        );
+    }
+}
+
+impl LocalDir {
+    /// The first of the extensions that is for a lossless format.
+    fn lossless_ext(&self) -> Option<&str> {
+        self.extensions
+            .iter()
+            .map(String::as_str)
+            .find(|ext| LOSSLESS_EXTENSIONS.contains(ext))
+    }
+
+    /// Find the file on disk that `identifier` refers to. An identifier that
+    /// already ends in one of the configured extensions is tried as is, then
+    /// each extension is appended in order of priority.
+    fn resolve(&self, identifier: &str) -> Result<PathBuf> {
+        let relative = Path::new(identifier);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(LoaderError::InvalidIdentifier);
+        }
+
+        let has_known_ext = relative
+            .extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|ext| {
+                self.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext))
+            });
+        if has_known_ext {
+            let file_path = self.path.join(relative);
+            if file_path.is_file() {
+                return Ok(file_path);
+            }
+        }
+
+        self.extensions
+            .iter()
+            .map(|ext| {
+                let mut file_name = OsString::from(identifier);
+                file_name.push(".");
+                file_name.push(ext);
+                self.path.join(file_name)
+            })
+            .find(|file_path| file_path.is_file())
+            .ok_or(LoaderError::NotFound)
+    }
+}
+
+impl<S, Z> FromIterator<(S, Z)> for LocalLoader
+where
+    S: Into<String>,
+    Z: Into<PathBuf>,
+{
+    fn from_iter<T: IntoIterator<Item = (S, Z)>>(iter: T) -> Self {
+        let mut loader = Self::new();
+        for (prefix, dir) in iter {
+            loader.insert_dir(prefix, dir);
+        }
+        loader
+    }
+}
+
+impl LocalLoader {
+    /// Find the file `identifier` refers to, and the page of it selected, if
+    /// any.
+    fn resolve(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<(PathBuf, Option<usize>)> {
+        let (identifier, page) = pages::split_page(identifier)?;
+        let file_path = self
+            .image_dirs
+            .get(prefix)
+            .ok_or(LoaderError::NotFound)?
+            .resolve(identifier)?;
+        Ok((file_path, page))
+    }
+
+    /// Check that every directory has an extension that images can be
+    /// copied into it with.
+    fn check_writable(&self) -> Result<()> {
+        match self
+            .image_dirs
+            .values()
+            .all(|dir| dir.lossless_ext().is_some())
+        {
+            true => Ok(()),
+            false => Err(LoaderError::Config(format!(
+                "copying images in takes one of the extensions {}",
+                LOSSLESS_EXTENSIONS.join(", ")
+            ))),
+        }
+    }
+
+    /// Where to copy the image `identifier` to, so that it is found there
+    /// afterwards.
+    fn promotion_path(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<PathBuf> {
+        let dir = self.image_dirs.get(prefix).ok_or(LoaderError::NotFound)?;
+        if !Path::new(identifier)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(LoaderError::InvalidIdentifier);
+        }
+        let ext = dir.lossless_ext().ok_or(LoaderError::UnsupportedFormat)?;
+        Ok(dir.path.join(format!("{identifier}.{ext}")))
+    }
+}
+
+/// Write `image` to `path`, in the format its extension names. The image is
+/// written to a temporary file first, so that it is never read half-written.
+fn store_image(path: &Path, image: &DynamicImage) -> Result<()> {
+    let format = ImageFormat::from_path(path)
+        .map_err(|_| LoaderError::UnsupportedFormat)?;
+    let dir = path.parent().ok_or(LoaderError::InvalidIdentifier)?;
+    std::fs::create_dir_all(dir)?;
+    let mut file = tempfile::Builder::new().prefix(".").tempfile_in(dir)?;
+    image.write_to(&mut io::BufWriter::new(file.as_file_mut()), format)?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Open the image at `path`, working out its format from its content.
+fn open_image(path: &Path) -> Result<(BufReader<File>, ImageFormat)> {
+    let reader = ImageReader::open(path)?.with_guessed_format()?;
+    let format = reader.format().ok_or(LoaderError::UnsupportedFormat)?;
+    Ok((reader.into_inner(), format))
+}
+
+impl GenericImageLoader for LocalLoader {
+    async fn get_image(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<DynamicImage> {
+        let (file_path, page) = self.resolve(prefix, identifier)?;
+        spawn_decode(move || match page {
+            Some(page) => {
+                let (reader, format) = open_image(&file_path)?;
+                pages::decode_page(reader, format, page)
+            }
+            None => Ok(ImageReader::open(&file_path)?
+                .with_guessed_format()?
+                .decode()?),
+        })
+        .await
+    }
+
+    async fn get_dimensions(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<(u32, u32)> {
+        let (file_path, page) = self.resolve(prefix, identifier)?;
+        let Some(page) = page else {
+            return Ok(self.get_image(prefix, identifier).await?.dimensions());
+        };
+        let pages = spawn_pages(file_path).await?;
+        pages.get(page - 1).copied().ok_or(LoaderError::NotFound)
+    }
+
+    async fn get_pages(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<Vec<(u32, u32)>> {
+        let (file_path, page) = self.resolve(prefix, identifier)?;
+        if page.is_some() {
+            return Err(LoaderError::InvalidIdentifier);
+        }
+        spawn_pages(file_path).await
+    }
+
+    async fn get_version(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<Option<String>> {
+        let (file_path, _) = self.resolve(prefix, identifier)?;
+        let metadata = tokio::fs::metadata(&file_path).await?;
+        let changes = self.changes.get(&file_path);
+        Ok(Some(format!("{}-{changes:x}", file_version(&metadata))))
+    }
+}
+
+/// The version of a file, which changes whenever it is written to or
+/// replaced.
+fn file_version(metadata: &std::fs::Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!(
+        "{:x}-{:x}-{:x}",
+        metadata.ino(),
+        modified.as_nanos(),
+        metadata.len()
+    )
+}
+
+/// List the pages of the image at `path` off the async worker threads.
+async fn spawn_pages(path: PathBuf) -> Result<Vec<(u32, u32)>> {
+    tokio::task::spawn_blocking(move || {
+        let (reader, format) = open_image(&path)?;
+        pages::page_dimensions(reader, format)
+    })
+    .await
+    .map_err(|e| LoaderError::CorruptImage(format!("decoder panicked: {e}")))?
+}
+
+/// A request's share in a load that every request for the same key waits
+/// for, taken out of `in_flight` once the request is done with it, whether
+/// it finished or was dropped part way, so that later requests start afresh.
+struct FlightGuard<'a, K: Eq + Hash, T> {
+    in_flight: &'a Mutex<HashMap<K, Arc<T>>>,
+    key: K,
+    flight: Arc<T>,
+}
+
+impl<'a, K: Eq + Hash + Clone, T: Default> FlightGuard<'a, K, T> {
+    /// Join the load of `key` under way, or else start one.
+    fn join(in_flight: &'a Mutex<HashMap<K, Arc<T>>>, key: K) -> Self {
+        let flight = Arc::clone(
+            in_flight.lock().unwrap().entry(key.clone()).or_default(),
+        );
+        Self {
+            in_flight,
+            key,
+            flight,
+        }
+    }
+}
+
+impl<K: Eq + Hash, T> std::ops::Deref for FlightGuard<'_, K, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.flight
+    }
+}
+
+impl<K: Eq + Hash, T> Drop for FlightGuard<'_, K, T> {
+    fn drop(&mut self) {
+        // A load started since this one finished is left alone
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(&self.key)
+            .is_some_and(|other| Arc::ptr_eq(other, &self.flight))
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+/// Run a blocking image decode off the async worker threads.
+async fn spawn_decode<F>(decode: F) -> Result<DynamicImage>
+where
+    F: FnOnce() -> Result<DynamicImage> + Send + 'static,
+{
+    tokio::task::spawn_blocking(decode).await.map_err(|e| {
+        LoaderError::CorruptImage(format!("decoder panicked: {e}"))
+    })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::image::SizeKind;
+
+    #[test]
+    fn test_crop_and_resize() {
+        let size = Size {
+            allow_upscale: false,
+            maintain_ratio: false,
+            kind: SizeKind::Max,
+        };
+        let region = |x| Region::Absolute {
+            x,
+            y: 0,
+            w: 5.try_into().unwrap(),
+            h: 5.try_into().unwrap(),
+        };
+        let crop = |x| {
+            crop_and_resize(DynamicImage::new_rgb8(10, 10), &region(x), &size)
+        };
+        assert_eq!(crop(8).unwrap().dimensions(), (2, 5));
+        // A region outside the image selects nothing, rather than a pixel
+        assert!(matches!(crop(20), Err(LoaderError::InvalidSize)));
+    }
+
+    #[test]
+    fn test_local_dir_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.png", "a.jpg", "b.JPG", "c.v2.webp", "scan;v2.png"] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let mut loader = LocalLoader::new();
+        loader.insert_dir_with_extensions("p", dir.path(), ["jpg", ".png"]);
+        let local_dir = &loader.image_dirs["p"];
+
+        assert_eq!(local_dir.resolve("a").unwrap(), dir.path().join("a.jpg"));
+        assert_eq!(
+            local_dir.resolve("a.png").unwrap(),
+            dir.path().join("a.png")
+        );
+        assert_eq!(
+            local_dir.resolve("b.JPG").unwrap(),
+            dir.path().join("b.JPG")
+        );
+        assert!(matches!(
+            local_dir.resolve("c.v2"),
+            Err(LoaderError::NotFound)
+        ));
+        assert!(matches!(
+            local_dir.resolve("../a"),
+            Err(LoaderError::InvalidIdentifier)
+        ));
+
+        // A separator that doesn't select a page is part of the file name
+        let path = dir.path().join("scan;v2.png");
+        for (identifier, page) in
+            [("scan;v2.png", None), ("scan;v2;2", Some(2))]
+        {
+            assert_eq!(
+                loader.resolve("p", identifier).unwrap(),
+                (path.clone(), page)
+            );
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use image::{
+    AnimationDecoder, DynamicImage, Frames, ImageBuffer, ImageDecoder,
+    ImageFormat, ImageReader,
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+};
+use std::io::{BufRead, Seek};
+use tiff::{
+    ColorType, TiffError, TiffFormatError,
+    decoder::{Decoder, DecodingResult},
+    tags::Tag,
+};
+
+use super::{LoaderError, Result};
+
+/// Separates the page or frame, numbered from 1, from the rest of an
+/// identifier, as in `doc.tif;3`
+pub const PAGE_SEPARATOR: char = ';';
+/// Most pages listed for, or looked through in, a single file
+const MAX_PAGES: usize = 10_000;
+/// NewSubfileType bit set on reduced-resolution copies of a page, such as
+/// the levels of a pyramidal TIFF
+const REDUCED_RESOLUTION: u32 = 1;
+
+/// Split the page selector off `identifier`, if it has one. Anything after
+/// the last separator that is not a number is part of the file name.
+pub fn split_page(identifier: &str) -> Result<(&str, Option<usize>)> {
+    let Some((file, page)) = identifier.rsplit_once(PAGE_SEPARATOR) else {
+        return Ok((identifier, None));
+    };
+    if page.is_empty() || !page.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok((identifier, None));
+    }
+    // No leading zeroes, so that each page has a single identifier
+    if file.is_empty() || page.starts_with('0') {
+        return Err(LoaderError::InvalidIdentifier);
+    }
+    let page = page.parse().map_err(|_| LoaderError::InvalidIdentifier)?;
+    Ok((file, Some(page)))
+}
+
+/// Decode page `page`, numbered from 1, of an image in `format`. Each
+/// frame of an animated GIF, PNG or WebP image counts as a page, and any
+/// other single image has only the one page.
+pub fn decode_page<R>(
+    reader: R,
+    format: ImageFormat,
+    page: usize,
+) -> Result<DynamicImage>
+where
+    R: BufRead + Seek,
+{
+    let index = page - 1;
+    match format {
+        ImageFormat::Tiff => decode_tiff_page(reader, index),
+        ImageFormat::Gif => {
+            nth_frame(GifDecoder::new(reader)?.into_frames(), index)
+        }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(reader)?;
+            if decoder.is_apng()? {
+                nth_frame(decoder.apng()?.into_frames(), index)
+            } else {
+                only_page(DynamicImage::from_decoder(decoder), index)
+            }
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(reader)?;
+            if decoder.has_animation() {
+                nth_frame(decoder.into_frames(), index)
+            } else {
+                only_page(DynamicImage::from_decoder(decoder), index)
+            }
+        }
+        _ => {
+            only_page(ImageReader::with_format(reader, format).decode(), index)
+        }
+    }
+}
+
+/// The width and height of each page of an image in `format`, in order.
+pub fn page_dimensions<R>(
+    reader: R,
+    format: ImageFormat,
+) -> Result<Vec<(u32, u32)>>
+where
+    R: BufRead + Seek,
+{
+    match format {
+        ImageFormat::Tiff => {
+            let mut decoder = Decoder::new(reader).map_err(tiff_error)?;
+            let mut pages = vec![];
+            for_each_tiff_page(&mut decoder, |decoder| {
+                pages.push(decoder.dimensions().map_err(tiff_error)?);
+                Ok(true)
+            })?;
+            Ok(pages)
+        }
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(reader)?;
+            let dimensions = decoder.dimensions();
+            frame_pages(decoder.into_frames(), dimensions)
+        }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(reader)?;
+            let dimensions = decoder.dimensions();
+            if decoder.is_apng()? {
+                frame_pages(decoder.apng()?.into_frames(), dimensions)
+            } else {
+                Ok(vec![dimensions])
+            }
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(reader)?;
+            let dimensions = decoder.dimensions();
+            if decoder.has_animation() {
+                frame_pages(decoder.into_frames(), dimensions)
+            } else {
+                Ok(vec![dimensions])
+            }
+        }
+        _ => Ok(vec![
+            ImageReader::with_format(reader, format).into_dimensions()?,
+        ]),
+    }
+}
+
+fn only_page(
+    image: image::ImageResult<DynamicImage>,
+    index: usize,
+) -> Result<DynamicImage> {
+    match index {
+        0 => Ok(image?),
+        _ => Err(LoaderError::NotFound),
+    }
+}
+
+/// Frame `index` of an animation, drawn onto the full canvas.
+fn nth_frame(mut frames: Frames, index: usize) -> Result<DynamicImage> {
+    if index >= MAX_PAGES {
+        return Err(LoaderError::NotFound);
+    }
+    let frame = frames.nth(index).ok_or(LoaderError::NotFound)??;
+    Ok(DynamicImage::ImageRgba8(frame.into_buffer()))
+}
+
+/// A page of the canvas size for each frame of an animation. The frames
+/// are composed on the canvas, so they all have its size, but counting
+/// them takes decoding them.
+fn frame_pages(
+    frames: Frames,
+    dimensions: (u32, u32),
+) -> Result<Vec<(u32, u32)>> {
+    let mut pages = vec![];
+    for frame in frames.take(MAX_PAGES) {
+        frame?;
+        pages.push(dimensions);
+    }
+    Ok(pages)
+}
+
+/// Visit the pages of a TIFF in turn until `visit` returns false, skipping
+/// the reduced-resolution copies of them that some TIFFs also hold.
+fn for_each_tiff_page<R, F>(
+    decoder: &mut Decoder<R>,
+    mut visit: F,
+) -> Result<()>
+where
+    R: std::io::Read + Seek,
+    F: FnMut(&mut Decoder<R>) -> Result<bool>,
+{
+    let mut pages = 0;
+    loop {
+        let subfile_type = decoder
+            .find_tag_unsigned::<u32>(Tag::NewSubfileType)
+            .map_err(tiff_error)?
+            .unwrap_or(0);
+        if subfile_type & REDUCED_RESOLUTION == 0 {
+            pages += 1;
+            if pages > MAX_PAGES || !visit(decoder)? {
+                return Ok(());
+            }
+        }
+        if !decoder.more_images() {
+            return Ok(());
+        }
+        decoder.next_image().map_err(tiff_error)?;
+    }
+}
+
+fn decode_tiff_page<R>(reader: R, index: usize) -> Result<DynamicImage>
+where
+    R: BufRead + Seek,
+{
+    let mut decoder = Decoder::new(reader).map_err(tiff_error)?;
+    let mut page = 0;
+    let mut image = None;
+    for_each_tiff_page(&mut decoder, |decoder| {
+        if page == index {
+            image = Some(read_tiff_image(decoder)?);
+        }
+        page += 1;
+        Ok(image.is_none())
+    })?;
+    image.ok_or(LoaderError::NotFound)
+}
+
+/// Decode the image `decoder` is at, for the colour types that `image`
+/// has a buffer type for.
+fn read_tiff_image<R>(decoder: &mut Decoder<R>) -> Result<DynamicImage>
+where
+    R: std::io::Read + Seek,
+{
+    use DecodingResult::{F32, U8, U16};
+    use DynamicImage::*;
+
+    let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+    let colortype = decoder.colortype().map_err(tiff_error)?;
+    let data = decoder.read_image().map_err(tiff_error)?;
+    let image = match (colortype, data) {
+        (ColorType::Gray(8), U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(ImageLuma8)
+        }
+        (ColorType::Gray(16), U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(ImageLuma16)
+        }
+        (ColorType::GrayA(8), U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(ImageLumaA8)
+        }
+        (ColorType::GrayA(16), U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(ImageLumaA16)
+        }
+        (ColorType::RGB(8), U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(ImageRgb8)
+        }
+        (ColorType::RGB(16), U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(ImageRgb16)
+        }
+        (ColorType::RGBA(8), U8(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(ImageRgba8)
+        }
+        (ColorType::RGBA(16), U16(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(ImageRgba16)
+        }
+        (ColorType::RGB(32), F32(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(ImageRgb32F)
+        }
+        (ColorType::RGBA(32), F32(data)) => {
+            ImageBuffer::from_raw(width, height, data).map(ImageRgba32F)
+        }
+        _ => return Err(LoaderError::UnsupportedFormat),
+    };
+    image.ok_or_else(|| {
+        LoaderError::CorruptImage("page data does not fit its size".into())
+    })
+}
+
+fn tiff_error(e: TiffError) -> LoaderError {
+    match e {
+        TiffError::FormatError(TiffFormatError::ImageFileDirectoryNotFound) => {
+            LoaderError::NotFound
+        }
+        TiffError::UnsupportedError(_) => LoaderError::UnsupportedFormat,
+        TiffError::IoError(e) => e.into(),
+        e => LoaderError::CorruptImage(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{
+        Delay, Frame, GenericImageView, RgbaImage, codecs::gif::GifEncoder,
+    };
+    use std::io::Cursor;
+    use tiff::encoder::{TiffEncoder, colortype};
+
+    /// A TIFF with a page of each of `sizes`, each followed by a
+    /// half-resolution copy of it.
+    fn tiff(sizes: &[(u32, u32)]) -> Vec<u8> {
+        let mut out = Cursor::new(vec![]);
+        let mut encoder = TiffEncoder::new(&mut out).unwrap();
+        for &(width, height) in sizes {
+            let data = vec![0; (width * height * 3) as usize];
+            encoder
+                .write_image::<colortype::RGB8>(width, height, &data)
+                .unwrap();
+            let (width, height) = (width / 2, height / 2);
+            let mut reduced =
+                encoder.new_image::<colortype::RGB8>(width, height).unwrap();
+            reduced
+                .encoder()
+                .write_tag(Tag::NewSubfileType, REDUCED_RESOLUTION)
+                .unwrap();
+            reduced
+                .write_data(&vec![0; (width * height * 3) as usize])
+                .unwrap();
+        }
+        out.into_inner()
+    }
+
+    fn gif(frames: usize) -> Vec<u8> {
+        let mut out = vec![];
+        GifEncoder::new(&mut out)
+            .encode_frames((0..frames).map(|i| {
+                let pixel = image::Rgba([i as u8 * 100, 0, 0, 255]);
+                Frame::from_parts(
+                    RgbaImage::from_pixel(4, 3, pixel),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(100, 1),
+                )
+            }))
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_split_page() {
+        assert_eq!(split_page("doc.tif").unwrap(), ("doc.tif", None));
+        assert_eq!(split_page("doc.tif;3").unwrap(), ("doc.tif", Some(3)));
+        assert_eq!(split_page("a;b;12").unwrap(), ("a;b", Some(12)));
+        for identifier in ["doc;", "doc;+3", "a;b.tif", "scan;v2.png"] {
+            assert_eq!(split_page(identifier).unwrap(), (identifier, None));
+        }
+        for identifier in [";3", "doc;0", "doc;03", "doc;99999999999999999999"]
+        {
+            assert!(
+                matches!(
+                    split_page(identifier),
+                    Err(LoaderError::InvalidIdentifier)
+                ),
+                "{identifier}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_tiff_pages() {
+        let data = tiff(&[(8, 6), (4, 10), (2, 2)]);
+        let pages = page_dimensions(Cursor::new(&data), ImageFormat::Tiff);
+        assert_eq!(pages.unwrap(), [(8, 6), (4, 10), (2, 2)]);
+
+        let page = decode_page(Cursor::new(&data), ImageFormat::Tiff, 2);
+        assert_eq!(page.unwrap().dimensions(), (4, 10));
+        assert!(matches!(
+            decode_page(Cursor::new(&data), ImageFormat::Tiff, 4),
+            Err(LoaderError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_animation_frames() {
+        let data = gif(3);
+        let pages = page_dimensions(Cursor::new(&data), ImageFormat::Gif);
+        assert_eq!(pages.unwrap(), [(4, 3); 3]);
+
+        let frame = decode_page(Cursor::new(&data), ImageFormat::Gif, 3)
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(frame.get_pixel(0, 0).0, [200, 0, 0, 255]);
+        assert!(matches!(
+            decode_page(Cursor::new(&data), ImageFormat::Gif, 4),
+            Err(LoaderError::NotFound)
+        ));
+
+        let mut png = Cursor::new(vec![]);
+        DynamicImage::new_rgb8(5, 5)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+        let pages = page_dimensions(Cursor::new(&png), ImageFormat::Png);
+        assert_eq!(pages.unwrap(), [(5, 5)]);
+        assert!(decode_page(Cursor::new(&png), ImageFormat::Png, 1).is_ok());
+        assert!(matches!(
+            decode_page(Cursor::new(&png), ImageFormat::Png, 2),
+            Err(LoaderError::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+use image::{DynamicImage, GenericImageView};
+use std::{collections::HashMap, fmt, future::Future, pin::Pin};
+
+use super::{LoaderError, Result, crop_and_resize};
+use crate::api::image::{Region, Size};
+
+/// The boxed future a [`DynImageLoader`] returns.
+pub type LoaderFuture<'a, T> =
+    Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Builds a loader for a prefix from the `options` table of its
+/// configuration.
+pub type LoaderFactory =
+    dyn Fn(&str, toml::Table) -> Result<Box<dyn DynImageLoader>> + Send + Sync;
+
+/// A loader that can be used as a trait object, so that crates depending on
+/// this one can add loaders of their own. The methods mirror those of
+/// [`GenericImageLoader`](super::GenericImageLoader), with boxed futures.
+pub trait DynImageLoader: fmt::Debug + Send + Sync {
+    fn get_image<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+    ) -> LoaderFuture<'a, DynamicImage>;
+
+    /// The width and height of an image.
+    fn get_dimensions<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+    ) -> LoaderFuture<'a, (u32, u32)> {
+        Box::pin(async move {
+            Ok(self.get_image(prefix, identifier).await?.dimensions())
+        })
+    }
+
+    /// The width and height of each page of an image, in order.
+    fn get_pages<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+    ) -> LoaderFuture<'a, Vec<(u32, u32)>> {
+        Box::pin(async move {
+            Ok(vec![self.get_dimensions(prefix, identifier).await?])
+        })
+    }
+
+    /// A token that changes whenever the image does, or None if the loader
+    /// cannot tell cheaply.
+    fn get_version<'a>(
+        &'a self,
+        _prefix: &'a str,
+        _identifier: &'a str,
+    ) -> LoaderFuture<'a, Option<String>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    /// The `region` of an image, scaled to `size`.
+    fn get_region<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+        region: &'a Region,
+        size: &'a Size,
+    ) -> LoaderFuture<'a, DynamicImage> {
+        Box::pin(async move {
+            let image = self.get_image(prefix, identifier).await?;
+            crop_and_resize(image, region, size)
+        })
+    }
+}
+
+/// The loaders that prefixes configured with `type = "plugin"` can name,
+/// by the name they are registered under.
+#[derive(Default)]
+pub struct LoaderRegistry {
+    factories: HashMap<String, Box<LoaderFactory>>,
+}
+
+impl fmt::Debug for LoaderRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.factories.keys()).finish()
+    }
+}
+
+impl LoaderRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `factory` available as the loader `name`, replacing any
+    /// registered under that name before.
+    pub fn register<F>(&mut self, name: impl Into<String>, factory: F)
+    where
+        F: Fn(&str, toml::Table) -> Result<Box<dyn DynImageLoader>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.factories.insert(name.into(), Box::new(factory));
+    }
+
+    /// Build the loader `name` for `prefix`.
+    pub fn build(
+        &self,
+        name: &str,
+        prefix: &str,
+        options: toml::Table,
+    ) -> Result<Box<dyn DynImageLoader>> {
+        let factory = self.factories.get(name).ok_or_else(|| {
+            LoaderError::Config(format!("no loader is registered as {name}"))
+        })?;
+        factory(prefix, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_loader::{GenericImageLoader, ImageLoader};
+    use serde::Deserialize;
+
+    /// Serves blank images of a configured size, for any identifier.
+    #[derive(Debug)]
+    struct Blank {
+        width: u32,
+        height: u32,
+    }
+
+    impl DynImageLoader for Blank {
+        fn get_image<'a>(
+            &'a self,
+            _prefix: &'a str,
+            identifier: &'a str,
+        ) -> LoaderFuture<'a, DynamicImage> {
+            Box::pin(async move {
+                match identifier {
+                    "missing" => Err(LoaderError::NotFound),
+                    _ => Ok(DynamicImage::new_rgb8(self.width, self.height)),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registry() {
+        #[derive(Deserialize)]
+        struct BlankConfig {
+            width: u32,
+            height: u32,
+        }
+
+        let mut registry = LoaderRegistry::new();
+        registry.register("blank", |_prefix, options| {
+            let BlankConfig { width, height } = options
+                .try_into()
+                .map_err(|e| LoaderError::Config(format!("{e}")))?;
+            Ok(Box::new(Blank { width, height }) as Box<dyn DynImageLoader>)
+        });
+
+        let options = toml::from_str("width = 4\nheight = 3").unwrap();
+        let loader =
+            ImageLoader::Plugin(registry.build("blank", "p", options).unwrap());
+        assert_eq!(loader.get_dimensions("p", "a").await.unwrap(), (4, 3));
+        assert_eq!(loader.get_pages("p", "a").await.unwrap(), [(4, 3)]);
+        assert!(matches!(
+            loader.get_image("p", "missing").await,
+            Err(LoaderError::NotFound)
+        ));
+
+        assert!(matches!(
+            registry.build("blank", "p", toml::Table::new()),
+            Err(LoaderError::Config(_))
+        ));
+        assert!(matches!(
+            registry.build("other", "p", toml::Table::new()),
+            Err(LoaderError::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,1750 @@
+use axum::http::header;
+use base64ct::{Base64UrlUnpadded, Encoding};
+use futures_util::future::try_join_all;
+use image::{
+    DynamicImage, GenericImageView, ImageFormat, RgbImage,
+    imageops::{self, FilterType},
+};
+use reqwest::{StatusCode, redirect};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::OnceCell;
+use url::Url;
+
+use super::auth::UpstreamAuth;
+use super::cache_index::{
+    CacheIndex, Failure, FailureKind, IndexEntry, Validators,
+};
+use super::cascade::{self, RemoteInfo};
+use super::content_cache::{
+    Download, cached_img_path, cached_version, decode_cached, download, forget,
+    remove_interrupted_downloads, spawn_store,
+};
+use super::eviction::{self, EvictionPolicy};
+use super::http_cache;
+use super::retry::{
+    CircuitBreaker, CircuitBreakerConfig, RetryPolicy, is_transient,
+    is_transient_status,
+};
+use super::upstream::{GuardedResolver, RefusedRedirect, UpstreamPolicy};
+use super::url_template::UrlTemplate;
+use super::{
+    ContentCacheKey, FlightGuard, GenericImageLoader, LoaderError, Result,
+    crop_and_resize,
+};
+use crate::DEFAULT_USER_AGENT;
+use crate::api::image::{Region, Size};
+use crate::image_ops::{output_size, region_rect};
+
+const CACHE_INDEX_FILE: &str = "index.sqlite3";
+const MAX_REDIRECTS: usize = 10;
+/// Largest info.json accepted from an upstream IIIF server
+const MAX_INFO_BYTES: usize = 1 << 20;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub cache_dir: PathBuf,
+    /// Upstream URL that identifiers are substituted into, such as
+    /// `https://images.example.org/masters/{id}.jpg`. Without one,
+    /// identifiers are base64url-encoded upstream URLs.
+    pub url_template: Option<UrlTemplate>,
+    /// Base URL of an upstream IIIF image server, such as
+    /// `https://iiif.example.org/iiif/3`, to cascade from instead. Images
+    /// are put together from the regions and sizes it serves, as described
+    /// by the info.json of each identifier.
+    pub iiif_base_url: Option<Url>,
+    /// How long to serve cached images for which the upstream gave no
+    /// freshness information before revalidating them
+    #[serde(default = "default_max_age_secs")]
+    pub default_max_age_secs: u64,
+    /// Evict least recently used content once the cache grows past this
+    pub max_cache_bytes: Option<u64>,
+    /// Evict content stored longer ago than this, however recently used
+    pub max_entry_age_secs: Option<u64>,
+    /// How often to check the cache against the limits above
+    #[serde(default = "default_sweep_interval_secs")]
+    pub sweep_interval_secs: u64,
+    /// How long to remember that an upstream said an image doesn't exist
+    /// (404 or 410), or 0 not to
+    #[serde(default = "default_permanent_failure_ttl_secs")]
+    pub permanent_failure_ttl_secs: u64,
+    /// How long to remember that an upstream errored or timed out, or 0
+    /// not to
+    #[serde(default = "default_transient_failure_ttl_secs")]
+    pub transient_failure_ttl_secs: u64,
+    /// Refuse upstream responses larger than this
+    #[serde(default = "default_max_download_bytes")]
+    pub max_download_bytes: u64,
+    /// How long to wait for a connection to an upstream
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    /// How long to wait for each read from an upstream connection
+    #[serde(default = "default_read_timeout_ms")]
+    pub read_timeout_ms: u64,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub auth: UpstreamAuth,
+    #[serde(default)]
+    pub allow: UpstreamPolicy,
+}
+
+/// A load of one upstream URI, shared by all requests for it that arrive
+/// while it is under way.
+type Flight = Arc<OnceCell<Result<DynamicImage>>>;
+
+/// Result of a possibly conditional request to an upstream. Responses
+/// that must not be stored have no `expires_at`.
+enum Fetched {
+    Modified {
+        download: Download,
+        format: ImageFormat,
+        validators: Validators,
+        expires_at: Option<SystemTime>,
+    },
+    NotModified {
+        validators: Validators,
+        expires_at: Option<SystemTime>,
+    },
+}
+
+fn default_max_age_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_sweep_interval_secs() -> u64 {
+    5 * 60
+}
+
+fn default_permanent_failure_ttl_secs() -> u64 {
+    10 * 60
+}
+
+fn default_transient_failure_ttl_secs() -> u64 {
+    15
+}
+
+fn default_max_download_bytes() -> u64 {
+    512 * 1024 * 1024
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_read_timeout_ms() -> u64 {
+    1000
+}
+
+impl ProxyConfig {
+    /// Configuration for caching into `cache_dir` with default settings.
+    pub fn new<T: Into<PathBuf>>(cache_dir: T) -> Self {
+        Self {
+            cache_dir: cache_dir.into(),
+            url_template: None,
+            iiif_base_url: None,
+            default_max_age_secs: default_max_age_secs(),
+            max_cache_bytes: None,
+            max_entry_age_secs: None,
+            sweep_interval_secs: default_sweep_interval_secs(),
+            permanent_failure_ttl_secs: default_permanent_failure_ttl_secs(),
+            transient_failure_ttl_secs: default_transient_failure_ttl_secs(),
+            max_download_bytes: default_max_download_bytes(),
+            connect_timeout_ms: default_connect_timeout_ms(),
+            read_timeout_ms: default_read_timeout_ms(),
+            retry: RetryPolicy::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            auth: UpstreamAuth::default(),
+            allow: UpstreamPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ProxyLoader {
+    cache_dir: PathBuf,
+    url_template: Option<UrlTemplate>,
+    iiif_base_url: Option<Url>,
+    /// info.json of upstream IIIF images, with when they expire
+    remote_infos: Mutex<HashMap<String, (Arc<RemoteInfo>, SystemTime)>>,
+    index: Arc<CacheIndex>,
+    default_max_age: Duration,
+    eviction: EvictionPolicy,
+    sweep_interval: Duration,
+    permanent_failure_ttl: Duration,
+    transient_failure_ttl: Duration,
+    max_download_bytes: u64,
+    retry: RetryPolicy,
+    breaker: CircuitBreaker,
+    policy: Arc<UpstreamPolicy>,
+    client: reqwest::Client,
+    in_flight: Mutex<HashMap<String, Flight>>,
+}
+
+impl ProxyLoader {
+    /// Create a loader caching into `config.cache_dir`. URIs fetched by
+    /// earlier runs are served from the cache directory via the index kept
+    /// alongside it.
+    pub fn new(config: ProxyConfig) -> Result<Self> {
+        let ProxyConfig {
+            cache_dir,
+            url_template,
+            iiif_base_url,
+            default_max_age_secs,
+            max_cache_bytes,
+            max_entry_age_secs,
+            sweep_interval_secs,
+            permanent_failure_ttl_secs,
+            transient_failure_ttl_secs,
+            max_download_bytes,
+            connect_timeout_ms,
+            read_timeout_ms,
+            retry,
+            circuit_breaker,
+            auth,
+            allow,
+        } = config;
+        if url_template.is_some() && iiif_base_url.is_some() {
+            return Err(LoaderError::Config(
+                "url_template and iiif_base_url cannot be used together".into(),
+            ));
+        }
+        std::fs::create_dir_all(&cache_dir)?;
+        remove_interrupted_downloads(&cache_dir)?;
+        let index =
+            Arc::new(CacheIndex::open(cache_dir.join(CACHE_INDEX_FILE))?);
+        let policy = Arc::new(allow);
+        let resolver = GuardedResolver {
+            private_addresses: policy.private_addresses,
+        };
+        let redirect_policy = {
+            let policy = Arc::clone(&policy);
+            // The client drops Authorization headers when redirected to
+            // another host, but knows nothing of custom ones
+            let same_origin_only = auth.has_custom_headers();
+            redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error(RefusedRedirect("too many redirects"))
+                } else if same_origin_only
+                    && attempt.url().origin() != attempt.previous()[0].origin()
+                {
+                    attempt.error(RefusedRedirect(
+                        "refusing to send credentials to another host",
+                    ))
+                } else if let Err(e) = policy.check(attempt.url()) {
+                    attempt.error(e)
+                } else {
+                    attempt.follow()
+                }
+            })
+        };
+        let client = auth
+            .apply(reqwest::ClientBuilder::new())?
+            .user_agent(DEFAULT_USER_AGENT)
+            .connect_timeout(Duration::from_millis(connect_timeout_ms))
+            .read_timeout(Duration::from_millis(read_timeout_ms))
+            // Requests must go straight to the checked address
+            .no_proxy()
+            .dns_resolver(Arc::new(resolver))
+            .redirect(redirect_policy)
+            .build()
+            .expect("ProxyLoader: failed to initialize http client");
+
+        Ok(Self {
+            cache_dir,
+            url_template,
+            iiif_base_url,
+            remote_infos: Mutex::default(),
+            index,
+            default_max_age: Duration::from_secs(default_max_age_secs),
+            eviction: EvictionPolicy {
+                max_size: max_cache_bytes,
+                max_age: max_entry_age_secs.map(Duration::from_secs),
+            },
+            sweep_interval: Duration::from_secs(sweep_interval_secs),
+            permanent_failure_ttl: Duration::from_secs(
+                permanent_failure_ttl_secs,
+            ),
+            transient_failure_ttl: Duration::from_secs(
+                transient_failure_ttl_secs,
+            ),
+            max_download_bytes,
+            retry,
+            breaker: CircuitBreaker::new(circuit_breaker),
+            policy,
+            client,
+            in_flight: Mutex::default(),
+        })
+    }
+
+    /// Periodically evict content from the cache directory in the
+    /// background, if the cache has a size or age limit. The first run also
+    /// reconciles the index with the files on disk.
+    pub fn spawn_sweeper(&self) {
+        eviction::spawn_sweeper(
+            self.cache_dir.clone(),
+            Arc::clone(&self.index),
+            self.eviction,
+            self.sweep_interval,
+        );
+    }
+
+    async fn get_from_cache(
+        &self,
+        key: &ContentCacheKey,
+        format: ImageFormat,
+    ) -> Result<DynamicImage> {
+        decode_cached(&self.cache_dir, &self.index, key, format).await
+    }
+
+    /// The upstream URL `identifier` stands for: substituted into the URL
+    /// template if there is one, or else the base64url-encoded URL itself.
+    fn upstream_url(&self, identifier: &str) -> Result<Url> {
+        if let Some(template) = &self.url_template {
+            return template.expand(identifier);
+        }
+        let id = identifier.trim_end_matches('=');
+        let uri = Base64UrlUnpadded::decode_vec(id)
+            .map_err(|_| LoaderError::InvalidIdentifier)?;
+        let uri = String::from_utf8(uri)
+            .map_err(|_| LoaderError::InvalidIdentifier)?;
+        uri.parse().map_err(|_| LoaderError::InvalidIdentifier)
+    }
+
+    /// Send `request` for `uri`, retrying on transient failures, unless the
+    /// circuit breaker says the upstream host is down.
+    async fn send(
+        &self,
+        uri: &Url,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response> {
+        let host = format!(
+            "{}:{}",
+            uri.host_str().unwrap_or_default(),
+            uri.port_or_known_default().unwrap_or_default()
+        );
+        self.breaker.check(&host, Instant::now())?;
+
+        let mut retry = 0;
+        let result = loop {
+            let request = request
+                .try_clone()
+                .expect("GET requests have no streaming body");
+            let result = match request.send().await {
+                Ok(response) if is_transient_status(response.status()) => {
+                    Err(LoaderError::Upstream(format!(
+                        "{uri} responded with {}",
+                        response.status()
+                    )))
+                }
+                result => result.map_err(LoaderError::from),
+            };
+            match result {
+                Err(e)
+                    if is_transient(&e) && retry < self.retry.max_retries =>
+                {
+                    tokio::time::sleep(self.retry.backoff(retry)).await;
+                    retry += 1;
+                }
+                result => break result,
+            }
+        };
+        self.breaker.record(&host, &result, Instant::now());
+        result
+    }
+
+    /// Fetch `uri`, revalidating `cached` with a conditional request if given.
+    async fn get_from_uri(
+        &self,
+        uri: &Url,
+        cached: Option<&IndexEntry>,
+    ) -> Result<Fetched> {
+        let mut request = self.client.get(uri.clone());
+        if let Some(validators) = cached.map(|entry| &entry.validators) {
+            if let Some(etag) = &validators.etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request =
+                    request.header(header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let mut response = self.send(uri, request).await?;
+
+        let headers = response.headers();
+        let header_str = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        let validators = Validators {
+            etag: header_str(header::ETAG),
+            last_modified: header_str(header::LAST_MODIFIED),
+        };
+        let expires_at = http_cache::expires_at(
+            headers,
+            SystemTime::now(),
+            self.default_max_age,
+        );
+
+        match response.status() {
+            StatusCode::OK => {}
+            StatusCode::NOT_MODIFIED if cached.is_some() => {
+                return Ok(Fetched::NotModified {
+                    validators,
+                    expires_at,
+                });
+            }
+            StatusCode::NOT_FOUND | StatusCode::GONE => {
+                return Err(LoaderError::NotFound);
+            }
+            status => {
+                return Err(LoaderError::Upstream(format!(
+                    "{uri} responded with {status}"
+                )));
+            }
+        }
+
+        let (download, format) =
+            download(&mut response, &self.cache_dir, self.max_download_bytes)
+                .await?;
+        Ok(Fetched::Modified {
+            download,
+            format,
+            validators,
+            expires_at,
+        })
+    }
+
+    /// Move `download` of `uri` into the cache, keyed by its hash, and
+    /// return the key.
+    async fn write_in_cache(
+        &self,
+        download: Download,
+        uri: &str,
+        format: ImageFormat,
+        validators: Validators,
+        expires_at: SystemTime,
+    ) -> Result<ContentCacheKey> {
+        spawn_store(
+            &self.cache_dir,
+            &self.index,
+            download,
+            uri,
+            format,
+            validators,
+            expires_at,
+        )
+        .await
+    }
+
+    /// Load `uri` from the cache, or from the upstream if it is missing or
+    /// stale, and keep the cache up to date.
+    async fn load_uri(&self, uri: &str, url: &Url) -> Result<DynamicImage> {
+        // Only revalidate entries whose content is still on disk
+        let cached = self.cached(uri).await?.filter(|entry| {
+            cached_img_path(&self.cache_dir, &entry.content_hash).is_file()
+        });
+        if let Some(entry) = &cached
+            && entry.expires_at > SystemTime::now()
+        {
+            match self.get_from_cache(&entry.content_hash, entry.format).await {
+                // The cached file has gone missing, fetch it again
+                Err(LoaderError::NotFound) => {}
+                result => return result,
+            }
+        }
+
+        let result = match self.get_failure(uri, SystemTime::now()).await? {
+            Some(failure) => Err(failure_error(failure)),
+            None => {
+                let result = self.fetch_uri(uri, url, cached.as_ref()).await;
+                self.remember_outcome(uri, &result).await?;
+                result
+            }
+        };
+
+        match result {
+            // Serve stale content rather than nothing while the upstream is
+            // having trouble
+            Err(e @ (LoaderError::Upstream(_) | LoaderError::Timeout)) => {
+                match &cached {
+                    Some(entry) => {
+                        self.get_from_cache(&entry.content_hash, entry.format)
+                            .await
+                    }
+                    None => Err(e),
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Fetch `uri` from the upstream, revalidating `cached` if given, and
+    /// update the cache with the response.
+    async fn fetch_uri(
+        &self,
+        uri: &str,
+        url: &Url,
+        cached: Option<&IndexEntry>,
+    ) -> Result<DynamicImage> {
+        match self.get_from_uri(url, cached).await {
+            Ok(Fetched::Modified {
+                download,
+                format,
+                expires_at: None,
+                ..
+            }) => {
+                self.forget(uri).await?;
+                download.decode(format).await
+            }
+            Ok(Fetched::Modified {
+                download,
+                format,
+                validators,
+                expires_at: Some(expires_at),
+            }) => {
+                let key = self
+                    .write_in_cache(
+                        download, uri, format, validators, expires_at,
+                    )
+                    .await?;
+                self.get_from_cache(&key, format).await
+            }
+            Ok(Fetched::NotModified {
+                expires_at: None, ..
+            }) => {
+                let entry = cached.expect("only revalidated when cached");
+                let image = self
+                    .get_from_cache(&entry.content_hash, entry.format)
+                    .await;
+                self.forget(uri).await?;
+                image
+            }
+            Ok(Fetched::NotModified {
+                validators,
+                expires_at: Some(expires_at),
+            }) => {
+                let entry =
+                    cached.cloned().expect("only revalidated when cached");
+                let entry = IndexEntry {
+                    expires_at,
+                    validators: Validators {
+                        etag: validators.etag.or(entry.validators.etag),
+                        last_modified: validators
+                            .last_modified
+                            .or(entry.validators.last_modified),
+                    },
+                    ..entry
+                };
+                let (uri, stored) = (uri.to_owned(), entry.clone());
+                self.index
+                    .run(move |index| index.insert(&uri, &stored))
+                    .await?;
+                self.get_from_cache(&entry.content_hash, entry.format).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stop serving `uri` from the cache, for an upstream that no longer
+    /// allows it to be stored.
+    async fn forget(&self, uri: &str) -> Result<()> {
+        let cache_dir = self.cache_dir.clone();
+        let index = Arc::clone(&self.index);
+        let uri = uri.to_owned();
+        tokio::task::spawn_blocking(move || forget(&cache_dir, &index, &uri))
+            .await
+            .expect("removing from the cache panicked")
+    }
+
+    /// Remember that fetching `uri` failed, so that requests for it fail
+    /// fast for a while, or forget an earlier failure if it succeeded.
+    async fn remember_outcome<T>(
+        &self,
+        uri: &str,
+        result: &Result<T>,
+    ) -> Result<()> {
+        let uri = uri.to_owned();
+        let (kind, message, ttl) = match result {
+            Ok(_) => {
+                self.index
+                    .run(move |index| index.remove_failures(Some(&uri)))
+                    .await?;
+                return Ok(());
+            }
+            Err(LoaderError::NotFound) => {
+                (FailureKind::NotFound, "", self.permanent_failure_ttl)
+            }
+            Err(LoaderError::Upstream(msg)) => (
+                FailureKind::Upstream,
+                msg.as_str(),
+                self.transient_failure_ttl,
+            ),
+            Err(LoaderError::Timeout) => {
+                (FailureKind::Timeout, "", self.transient_failure_ttl)
+            }
+            Err(_) => return Ok(()),
+        };
+        if ttl.is_zero() {
+            return Ok(());
+        }
+        let now = SystemTime::now();
+        let failure = Failure {
+            kind,
+            message: message.to_owned(),
+            expires_at: now + ttl,
+        };
+        self.index
+            .run(move |index| index.insert_failure(&uri, &failure, now))
+            .await?;
+        Ok(())
+    }
+
+    /// The index entry of `uri`, if it has been cached.
+    async fn cached(&self, uri: &str) -> Result<Option<IndexEntry>> {
+        let uri = uri.to_owned();
+        Ok(self.index.run(move |index| index.get(&uri)).await?)
+    }
+
+    /// The remembered failure to fetch `uri`, if it has not expired by
+    /// `now`.
+    async fn get_failure(
+        &self,
+        uri: &str,
+        now: SystemTime,
+    ) -> Result<Option<Failure>> {
+        let uri = uri.to_owned();
+        Ok(self
+            .index
+            .run(move |index| index.get_failure(&uri, now))
+            .await?)
+    }
+
+    /// Forget remembered upstream failures for `identifier`, or for all
+    /// identifiers if `None`, so that they are fetched again on the next
+    /// request. Returns the number of failures forgotten.
+    pub async fn purge_failures(
+        &self,
+        identifier: Option<&str>,
+    ) -> Result<usize> {
+        // Everything fetched for an image of an upstream IIIF server is
+        // under its base URI
+        if let (Some(base), Some(identifier)) =
+            (&self.iiif_base_url, identifier)
+        {
+            let base_uri = cascade::image_url(base, identifier, "")?;
+            let base_uri = String::from(base_uri);
+            return Ok(self
+                .index
+                .run(move |index| index.remove_failures_under(&base_uri))
+                .await?);
+        }
+        let uri = identifier
+            .map(|identifier| self.upstream_url(identifier))
+            .transpose()?
+            .map(String::from);
+        Ok(self
+            .index
+            .run(move |index| index.remove_failures(uri.as_deref()))
+            .await?)
+    }
+
+    /// Load the image at `url`, through the cache.
+    async fn get_url(&self, url: Url) -> Result<DynamicImage> {
+        self.policy.check(&url)?;
+        let uri = url.to_string();
+
+        // Requests for a URI that is already being loaded wait for that load
+        // instead of starting their own. Should the request driving it go
+        // away, one of the waiters takes over.
+        let flight = FlightGuard::join(&self.in_flight, uri.clone());
+        flight
+            .get_or_init(|| self.load_uri(&uri, &url))
+            .await
+            .clone()
+    }
+
+    /// The info.json of `identifier` on the upstream IIIF server at `base`,
+    /// kept in memory for as long as the upstream allows.
+    async fn remote_info(
+        &self,
+        base: &Url,
+        identifier: &str,
+    ) -> Result<Arc<RemoteInfo>> {
+        let url = cascade::image_url(base, identifier, "info.json")?;
+        self.policy.check(&url)?;
+        let uri = url.to_string();
+        let now = SystemTime::now();
+        {
+            let mut remote_infos = self.remote_infos.lock().unwrap();
+            remote_infos.retain(|_, (_, expires_at)| *expires_at > now);
+            if let Some((info, _)) = remote_infos.get(&uri) {
+                return Ok(Arc::clone(info));
+            }
+        }
+        if let Some(failure) = self.get_failure(&uri, now).await? {
+            return Err(failure_error(failure));
+        }
+
+        let result = self.fetch_info(&url).await;
+        self.remember_outcome(&uri, &result).await?;
+        let (info, expires_at) = result?;
+        let info = Arc::new(info);
+        self.remote_infos
+            .lock()
+            .unwrap()
+            .insert(uri, (Arc::clone(&info), expires_at));
+        Ok(info)
+    }
+
+    async fn fetch_info(&self, url: &Url) -> Result<(RemoteInfo, SystemTime)> {
+        let request = self
+            .client
+            .get(url.clone())
+            .header(header::ACCEPT, "application/ld+json, application/json");
+        let mut response = self.send(url, request).await?;
+        match response.status() {
+            StatusCode::OK => {}
+            StatusCode::NOT_FOUND | StatusCode::GONE => {
+                return Err(LoaderError::NotFound);
+            }
+            status => {
+                return Err(LoaderError::Upstream(format!(
+                    "{url} responded with {status}"
+                )));
+            }
+        }
+        let now = SystemTime::now();
+        // Kept in memory only, and not past the request that fetched it
+        let expires_at = http_cache::expires_at(
+            response.headers(),
+            now,
+            self.default_max_age,
+        )
+        .unwrap_or(now);
+
+        let mut body = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > MAX_INFO_BYTES {
+                return Err(LoaderError::Upstream(format!(
+                    "{url} is larger than {MAX_INFO_BYTES} bytes"
+                )));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        let mut info: RemoteInfo = serde_json::from_slice(&body)
+            .ok()
+            .filter(|info: &RemoteInfo| info.width > 0 && info.height > 0)
+            .ok_or_else(|| {
+                LoaderError::Upstream(format!("{url} is not a valid info.json"))
+            })?;
+        // The image can still be fetched a region at a time
+        if let Err(e) = info.check_tiles() {
+            eprintln!("{url}: {e}, not fetching tiles");
+            info.clear_tiles();
+        }
+        Ok((info, expires_at))
+    }
+
+    /// Put together `region` of `identifier` at `size` from what the
+    /// upstream IIIF server at `base` serves: the tiles overlapping the
+    /// region at the smallest scale with enough detail, if it has tiles, or
+    /// else the region itself. The pieces are cached like any other image.
+    async fn get_cascaded(
+        &self,
+        base: &Url,
+        identifier: &str,
+        region: &Region,
+        size: &Size,
+    ) -> Result<DynamicImage> {
+        let info = self.remote_info(base, identifier).await?;
+        let rect = region_rect(info.width, info.height, region);
+        let out = output_size(rect.2, rect.3, size)
+            .ok_or(LoaderError::InvalidSize)?;
+        let plan = info.plan(rect, out);
+
+        let mut pieces = try_join_all(plan.pieces.iter().map(|piece| async {
+            let url = info.piece_url(base, identifier, piece)?;
+            Ok::<_, LoaderError>((piece.offset, self.get_url(url).await?))
+        }))
+        .await?;
+        let image = if let [(_, image)] = pieces.as_mut_slice() {
+            std::mem::take(image)
+        } else {
+            let (width, height) = plan.canvas;
+            let mut canvas = RgbImage::new(width, height);
+            for ((x, y), piece) in &pieces {
+                imageops::replace(
+                    &mut canvas,
+                    &piece.to_rgb8(),
+                    (*x).into(),
+                    (*y).into(),
+                );
+            }
+            DynamicImage::ImageRgb8(canvas)
+        };
+
+        let (x, y, w, h) = plan.crop;
+        let image = if (x, y, w, h) == (0, 0, image.width(), image.height()) {
+            image
+        } else {
+            image.crop_imm(x, y, w, h)
+        };
+        if image.dimensions() == out {
+            Ok(image)
+        } else {
+            Ok(image.resize_exact(out.0, out.1, FilterType::Triangle))
+        }
+    }
+}
+
+impl GenericImageLoader for ProxyLoader {
+    async fn get_image(
+        &self,
+        _prefix: &str,
+        identifier: &str,
+    ) -> Result<DynamicImage> {
+        match &self.iiif_base_url {
+            Some(base) => {
+                self.get_cascaded(
+                    base,
+                    identifier,
+                    &Region::Full,
+                    &Size::default(),
+                )
+                .await
+            }
+            None => self.get_url(self.upstream_url(identifier)?).await,
+        }
+    }
+
+    async fn get_dimensions(
+        &self,
+        _prefix: &str,
+        identifier: &str,
+    ) -> Result<(u32, u32)> {
+        match &self.iiif_base_url {
+            Some(base) => {
+                let info = self.remote_info(base, identifier).await?;
+                Ok((info.width, info.height))
+            }
+            None => Ok(self
+                .get_url(self.upstream_url(identifier)?)
+                .await?
+                .dimensions()),
+        }
+    }
+
+    /// The hash of the content cached for `identifier`, while it is fresh.
+    /// Images put together from an upstream IIIF server have no version.
+    async fn get_version(
+        &self,
+        _prefix: &str,
+        identifier: &str,
+    ) -> Result<Option<String>> {
+        if self.iiif_base_url.is_some() {
+            return Ok(None);
+        }
+        let uri = String::from(self.upstream_url(identifier)?);
+        Ok(self.cached(&uri).await?.and_then(|entry| {
+            cached_version(&self.cache_dir, &entry, SystemTime::now())
+        }))
+    }
+
+    async fn get_region(
+        &self,
+        _prefix: &str,
+        identifier: &str,
+        region: &Region,
+        size: &Size,
+    ) -> Result<DynamicImage> {
+        match &self.iiif_base_url {
+            Some(base) => {
+                self.get_cascaded(base, identifier, region, size).await
+            }
+            None => {
+                let image =
+                    self.get_url(self.upstream_url(identifier)?).await?;
+                crop_and_resize(image, region, size)
+            }
+        }
+    }
+}
+
+/// The error to fail with while `failure` is remembered.
+fn failure_error(failure: Failure) -> LoaderError {
+    match failure.kind {
+        FailureKind::NotFound => LoaderError::NotFound,
+        FailureKind::Upstream => LoaderError::Upstream(failure.message),
+        FailureKind::Timeout => LoaderError::Timeout,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::image::SizeKind;
+    use crate::image_loader::{BasicAuth, ImageCache, SecretSource};
+    use axum::{
+        Router,
+        extract::{Path as AxumPath, Query, State},
+        http::{HeaderMap, Uri},
+        response::{IntoResponse, Redirect, Response},
+        routing::get,
+    };
+    use base64ct::{Base64UrlUnpadded, Encoding};
+    use sha2::{Digest, Sha256};
+    use std::path::Path;
+    use std::{
+        collections::HashMap,
+        io::Cursor,
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    #[derive(Default)]
+    struct Upstream {
+        hits: AtomicUsize,
+        /// Bumped to change the image served, which is `4 + version` pixels
+        /// wide
+        version: AtomicUsize,
+    }
+
+    /// A local upstream serving a small PNG at `/image.png`, with its ETag
+    /// and a `max-age`, or a whole `Cache-Control`, taken from the query,
+    /// redirecting to the `to` query parameter at `/redirect`, and failing
+    /// at `/error`. `/octet-stream` serves the PNG without saying what it
+    /// is, `/page.jpg` serves HTML, and `/private.png` requires an API key
+    /// and a bearer token. `/gone` answers 410 Gone.
+    async fn stub_upstream() -> (SocketAddr, Arc<Upstream>) {
+        async fn image(
+            State(upstream): State<Arc<Upstream>>,
+            Query(query): Query<HashMap<String, String>>,
+            headers: HeaderMap,
+        ) -> Response {
+            upstream.hits.fetch_add(1, Ordering::SeqCst);
+            let version = upstream.version.load(Ordering::SeqCst);
+            let etag = format!("\"v{version}\"");
+            let cache_control =
+                query.get("cache_control").cloned().unwrap_or_else(|| {
+                    format!(
+                        "max-age={}",
+                        query.get("max_age").map_or("0", String::as_str)
+                    )
+                });
+            if headers
+                .get(header::IF_NONE_MATCH)
+                .is_some_and(|v| v == &etag)
+            {
+                return (
+                    StatusCode::NOT_MODIFIED,
+                    [(header::CACHE_CONTROL, cache_control)],
+                )
+                    .into_response();
+            }
+            let mut png = Cursor::new(vec![]);
+            DynamicImage::new_rgb8(4 + version as u32, 3)
+                .write_to(&mut png, ImageFormat::Png)
+                .unwrap();
+            (
+                [
+                    (header::CONTENT_TYPE, "image/png".into()),
+                    (header::ETAG, etag),
+                    (header::CACHE_CONTROL, cache_control),
+                ],
+                png.into_inner(),
+            )
+                .into_response()
+        }
+
+        async fn redirect(
+            State(upstream): State<Arc<Upstream>>,
+            Query(query): Query<HashMap<String, String>>,
+        ) -> Redirect {
+            upstream.hits.fetch_add(1, Ordering::SeqCst);
+            Redirect::temporary(&query["to"])
+        }
+
+        async fn redirect_loop(
+            State(upstream): State<Arc<Upstream>>,
+        ) -> Redirect {
+            upstream.hits.fetch_add(1, Ordering::SeqCst);
+            Redirect::temporary("/loop")
+        }
+
+        async fn error(State(upstream): State<Arc<Upstream>>) -> StatusCode {
+            upstream.hits.fetch_add(1, Ordering::SeqCst);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+
+        async fn octet_stream() -> impl IntoResponse {
+            let mut png = Cursor::new(vec![]);
+            DynamicImage::new_rgb8(4, 3)
+                .write_to(&mut png, ImageFormat::Png)
+                .unwrap();
+            (
+                [(header::CONTENT_TYPE, "application/octet-stream")],
+                png.into_inner(),
+            )
+        }
+
+        async fn page() -> impl IntoResponse {
+            (
+                [(header::CONTENT_TYPE, "text/html")],
+                "<!doctype html><title>Not found</title>",
+            )
+        }
+
+        async fn private(headers: HeaderMap) -> Response {
+            if headers.get("x-api-key").is_none_or(|key| key != "k3y")
+                || headers
+                    .get(header::AUTHORIZATION)
+                    .is_none_or(|auth| auth != "Bearer s3cret")
+            {
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+            octet_stream().await.into_response()
+        }
+
+        async fn gone(State(upstream): State<Arc<Upstream>>) -> StatusCode {
+            upstream.hits.fetch_add(1, Ordering::SeqCst);
+            StatusCode::GONE
+        }
+
+        let upstream = Arc::new(Upstream::default());
+        let app = Router::new()
+            .route("/gone", get(gone))
+            .route("/private.png", get(private))
+            .route("/octet-stream", get(octet_stream))
+            .route("/page.jpg", get(page))
+            .route("/image.png", get(image))
+            .route("/redirect", get(redirect))
+            .route("/loop", get(redirect_loop))
+            .route("/error", get(error))
+            .with_state(Arc::clone(&upstream));
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (addr, upstream)
+    }
+
+    fn local_policy() -> UpstreamPolicy {
+        UpstreamPolicy {
+            schemes: vec!["http".into()],
+            hosts: vec!["127.0.0.1".into()],
+            private_addresses: true,
+            ..Default::default()
+        }
+    }
+
+    fn loader(cache_dir: &Path, allow: UpstreamPolicy) -> ProxyLoader {
+        ProxyLoader::new(ProxyConfig {
+            allow,
+            ..ProxyConfig::new(cache_dir)
+        })
+        .unwrap()
+    }
+
+    fn identifier(uri: &str) -> String {
+        Base64UrlUnpadded::encode_string(uri.as_bytes())
+    }
+
+    #[tokio::test]
+    async fn test_refuses_private_addresses() {
+        let (addr, upstream) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let proxy = loader(cache.path(), UpstreamPolicy::default());
+
+        for uri in [
+            format!("http://{addr}/image.png"),
+            format!("http://localhost:{}/image.png", addr.port()),
+        ] {
+            let result = proxy.get_image("proxy", &identifier(&uri)).await;
+            assert!(
+                matches!(result, Err(LoaderError::Forbidden(_))),
+                "{uri}: {result:?}"
+            );
+        }
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_allowlist() {
+        let (addr, upstream) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let proxy = loader(cache.path(), local_policy());
+
+        let uri = format!("http://{addr}/image.png");
+        let image = proxy.get_image("proxy", &identifier(&uri)).await.unwrap();
+        assert_eq!((image.width(), image.height()), (4, 3));
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+
+        let uri = format!("http://localhost:{}/image.png", addr.port());
+        let result = proxy.get_image("proxy", &identifier(&uri)).await;
+        assert!(matches!(result, Err(LoaderError::Forbidden(_))));
+
+        // Redirects are checked against the policy too
+        let uri = format!(
+            "http://{addr}/redirect?to=http://localhost:{}/image.png",
+            addr.port()
+        );
+        let result = proxy.get_image("proxy", &identifier(&uri)).await;
+        assert!(
+            matches!(result, Err(LoaderError::Forbidden(_))),
+            "{result:?}"
+        );
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_freshness_and_revalidation() {
+        let (addr, upstream) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let proxy = loader(cache.path(), local_policy());
+        let hits = || upstream.hits.load(Ordering::SeqCst);
+
+        // Fresh entries are served without asking the upstream
+        let fresh = identifier(&format!("http://{addr}/image.png?max_age=60"));
+        proxy.get_image("proxy", &fresh).await.unwrap();
+        proxy.get_image("proxy", &fresh).await.unwrap();
+        assert_eq!(hits(), 1);
+
+        // Stale entries are revalidated, and kept on 304 Not Modified
+        let stale = identifier(&format!("http://{addr}/image.png"));
+        let image = proxy.get_image("proxy", &stale).await.unwrap();
+        assert_eq!(image.width(), 4);
+        let image = proxy.get_image("proxy", &stale).await.unwrap();
+        assert_eq!(image.width(), 4);
+        assert_eq!(hits(), 3);
+
+        // and replaced when the upstream sends new content
+        upstream.version.store(1, Ordering::SeqCst);
+        let image = proxy.get_image("proxy", &stale).await.unwrap();
+        assert_eq!(image.width(), 5);
+        let image = proxy.get_image("proxy", &fresh).await.unwrap();
+        assert_eq!(image.width(), 4);
+        assert_eq!(hits(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_no_store() {
+        let (addr, upstream) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let proxy = loader(cache.path(), local_policy());
+
+        // Served, but neither stored nor given a version
+        let id = identifier(&format!(
+            "http://{addr}/image.png?cache_control=no-store"
+        ));
+        for _ in 0..2 {
+            let image = proxy.get_image("proxy", &id).await.unwrap();
+            assert_eq!(image.width(), 4);
+            assert_eq!(proxy.get_version("proxy", &id).await.unwrap(), None);
+        }
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 2);
+        assert!(proxy.index.content_hashes().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_versions() {
+        let (addr, upstream) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let proxy = loader(cache.path(), local_policy());
+        let image_cache = ImageCache::new(1 << 20);
+        let (full, max): (Region, Size) =
+            ("full".parse().unwrap(), "max".parse().unwrap());
+        let fresh = identifier(&format!("http://{addr}/image.png?max_age=60"));
+        let version = || proxy.get_version("proxy", &fresh);
+
+        // Images get a version once they are in the content cache, and are
+        // then kept in memory once decoded
+        assert_eq!(version().await.unwrap(), None);
+        for _ in 0..3 {
+            image_cache
+                .get_region(&proxy, "proxy", &fresh, &full, &max)
+                .await
+                .unwrap();
+        }
+        assert!(version().await.unwrap().is_some());
+        let stats = image_cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+
+        // Stale ones have none until they are revalidated
+        let stale = identifier(&format!("http://{addr}/image.png"));
+        proxy.get_image("proxy", &stale).await.unwrap();
+        assert_eq!(proxy.get_version("proxy", &stale).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_coalesces_concurrent_fetches() {
+        let (addr, upstream) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let proxy = Arc::new(loader(cache.path(), local_policy()));
+
+        let get_concurrently = |uri: String| {
+            let mut requests = tokio::task::JoinSet::new();
+            for _ in 0..8 {
+                let proxy = Arc::clone(&proxy);
+                let id = identifier(&uri);
+                requests
+                    .spawn(async move { proxy.get_image("proxy", &id).await });
+            }
+            requests.join_all()
+        };
+
+        let images = get_concurrently(format!("http://{addr}/image.png")).await;
+        assert!(
+            images
+                .iter()
+                .all(|image| image.as_ref().unwrap().width() == 4)
+        );
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+
+        // Failures are shared with every waiter too, after the one request
+        // has been retried
+        let errors = get_concurrently(format!("http://{addr}/error")).await;
+        assert!(
+            errors
+                .iter()
+                .all(|error| matches!(error, Err(LoaderError::Upstream(_))))
+        );
+        let attempts = 1 + RetryPolicy::default().max_retries as usize;
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1 + attempts);
+        assert!(proxy.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stores_upstream_bytes() {
+        let (addr, _) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let proxy = loader(cache.path(), local_policy());
+
+        let uris = [
+            format!("http://{addr}/image.png"),
+            format!("http://{addr}/image.png?max_age=60"),
+        ];
+        for uri in &uris {
+            proxy.get_image("proxy", &identifier(uri)).await.unwrap();
+        }
+
+        // Both URIs serve the same body, which is stored once, as sent
+        let body = reqwest::get(&uris[0]).await.unwrap().bytes().await.unwrap();
+        let entries: Vec<_> = uris
+            .iter()
+            .map(|uri| proxy.index.get(uri).unwrap().unwrap())
+            .collect();
+        assert_eq!(entries[0].content_hash, entries[1].content_hash);
+        assert_eq!(
+            entries[0].content_hash,
+            ContentCacheKey::from(Sha256::digest(&body))
+        );
+        let path = cached_img_path(cache.path(), &entries[0].content_hash);
+        assert_eq!(std::fs::read(path).unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn test_max_download_size() {
+        let (addr, upstream) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let proxy = ProxyLoader::new(ProxyConfig {
+            max_download_bytes: 16,
+            allow: local_policy(),
+            ..ProxyConfig::new(cache.path())
+        })
+        .unwrap();
+
+        let uri = format!("http://{addr}/image.png");
+        let result = proxy.get_image("proxy", &identifier(&uri)).await;
+        assert!(
+            matches!(result, Err(LoaderError::Upstream(_))),
+            "{result:?}"
+        );
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+        assert!(proxy.index.get(&uri).unwrap().is_none());
+        let files: Vec<_> = std::fs::read_dir(cache.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, [CACHE_INDEX_FILE]);
+    }
+
+    #[tokio::test]
+    async fn test_retries_and_circuit_breaker() {
+        let (addr, upstream) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let proxy = ProxyLoader::new(ProxyConfig {
+            retry: RetryPolicy {
+                max_retries: 2,
+                initial_backoff_ms: 1,
+                max_backoff_ms: 1,
+            },
+            circuit_breaker: CircuitBreakerConfig {
+                failure_threshold: 2,
+                open_secs: 60,
+            },
+            transient_failure_ttl_secs: 0,
+            allow: local_policy(),
+            ..ProxyConfig::new(cache.path())
+        })
+        .unwrap();
+        let hits = || upstream.hits.load(Ordering::SeqCst);
+        let error = identifier(&format!("http://{addr}/error"));
+        let image = identifier(&format!("http://{addr}/image.png"));
+
+        // Failed requests are retried, and a success resets the failure count
+        let result = proxy.get_image("proxy", &error).await;
+        assert!(matches!(result, Err(LoaderError::Upstream(_))));
+        assert_eq!(hits(), 3);
+        proxy.get_image("proxy", &image).await.unwrap();
+        assert_eq!(hits(), 4);
+
+        // Two failures in a row open the circuit for the whole host
+        for _ in 0..2 {
+            let result = proxy.get_image("proxy", &error).await;
+            assert!(matches!(result, Err(LoaderError::Upstream(_))));
+        }
+        assert_eq!(hits(), 10);
+        let result = proxy.get_image("proxy", &error).await;
+        assert!(matches!(result, Err(LoaderError::Upstream(_))));
+        let fresh = identifier(&format!("http://{addr}/image.png?max_age=1"));
+        let result = proxy.get_image("proxy", &fresh).await;
+        assert!(matches!(result, Err(LoaderError::Upstream(_))));
+        assert_eq!(hits(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_bad_redirects_are_not_retried() {
+        let (addr, upstream) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let proxy = ProxyLoader::new(ProxyConfig {
+            retry: RetryPolicy {
+                max_retries: 2,
+                initial_backoff_ms: 1,
+                max_backoff_ms: 1,
+            },
+            circuit_breaker: CircuitBreakerConfig {
+                failure_threshold: 1,
+                open_secs: 60,
+            },
+            allow: local_policy(),
+            ..ProxyConfig::new(cache.path())
+        })
+        .unwrap();
+        let hits = || upstream.hits.load(Ordering::SeqCst);
+
+        // A redirect loop is followed once, up to the limit, and not held
+        // against the host
+        let looping = identifier(&format!("http://{addr}/loop"));
+        let result = proxy.get_image("proxy", &looping).await;
+        assert!(
+            matches!(result, Err(LoaderError::BadRedirect(_))),
+            "{result:?}"
+        );
+        assert_eq!(hits(), MAX_REDIRECTS);
+        let image = identifier(&format!("http://{addr}/image.png"));
+        proxy.get_image("proxy", &image).await.unwrap();
+        assert_eq!(hits(), MAX_REDIRECTS + 1);
+    }
+
+    #[tokio::test]
+    async fn test_url_template() {
+        let (addr, upstream) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let template = format!("http://{addr}/{{id}}.png?max_age=60");
+        let proxy = ProxyLoader::new(ProxyConfig {
+            url_template: Some(template.try_into().unwrap()),
+            allow: local_policy(),
+            ..ProxyConfig::new(cache.path())
+        })
+        .unwrap();
+
+        let image = proxy.get_image("proxy", "image").await.unwrap();
+        assert_eq!(image.width(), 4);
+        assert!(
+            proxy
+                .index
+                .get(&format!("http://{addr}/image.png?max_age=60"))
+                .unwrap()
+                .is_some()
+        );
+
+        // Identifiers cannot step out of their place in the template, and
+        // only ever reach paths the stub has no route for
+        let result = proxy.get_image("proxy", "../image").await;
+        assert!(matches!(result, Err(LoaderError::NotFound)), "{result:?}");
+        let result = proxy
+            .get_image(
+                "proxy",
+                &identifier(&format!("http://{addr}/image.png")),
+            )
+            .await;
+        assert!(matches!(result, Err(LoaderError::NotFound)), "{result:?}");
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+    }
+
+    /// The image served by [`stub_iiif`], with a different colour for
+    /// every pixel.
+    fn iiif_source() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(100, 60, |x, y| {
+            image::Rgb([x as u8, y as u8, (x + y) as u8])
+        }))
+    }
+
+    /// A local IIIF image server with the single image `ms 1`, in tiles of
+    /// 32 pixels at scale factors 1, 2 and 4. Paths requested are recorded.
+    async fn stub_iiif() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        async fn info(
+            State(paths): State<Arc<Mutex<Vec<String>>>>,
+            uri: Uri,
+            AxumPath(id): AxumPath<String>,
+        ) -> Response {
+            paths.lock().unwrap().push(uri.path().to_owned());
+            // "ms 3" is the same image, described with tiles at a scale
+            // factor that is not a power of two
+            let scale_factors = match id.as_str() {
+                "ms 1" => "[1, 2, 4]",
+                "ms 3" => "[1, 3]",
+                _ => return StatusCode::NOT_FOUND.into_response(),
+            };
+            (
+                [(header::CACHE_CONTROL, "max-age=60")],
+                format!(
+                    r#"{{
+                        "@context": "http://iiif.io/api/image/3/context.json",
+                        "type": "ImageService3",
+                        "width": 100, "height": 60,
+                        "tiles": [{{"width": 32, "scaleFactors": {scale_factors}}}]
+                    }}"#
+                ),
+            )
+                .into_response()
+        }
+
+        async fn image(
+            State(paths): State<Arc<Mutex<Vec<String>>>>,
+            uri: Uri,
+            AxumPath((id, region, size)): AxumPath<(String, String, String)>,
+        ) -> Response {
+            paths.lock().unwrap().push(uri.path().to_owned());
+            if id != "ms 1" && id != "ms 3" {
+                return StatusCode::NOT_FOUND.into_response();
+            }
+            let mut image = iiif_source();
+            if region != "full" {
+                let r: Vec<u32> =
+                    region.split(',').map(|n| n.parse().unwrap()).collect();
+                image = image.crop_imm(r[0], r[1], r[2], r[3]);
+            }
+            if size != "max" {
+                let (w, h) = size.split_once(',').unwrap();
+                image = image.resize_exact(
+                    w.parse().unwrap(),
+                    h.parse().unwrap(),
+                    FilterType::Triangle,
+                );
+            }
+            let mut png = Cursor::new(vec![]);
+            image.write_to(&mut png, ImageFormat::Png).unwrap();
+            (
+                [
+                    (header::CONTENT_TYPE, "image/png"),
+                    (header::CACHE_CONTROL, "max-age=60"),
+                ],
+                png.into_inner(),
+            )
+                .into_response()
+        }
+
+        let paths = Arc::default();
+        let app = Router::new()
+            .route("/iiif/{id}/info.json", get(info))
+            .route("/iiif/{id}/{region}/{size}/0/default.jpg", get(image))
+            .with_state(Arc::clone(&paths));
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (addr, paths)
+    }
+
+    #[tokio::test]
+    async fn test_iiif_cascade() {
+        let (addr, paths) = stub_iiif().await;
+        let cache = tempfile::tempdir().unwrap();
+        let proxy = ProxyLoader::new(ProxyConfig {
+            iiif_base_url: Some(
+                format!("http://{addr}/iiif/").parse().unwrap(),
+            ),
+            allow: local_policy(),
+            ..ProxyConfig::new(cache.path())
+        })
+        .unwrap();
+        let requests = || paths.lock().unwrap().len();
+        let source = iiif_source();
+
+        // Dimensions come from the info.json alone
+        let dimensions = proxy.get_dimensions("proxy", "ms 1").await.unwrap();
+        assert_eq!(dimensions, (100, 60));
+        assert_eq!(requests(), 1);
+
+        // Full resolution is put together from the tiles overlapping the
+        // region
+        let region = Region::Absolute {
+            x: 10,
+            y: 20,
+            w: 40.try_into().unwrap(),
+            h: 30.try_into().unwrap(),
+        };
+        let image = proxy
+            .get_region("proxy", "ms 1", &region, &Size::default())
+            .await
+            .unwrap();
+        assert_eq!(image.to_rgb8(), source.crop_imm(10, 20, 40, 30).to_rgb8());
+        assert_eq!(requests(), 5);
+        assert!(
+            paths
+                .lock()
+                .unwrap()
+                .contains(&"/iiif/ms%201/32,32,32,28/max/0/default.jpg".into())
+        );
+        let image = proxy.get_image("proxy", "ms 1").await.unwrap();
+        assert_eq!(image.to_rgb8(), source.to_rgb8());
+        assert_eq!(requests(), 9);
+
+        // Thumbnails are asked for at the scale they are needed at
+        let size = Size {
+            kind: SizeKind::Width(25.try_into().unwrap()),
+            ..Size::default()
+        };
+        let image = proxy
+            .get_region("proxy", "ms 1", &Region::Full, &size)
+            .await
+            .unwrap();
+        assert_eq!(image.dimensions(), (25, 15));
+        assert_eq!(
+            paths.lock().unwrap().last().unwrap(),
+            "/iiif/ms%201/full/25,15/0/default.jpg"
+        );
+        assert_eq!(requests(), 10);
+
+        // and everything fetched is cached
+        proxy
+            .get_region("proxy", "ms 1", &region, &Size::default())
+            .await
+            .unwrap();
+        proxy
+            .get_region("proxy", "ms 1", &Region::Full, &size)
+            .await
+            .unwrap();
+        assert_eq!(requests(), 10);
+
+        let result = proxy.get_dimensions("proxy", "ms 2").await;
+        assert!(matches!(result, Err(LoaderError::NotFound)), "{result:?}");
+        let result = proxy
+            .get_region(
+                "proxy",
+                "ms 1",
+                &region,
+                &Size {
+                    kind: SizeKind::Width(41.try_into().unwrap()),
+                    ..Size::default()
+                },
+            )
+            .await;
+        assert!(matches!(result, Err(LoaderError::InvalidSize)));
+
+        // Regions are asked for whole from servers whose tiles are invalid
+        let image = proxy
+            .get_region("proxy", "ms 3", &region, &Size::default())
+            .await
+            .unwrap();
+        assert_eq!(image.to_rgb8(), source.crop_imm(10, 20, 40, 30).to_rgb8());
+        assert_eq!(
+            paths.lock().unwrap().last().unwrap(),
+            "/iiif/ms%203/10,20,40,30/max/0/default.jpg"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sniffs_format() {
+        let (addr, _) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let proxy = loader(cache.path(), local_policy());
+
+        let uri = format!("http://{addr}/octet-stream");
+        let image = proxy.get_image("proxy", &identifier(&uri)).await.unwrap();
+        assert_eq!(image.width(), 4);
+        let entry = proxy.index.get(&uri).unwrap().unwrap();
+        assert_eq!(entry.format, ImageFormat::Png);
+
+        let uri = format!("http://{addr}/page.jpg");
+        let result = proxy.get_image("proxy", &identifier(&uri)).await;
+        assert!(
+            matches!(&result, Err(LoaderError::Upstream(msg)) if msg.contains("text/html")),
+            "{result:?}"
+        );
+        assert!(proxy.index.get(&uri).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_auth() {
+        let (addr, _) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let secrets = tempfile::tempdir().unwrap();
+        for (name, secret) in [("key", "k3y\n"), ("token", "s3cret\n")] {
+            std::fs::write(secrets.path().join(name), secret).unwrap();
+        }
+        let auth = || UpstreamAuth {
+            headers: HashMap::from([(
+                "X-Api-Key".into(),
+                SecretSource::File(secrets.path().join("key")),
+            )]),
+            bearer: Some(SecretSource::File(secrets.path().join("token"))),
+            ..Default::default()
+        };
+        let uri = identifier(&format!("http://{addr}/private.png"));
+
+        let result = loader(cache.path(), local_policy())
+            .get_image("proxy", &uri)
+            .await;
+        assert!(matches!(result, Err(LoaderError::Upstream(_))));
+
+        let proxy = ProxyLoader::new(ProxyConfig {
+            auth: auth(),
+            allow: local_policy(),
+            ..ProxyConfig::new(cache.path())
+        })
+        .unwrap();
+        // The failure without credentials is remembered until purged
+        let result = proxy.get_image("proxy", &uri).await;
+        assert!(matches!(result, Err(LoaderError::Upstream(_))));
+        assert_eq!(proxy.purge_failures(Some(&uri)).await.unwrap(), 1);
+        proxy.get_image("proxy", &uri).await.unwrap();
+        let debug = format!("{proxy:?}");
+        assert!(!debug.contains("k3y") && !debug.contains("s3cret"));
+
+        // Custom headers are not sent to other hosts
+        let uri = identifier(&format!(
+            "http://{addr}/redirect?to=http://localhost:{}/image.png",
+            addr.port()
+        ));
+        let result = ProxyLoader::new(ProxyConfig {
+            auth: auth(),
+            allow: UpstreamPolicy {
+                hosts: vec!["127.0.0.1".into(), "localhost".into()],
+                ..local_policy()
+            },
+            ..ProxyConfig::new(cache.path())
+        })
+        .unwrap()
+        .get_image("proxy", &uri)
+        .await;
+        assert!(
+            matches!(result, Err(LoaderError::BadRedirect(_))),
+            "{result:?}"
+        );
+
+        let result = ProxyLoader::new(ProxyConfig {
+            auth: UpstreamAuth {
+                bearer: Some(SecretSource::Env("IIIRS_TEST_UNSET".into())),
+                ..Default::default()
+            },
+            ..ProxyConfig::new(cache.path())
+        });
+        assert!(matches!(result, Err(LoaderError::Config(_))));
+    }
+
+    #[test]
+    fn test_conflicting_upstream_auth() {
+        let cache = tempfile::tempdir().unwrap();
+        let secret = || SecretSource::Env("IIIRS_TEST_UNSET".into());
+        let basic = || BasicAuth {
+            username: "user".into(),
+            password: secret(),
+        };
+        let conflicting = [
+            UpstreamAuth {
+                bearer: Some(secret()),
+                basic: Some(basic()),
+                ..Default::default()
+            },
+            UpstreamAuth {
+                headers: HashMap::from([("authorization".into(), secret())]),
+                bearer: Some(secret()),
+                ..Default::default()
+            },
+            UpstreamAuth {
+                headers: HashMap::from([("Authorization".into(), secret())]),
+                basic: Some(basic()),
+                ..Default::default()
+            },
+            UpstreamAuth {
+                headers: HashMap::from([
+                    ("X-Api-Key".into(), secret()),
+                    ("x-api-key".into(), secret()),
+                ]),
+                ..Default::default()
+            },
+        ];
+        for auth in conflicting {
+            let result = ProxyLoader::new(ProxyConfig {
+                auth,
+                ..ProxyConfig::new(cache.path())
+            });
+            // Rejected before the unset secrets are read
+            let Err(LoaderError::Config(message)) = result else {
+                panic!("{result:?}");
+            };
+            assert!(!message.contains("IIIRS_TEST_UNSET"), "{message}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_negative_caching() {
+        let (addr, upstream) = stub_upstream().await;
+        let cache = tempfile::tempdir().unwrap();
+        let proxy = ProxyLoader::new(ProxyConfig {
+            retry: RetryPolicy {
+                max_retries: 0,
+                ..Default::default()
+            },
+            allow: local_policy(),
+            ..ProxyConfig::new(cache.path())
+        })
+        .unwrap();
+        let hits = || upstream.hits.load(Ordering::SeqCst);
+        let gone = identifier(&format!("http://{addr}/gone"));
+        let error = identifier(&format!("http://{addr}/error"));
+
+        for _ in 0..3 {
+            let result = proxy.get_image("proxy", &gone).await;
+            assert!(matches!(result, Err(LoaderError::NotFound)));
+            let result = proxy.get_image("proxy", &error).await;
+            assert!(matches!(result, Err(LoaderError::Upstream(_))));
+        }
+        assert_eq!(hits(), 2);
+
+        // Remembered failures survive a restart, and can be purged
+        drop(proxy);
+        let proxy = loader(cache.path(), local_policy());
+        let result = proxy.get_image("proxy", &gone).await;
+        assert!(matches!(result, Err(LoaderError::NotFound)));
+        assert_eq!(hits(), 2);
+        assert_eq!(proxy.purge_failures(None).await.unwrap(), 2);
+        let result = proxy.get_image("proxy", &gone).await;
+        assert!(matches!(result, Err(LoaderError::NotFound)));
+        assert_eq!(hits(), 3);
+    }
+}
@@ -0,0 +1,579 @@
+use image::DynamicImage;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::{
+    GenericImageLoader, ImageLoader, LoaderError, LoaderFuture, PAGE_SEPARATOR,
+    Result, pages,
+};
+use crate::api::image::{Region, Size};
+
+/// Where identifiers are looked up, and how.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResolverConfig {
+    /// A CSV or TSV file of identifiers and the storage keys they map to, or
+    /// an SQLite database, told apart by their extension
+    pub table: PathBuf,
+    /// Whether the first row of a CSV or TSV file names its columns
+    #[serde(default)]
+    pub header: bool,
+    /// Looks up the storage key for the identifier bound to `?1` in an
+    /// SQLite database
+    #[serde(default = "default_query")]
+    pub query: String,
+    /// How often to check whether a CSV or TSV file has changed, and load
+    /// it again if it has
+    #[serde(default = "default_check_interval_secs")]
+    pub check_interval_secs: u64,
+}
+
+fn default_query() -> String {
+    "SELECT key FROM identifiers WHERE identifier = ?1".into()
+}
+
+fn default_check_interval_secs() -> u64 {
+    10
+}
+
+impl ResolverConfig {
+    pub fn new<T: Into<PathBuf>>(table: T) -> Self {
+        Self {
+            table: table.into(),
+            header: false,
+            query: default_query(),
+            check_interval_secs: default_check_interval_secs(),
+        }
+    }
+}
+
+/// Maps public identifiers, such as ARKs or catalogue numbers, to the keys
+/// images are stored under.
+#[derive(Debug)]
+pub struct IdentifierResolver {
+    source: Source,
+}
+
+#[derive(Debug)]
+enum Source {
+    Delimited {
+        path: PathBuf,
+        separator: char,
+        header: bool,
+        check_interval: Duration,
+        table: Mutex<Table>,
+    },
+    Sqlite {
+        conn: Mutex<Connection>,
+        query: String,
+    },
+}
+
+#[derive(Debug)]
+struct Table {
+    keys: Arc<HashMap<String, String>>,
+    /// Modification time of the file the table was read from
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+}
+
+impl IdentifierResolver {
+    pub fn new(config: ResolverConfig) -> Result<Self> {
+        let ResolverConfig {
+            table: path,
+            header,
+            query,
+            check_interval_secs,
+        } = config;
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        let separator = match ext.as_deref() {
+            Some("csv") => ',',
+            Some("tsv" | "tab") => '\t',
+            Some("sqlite" | "sqlite3" | "db") => {
+                let conn = Connection::open_with_flags(
+                    &path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY
+                        | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                )?;
+                // Catch mistakes in the query before the first lookup
+                conn.prepare_cached(&query).map_err(|e| {
+                    LoaderError::Config(format!("invalid query: {e}"))
+                })?;
+                let conn = Mutex::new(conn);
+                return Ok(Self {
+                    source: Source::Sqlite { conn, query },
+                });
+            }
+            _ => {
+                return Err(LoaderError::Config(format!(
+                    "{} is not a .csv, .tsv or SQLite file",
+                    path.display()
+                )));
+            }
+        };
+        let table = read_table(&path, separator, header)?;
+        Ok(Self {
+            source: Source::Delimited {
+                path,
+                separator,
+                header,
+                check_interval: Duration::from_secs(check_interval_secs),
+                table: Mutex::new(table),
+            },
+        })
+    }
+
+    /// Run `f` against the resolver on the blocking thread pool, since
+    /// lookups wait for SQLite or read the table file.
+    pub async fn run<T, F>(self: &Arc<Self>, f: F) -> Result<T>
+    where
+        F: FnOnce(&Self) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let resolver = Arc::clone(self);
+        tokio::task::spawn_blocking(move || f(&resolver))
+            .await
+            .expect("identifier lookup panicked")
+    }
+
+    /// The storage key for `identifier`. A page selector that is not part of
+    /// a known identifier is carried over to the key. This blocks, so call
+    /// it through [`IdentifierResolver::run`] from async code.
+    pub fn resolve(&self, identifier: &str) -> Result<String> {
+        if let Some(key) = self.lookup(identifier)? {
+            return Ok(key);
+        }
+        match pages::split_page(identifier) {
+            Ok((identifier, Some(page))) => match self.lookup(identifier)? {
+                Some(key) => Ok(format!("{key}{PAGE_SEPARATOR}{page}")),
+                None => Err(LoaderError::NotFound),
+            },
+            _ => Err(LoaderError::NotFound),
+        }
+    }
+
+    /// Read the table again. Lookups in SQLite always see its current
+    /// contents, so there is nothing to do for those. This blocks, like
+    /// [`IdentifierResolver::resolve`].
+    pub fn reload(&self) -> Result<()> {
+        if let Source::Delimited {
+            path,
+            separator,
+            header,
+            table,
+            ..
+        } = &self.source
+        {
+            let reloaded = read_table(path, *separator, *header)?;
+            *table.lock().unwrap() = reloaded;
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, identifier: &str) -> Result<Option<String>> {
+        match &self.source {
+            Source::Delimited { .. } => {
+                Ok(self.current_keys().get(identifier).cloned())
+            }
+            Source::Sqlite { conn, query } => {
+                let conn = conn.lock().unwrap();
+                let key = conn
+                    .prepare_cached(query)?
+                    .query_row([identifier], |row| row.get(0))
+                    .optional()?;
+                Ok(key)
+            }
+        }
+    }
+
+    /// The keys in the table, read again first if the file has changed.
+    /// A table that fails to read is reported, and the previous one kept, so
+    /// that a half-written edit doesn't take the prefix down.
+    fn current_keys(&self) -> Arc<HashMap<String, String>> {
+        let Source::Delimited {
+            path,
+            separator,
+            header,
+            check_interval,
+            table,
+        } = &self.source
+        else {
+            unreachable!("only delimited files are read into a table");
+        };
+        {
+            let mut table = table.lock().unwrap();
+            if table.checked_at.elapsed() < *check_interval {
+                return Arc::clone(&table.keys);
+            }
+            table.checked_at = Instant::now();
+            let modified = modified(path);
+            if modified == table.modified {
+                return Arc::clone(&table.keys);
+            }
+            // Other lookups carry on with the current table meanwhile
+            table.modified = modified;
+        }
+
+        let reloaded = read_table(path, *separator, *header);
+        let mut table = table.lock().unwrap();
+        match reloaded {
+            Ok(reloaded) => *table = reloaded,
+            Err(e) => {
+                eprintln!("{}: keeping the previous table: {e}", path.display())
+            }
+        }
+        Arc::clone(&table.keys)
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Read a table of identifiers and storage keys, one per row in the first
+/// two columns.
+fn read_table(path: &Path, separator: char, header: bool) -> Result<Table> {
+    let modified = modified(path);
+    let text = std::fs::read_to_string(path)?;
+    let invalid = |line: usize, msg: &str| {
+        LoaderError::Config(format!("{}:{line}: {msg}", path.display()))
+    };
+    let mut keys = HashMap::new();
+    for (i, line) in text.lines().enumerate().skip(usize::from(header)) {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = match separator {
+            ',' => {
+                split_csv(line).ok_or_else(|| invalid(i + 1, "bad quoting"))?
+            }
+            _ => line.split(separator).map(String::from).collect(),
+        };
+        let [identifier, key, ..] = fields.as_slice() else {
+            return Err(invalid(i + 1, "expected an identifier and a key"));
+        };
+        if identifier.is_empty() || key.is_empty() {
+            return Err(invalid(i + 1, "empty identifier or key"));
+        }
+        if keys.insert(identifier.clone(), key.clone()).is_some() {
+            return Err(invalid(i + 1, "identifier listed twice"));
+        }
+    }
+    Ok(Table {
+        keys: Arc::new(keys),
+        modified,
+        checked_at: Instant::now(),
+    })
+}
+
+/// The fields of a CSV line, which may be quoted, with quotes doubled
+/// inside them.
+fn split_csv(line: &str) -> Option<Vec<String>> {
+    let mut fields = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next()? {
+                    '"' if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' => break,
+                    c => field.push(c),
+                }
+            }
+            if !matches!(chars.peek(), None | Some(',')) {
+                return None;
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' {
+                    break;
+                }
+                field.push(c);
+                chars.next();
+            }
+        }
+        fields.push(field);
+        if chars.next().is_none() {
+            return Some(fields);
+        }
+    }
+}
+
+/// Serves a prefix from another loader, under identifiers that an
+/// [`IdentifierResolver`] maps to the keys the loader knows the images by.
+/// Identifiers it doesn't know of are not found.
+#[derive(Debug)]
+pub struct ResolvingLoader {
+    resolver: Arc<IdentifierResolver>,
+    loader: Box<ImageLoader>,
+}
+
+impl ResolvingLoader {
+    pub fn new(resolver: IdentifierResolver, loader: ImageLoader) -> Self {
+        Self {
+            resolver: Arc::new(resolver),
+            loader: Box::new(loader),
+        }
+    }
+
+    pub fn resolver(&self) -> &Arc<IdentifierResolver> {
+        &self.resolver
+    }
+
+    /// The loader the images are loaded from, by their storage keys.
+    pub fn loader(&self) -> &ImageLoader {
+        &self.loader
+    }
+}
+
+// The futures are boxed and named, as for chains, because the wrapped loader
+// may itself be one that resolves identifiers
+impl ResolvingLoader {
+    async fn key(&self, identifier: &str) -> Result<String> {
+        let identifier = identifier.to_owned();
+        self.resolver
+            .run(move |resolver| resolver.resolve(&identifier))
+            .await
+    }
+
+    fn image<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+    ) -> LoaderFuture<'a, DynamicImage> {
+        Box::pin(async move {
+            let key = self.key(identifier).await?;
+            self.loader.get_image(prefix, &key).await
+        })
+    }
+
+    fn dimensions<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+    ) -> LoaderFuture<'a, (u32, u32)> {
+        Box::pin(async move {
+            let key = self.key(identifier).await?;
+            self.loader.get_dimensions(prefix, &key).await
+        })
+    }
+
+    fn pages<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+    ) -> LoaderFuture<'a, Vec<(u32, u32)>> {
+        Box::pin(async move {
+            let key = self.key(identifier).await?;
+            self.loader.get_pages(prefix, &key).await
+        })
+    }
+
+    fn version<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+    ) -> LoaderFuture<'a, Option<String>> {
+        Box::pin(async move {
+            let key = self.key(identifier).await?;
+            self.loader.get_version(prefix, &key).await
+        })
+    }
+
+    fn region<'a>(
+        &'a self,
+        prefix: &'a str,
+        identifier: &'a str,
+        region: &'a Region,
+        size: &'a Size,
+    ) -> LoaderFuture<'a, DynamicImage> {
+        Box::pin(async move {
+            let key = self.key(identifier).await?;
+            self.loader.get_region(prefix, &key, region, size).await
+        })
+    }
+}
+
+impl GenericImageLoader for ResolvingLoader {
+    async fn get_image(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<DynamicImage> {
+        self.image(prefix, identifier).await
+    }
+
+    async fn get_dimensions(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<(u32, u32)> {
+        self.dimensions(prefix, identifier).await
+    }
+
+    async fn get_pages(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<Vec<(u32, u32)>> {
+        self.pages(prefix, identifier).await
+    }
+
+    async fn get_version(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<Option<String>> {
+        self.version(prefix, identifier).await
+    }
+
+    async fn get_region(
+        &self,
+        prefix: &str,
+        identifier: &str,
+        region: &Region,
+        size: &Size,
+    ) -> Result<DynamicImage> {
+        self.region(prefix, identifier, region, size).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_loader::LocalLoader;
+    use image::{GenericImageView, ImageFormat};
+    use std::fs::File;
+
+    /// Write `text` to `path`, marking it modified `secs` after the epoch so
+    /// that rewrites are told apart however quickly they follow each other.
+    fn write_table(path: &Path, text: &str, secs: u64) {
+        std::fs::write(path, text).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_split_csv() {
+        let split = |line| split_csv(line).unwrap();
+        assert_eq!(split("a,b"), ["a", "b"]);
+        assert_eq!(split("a,,b,"), ["a", "", "b", ""]);
+        assert_eq!(
+            split(r#""a,b","say ""hi""",c"#),
+            ["a,b", r#"say "hi""#, "c"]
+        );
+        assert_eq!(split_csv(r#""a"b,c"#), None);
+        assert_eq!(split_csv(r#""a,b"#), None);
+    }
+
+    #[test]
+    fn test_table_resolver() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identifiers.csv");
+        write_table(
+            &path,
+            "identifier,key\n\"ark:/12345/x1\",scans/x1.tif\nMS 42,ms/42\n",
+            1,
+        );
+        let mut config = ResolverConfig::new(&path);
+        config.header = true;
+        config.check_interval_secs = 0;
+        let resolver = IdentifierResolver::new(config).unwrap();
+
+        assert_eq!(resolver.resolve("ark:/12345/x1").unwrap(), "scans/x1.tif");
+        assert_eq!(resolver.resolve("MS 42;3").unwrap(), "ms/42;3");
+        for identifier in ["identifier", "ark:/12345/x2", "MS 42;x", "ms/42"] {
+            assert!(matches!(
+                resolver.resolve(identifier),
+                Err(LoaderError::NotFound)
+            ));
+        }
+
+        // Changes to the file are picked up, unless it no longer reads
+        write_table(&path, "identifier,key\nMS 43,ms/43\n", 2);
+        assert_eq!(resolver.resolve("MS 43").unwrap(), "ms/43");
+        assert!(resolver.resolve("MS 42").is_err());
+        write_table(&path, "identifier,key\nMS 44,ms/44\nMS 44,ms/45\n", 3);
+        assert_eq!(resolver.resolve("MS 43").unwrap(), "ms/43");
+        assert!(matches!(resolver.reload(), Err(LoaderError::Config(_))));
+    }
+
+    #[test]
+    fn test_sqlite_resolver() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identifiers.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE identifiers (identifier TEXT PRIMARY KEY, key TEXT);
+            INSERT INTO identifiers VALUES ('ark:/12345/x1', 'scans/x1');",
+        )
+        .unwrap();
+
+        let resolver =
+            IdentifierResolver::new(ResolverConfig::new(&path)).unwrap();
+        assert_eq!(resolver.resolve("ark:/12345/x1").unwrap(), "scans/x1");
+        assert!(matches!(
+            resolver.resolve("ark:/12345/x2"),
+            Err(LoaderError::NotFound)
+        ));
+        // Lookups see rows added since
+        conn.execute(
+            "INSERT INTO identifiers VALUES ('ark:/12345/x2', 'scans/x2')",
+            [],
+        )
+        .unwrap();
+        assert_eq!(resolver.resolve("ark:/12345/x2").unwrap(), "scans/x2");
+
+        let mut config = ResolverConfig::new(&path);
+        config.query = "SELECT nothing FROM nowhere".into();
+        assert!(matches!(
+            IdentifierResolver::new(config),
+            Err(LoaderError::Config(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resolving_loader() {
+        let dir = tempfile::tempdir().unwrap();
+        DynamicImage::new_rgb8(3, 2)
+            .save_with_format(dir.path().join("0001.png"), ImageFormat::Png)
+            .unwrap();
+        let path = dir.path().join("identifiers.tsv");
+        write_table(&path, "cat-1\t0001\ncat-2\t0002\n", 1);
+
+        let mut local = LocalLoader::new();
+        local.insert_dir_with_extensions("p", dir.path(), ["png"]);
+        let resolver =
+            IdentifierResolver::new(ResolverConfig::new(&path)).unwrap();
+        let loader = ResolvingLoader::new(resolver, ImageLoader::Local(local));
+
+        let image = loader.get_image("p", "cat-1").await.unwrap();
+        assert_eq!(image.dimensions(), (3, 2));
+        // Neither unknown identifiers nor storage keys are found
+        for identifier in ["cat-2", "cat-3", "0001"] {
+            assert!(matches!(
+                loader.get_image("p", identifier).await,
+                Err(LoaderError::NotFound)
+            ));
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::{LoaderError, Result};
+
+/// How often, and how patiently, to retry upstream requests that failed in
+/// a way that may be transient.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for each following one
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+/// When to stop sending requests to an upstream host that keeps failing.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failed requests after which the circuit opens, or 0 to
+    /// never open it
+    pub failure_threshold: u32,
+    /// How long requests to the host fail fast once the circuit is open,
+    /// before one is let through again to see if it has recovered. Others
+    /// keep failing fast until that one is done, or for as long again if
+    /// it never finishes.
+    pub open_secs: u64,
+}
+
+/// Tracks failures per upstream host and fails requests to hosts that are
+/// down without contacting them.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    hosts: Mutex<HashMap<String, HostHealth>>,
+}
+
+#[derive(Debug)]
+struct HostHealth {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    /// Whether a request was let through to see if the host has recovered,
+    /// and its outcome is not known yet
+    probing: bool,
+    last_failure: LoaderError,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_backoff_ms: 200,
+            max_backoff_ms: 5_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before retry number `retry`, counting from 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff_ms
+            .saturating_mul(1 << retry.min(32))
+            .min(self.max_backoff_ms);
+        Duration::from_millis(backoff)
+    }
+}
+
+/// Whether a request that failed with `error` is worth retrying.
+pub fn is_transient(error: &LoaderError) -> bool {
+    matches!(error, LoaderError::Upstream(_) | LoaderError::Timeout)
+}
+
+/// Whether a response with `status` may succeed if the request is retried.
+pub fn is_transient_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_secs: 30,
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            hosts: Mutex::default(),
+        }
+    }
+
+    /// Fail with the last error seen from `host` if its circuit is open.
+    /// Once it has been open for long enough, the first caller is let
+    /// through to probe the host while the others keep failing.
+    pub fn check(&self, host: &str, now: Instant) -> Result<()> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let Some(health) = hosts.get_mut(host) else {
+            return Ok(());
+        };
+        let Some(open_until) = health.open_until else {
+            return Ok(());
+        };
+        if now >= open_until {
+            // Should the probe never be recorded, another is let through
+            // once this one has had as long as the circuit was open
+            health.open_until =
+                Some(now + Duration::from_secs(self.config.open_secs));
+            health.probing = true;
+            return Ok(());
+        }
+        Err(match &health.last_failure {
+            LoaderError::Timeout => LoaderError::Timeout,
+            e => LoaderError::Upstream(format!(
+                "{host} is failing, not contacting it for now: {e}"
+            )),
+        })
+    }
+
+    /// Record the outcome of a request to `host`. Only transient errors
+    /// count as failures; other errors leave the host's health unchanged,
+    /// unless they answer a probe, which shows that the host is back.
+    pub fn record<T>(&self, host: &str, result: &Result<T>, now: Instant) {
+        if self.config.failure_threshold == 0 {
+            return;
+        }
+        let mut hosts = self.hosts.lock().unwrap();
+        match result {
+            Ok(_) => {
+                hosts.remove(host);
+            }
+            Err(e) if is_transient(e) => {
+                let health =
+                    hosts.entry(host.to_owned()).or_insert(HostHealth {
+                        consecutive_failures: 0,
+                        open_until: None,
+                        probing: false,
+                        last_failure: LoaderError::Timeout,
+                    });
+                health.consecutive_failures += 1;
+                health.last_failure = e.clone();
+                health.probing = false;
+                if health.consecutive_failures >= self.config.failure_threshold
+                {
+                    health.open_until =
+                        Some(now + Duration::from_secs(self.config.open_secs));
+                }
+            }
+            // The host answered the probe, even if not with the image
+            Err(_) if hosts.get(host).is_some_and(|health| health.probing) => {
+                hosts.remove(host);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1_000,
+        };
+        let backoffs: Vec<_> = (0..5)
+            .map(|retry| policy.backoff(retry).as_millis())
+            .collect();
+        assert_eq!(backoffs, [100, 200, 400, 800, 1_000]);
+        assert_eq!(policy.backoff(u32::MAX).as_millis(), 1_000);
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 2,
+            open_secs: 30,
+        });
+        let now = Instant::now();
+        let failed: Result<()> = Err(LoaderError::Timeout);
+
+        breaker.record("a", &failed, now);
+        assert!(breaker.check("a", now).is_ok());
+        breaker.record("a", &Err::<(), _>(LoaderError::NotFound), now);
+        breaker.record("a", &failed, now);
+        assert!(matches!(breaker.check("a", now), Err(LoaderError::Timeout)));
+        assert!(breaker.check("b", now).is_ok());
+
+        // Once the circuit has been open for long enough, a request is let
+        // through; another failure opens it again, a success closes it
+        let later = now + Duration::from_secs(30);
+        assert!(breaker.check("a", later).is_ok());
+        breaker.record("a", &failed, later);
+        assert!(breaker.check("a", later).is_err());
+        let even_later = later + Duration::from_secs(30);
+        breaker.record("a", &Ok(()), even_later);
+        breaker.record("a", &failed, even_later);
+        assert!(breaker.check("a", even_later).is_ok());
+    }
+
+    #[test]
+    fn test_circuit_breaker_half_open() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 1,
+            open_secs: 30,
+        });
+        let now = Instant::now();
+        let failed: Result<()> = Err(LoaderError::Timeout);
+        breaker.record("a", &failed, now);
+
+        // Of two callers arriving after the cool-down, only one probes
+        let later = now + Duration::from_secs(30);
+        let probes = std::thread::scope(|scope| {
+            let callers: Vec<_> = (0..2)
+                .map(|_| scope.spawn(|| breaker.check("a", later).is_ok()))
+                .collect();
+            callers
+                .into_iter()
+                .map(|caller| caller.join().unwrap())
+                .filter(|probed| *probed)
+                .count()
+        });
+        assert_eq!(probes, 1);
+
+        // A failed probe opens the circuit for another cool-down
+        breaker.record("a", &failed, later);
+        let during = later + Duration::from_secs(29);
+        assert!(breaker.check("a", during).is_err());
+
+        // A probe that is never recorded is followed by another
+        let probe = later + Duration::from_secs(30);
+        assert!(breaker.check("a", probe).is_ok());
+        assert!(breaker.check("a", probe).is_err());
+        let abandoned = probe + Duration::from_secs(30);
+        assert!(breaker.check("a", abandoned).is_ok());
+
+        // Any answer from the host closes the circuit
+        breaker.record("a", &Err::<(), _>(LoaderError::NotFound), abandoned);
+        assert!(breaker.check("a", abandoned).is_ok());
+        assert!(breaker.check("a", abandoned).is_ok());
+    }
+}
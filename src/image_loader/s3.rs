@@ -0,0 +1,708 @@
+use axum::http::header;
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageReader};
+use reqwest::{StatusCode, redirect};
+use serde::Deserialize;
+use std::{
+    io::Cursor,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use url::Url;
+
+use super::auth::SecretSource;
+use super::cache_index::{CacheIndex, IndexEntry, Validators};
+use super::content_cache::{
+    SNIFF_LEN, cached_img_path, cached_version, decode_cached, detect_format,
+    download, remove_interrupted_downloads, spawn_store,
+};
+use super::eviction::{self, EvictionPolicy};
+use super::retry::{RetryPolicy, is_transient, is_transient_status};
+use super::sigv4::{self, Credentials};
+use super::url_template::encode_identifier;
+use super::{GenericImageLoader, LoaderError, Result};
+use crate::DEFAULT_USER_AGENT;
+
+const CACHE_INDEX_FILE: &str = "index.sqlite3";
+/// How much of an object to fetch to read the dimensions from its header
+const HEADER_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    /// The S3-compatible service, such as
+    /// `https://s3.eu-west-1.amazonaws.com` or `http://localhost:9000`.
+    /// Buckets are addressed path-style, under the endpoint.
+    pub endpoint: Url,
+    pub bucket: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    /// Prepended to identifiers to make object keys. Defaults to the
+    /// prefix followed by a slash.
+    pub key_prefix: Option<String>,
+    pub access_key_id: SecretSource,
+    pub secret_access_key: SecretSource,
+    pub session_token: Option<SecretSource>,
+    pub cache_dir: PathBuf,
+    /// How long to serve cached objects before checking that they haven't
+    /// changed in the bucket
+    #[serde(default = "default_max_age_secs")]
+    pub max_age_secs: u64,
+    /// Evict least recently used objects once the cache grows past this
+    pub max_cache_bytes: Option<u64>,
+    /// Evict objects stored longer ago than this, however recently used
+    pub max_entry_age_secs: Option<u64>,
+    /// How often to check the cache against the limits above
+    #[serde(default = "default_sweep_interval_secs")]
+    pub sweep_interval_secs: u64,
+    /// Largest object to download
+    #[serde(default = "default_max_download_bytes")]
+    pub max_download_bytes: u64,
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    #[serde(default = "default_read_timeout_ms")]
+    pub read_timeout_ms: u64,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+fn default_region() -> String {
+    "us-east-1".into()
+}
+
+fn default_max_age_secs() -> u64 {
+    5 * 60
+}
+
+fn default_sweep_interval_secs() -> u64 {
+    5 * 60
+}
+
+fn default_max_download_bytes() -> u64 {
+    512 * 1024 * 1024
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_read_timeout_ms() -> u64 {
+    1000
+}
+
+impl S3Config {
+    /// Configuration for reading `bucket` at `endpoint` with the given
+    /// credentials, caching into `cache_dir`, with default settings.
+    pub fn new<T: Into<PathBuf>>(
+        endpoint: Url,
+        bucket: &str,
+        access_key_id: SecretSource,
+        secret_access_key: SecretSource,
+        cache_dir: T,
+    ) -> Self {
+        Self {
+            endpoint,
+            bucket: bucket.into(),
+            region: default_region(),
+            key_prefix: None,
+            access_key_id,
+            secret_access_key,
+            session_token: None,
+            cache_dir: cache_dir.into(),
+            max_age_secs: default_max_age_secs(),
+            max_cache_bytes: None,
+            max_entry_age_secs: None,
+            sweep_interval_secs: default_sweep_interval_secs(),
+            max_download_bytes: default_max_download_bytes(),
+            connect_timeout_ms: default_connect_timeout_ms(),
+            read_timeout_ms: default_read_timeout_ms(),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// Serves images stored as objects in an S3-compatible bucket, keeping a
+/// copy of them on disk.
+#[derive(Debug)]
+pub struct S3Loader {
+    bucket_url: String,
+    region: String,
+    key_prefix: Option<String>,
+    credentials: Credentials,
+    cache_dir: PathBuf,
+    index: Arc<CacheIndex>,
+    max_age: Duration,
+    eviction: EvictionPolicy,
+    sweep_interval: Duration,
+    max_download_bytes: u64,
+    retry: RetryPolicy,
+    client: reqwest::Client,
+}
+
+impl S3Loader {
+    /// Create a loader for `config.bucket`, caching into `config.cache_dir`.
+    /// The credentials are read once, here.
+    pub fn new(config: S3Config) -> Result<Self> {
+        let S3Config {
+            endpoint,
+            bucket,
+            region,
+            key_prefix,
+            access_key_id,
+            secret_access_key,
+            session_token,
+            cache_dir,
+            max_age_secs,
+            max_cache_bytes,
+            max_entry_age_secs,
+            sweep_interval_secs,
+            max_download_bytes,
+            connect_timeout_ms,
+            read_timeout_ms,
+            retry,
+        } = config;
+        if !matches!(endpoint.scheme(), "http" | "https")
+            || endpoint.query().is_some()
+        {
+            return Err(LoaderError::Config(format!(
+                "invalid S3 endpoint {endpoint}"
+            )));
+        }
+        // As S3 requires, which also keeps the name within its URL segment
+        if !(3..=63).contains(&bucket.len())
+            || !bucket.bytes().all(|byte| {
+                byte.is_ascii_lowercase()
+                    || byte.is_ascii_digit()
+                    || b".-".contains(&byte)
+            })
+            || bucket.starts_with('.')
+        {
+            return Err(LoaderError::Config(format!(
+                "invalid bucket name {bucket:?}"
+            )));
+        }
+        let credentials = Credentials {
+            access_key_id: access_key_id.read()?,
+            secret_access_key: secret_access_key.read()?,
+            session_token: session_token
+                .map(|token| token.read())
+                .transpose()?,
+        };
+
+        std::fs::create_dir_all(&cache_dir)?;
+        remove_interrupted_downloads(&cache_dir)?;
+        let index =
+            Arc::new(CacheIndex::open(cache_dir.join(CACHE_INDEX_FILE))?);
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(DEFAULT_USER_AGENT)
+            .connect_timeout(Duration::from_millis(connect_timeout_ms))
+            .read_timeout(Duration::from_millis(read_timeout_ms))
+            // A signed request is only valid for the URL it was signed for
+            .redirect(redirect::Policy::none())
+            .build()
+            .expect("S3Loader: failed to initialize http client");
+
+        Ok(Self {
+            bucket_url: format!(
+                "{}/{bucket}",
+                endpoint.as_str().trim_end_matches('/')
+            ),
+            region,
+            key_prefix,
+            credentials,
+            cache_dir,
+            index,
+            max_age: Duration::from_secs(max_age_secs),
+            eviction: EvictionPolicy {
+                max_size: max_cache_bytes,
+                max_age: max_entry_age_secs.map(Duration::from_secs),
+            },
+            sweep_interval: Duration::from_secs(sweep_interval_secs),
+            max_download_bytes,
+            retry,
+            client,
+        })
+    }
+
+    /// Periodically evict objects from the cache directory in the
+    /// background, if the cache has a size or age limit.
+    pub fn spawn_sweeper(&self) {
+        eviction::spawn_sweeper(
+            self.cache_dir.clone(),
+            Arc::clone(&self.index),
+            self.eviction,
+            self.sweep_interval,
+        );
+    }
+
+    /// The URL of the object `identifier` of `prefix` is stored as. Every
+    /// `/`-separated part of the key is percent-encoded, so that the URL
+    /// cannot leave the bucket.
+    fn object_url(&self, prefix: &str, identifier: &str) -> Result<Url> {
+        let key = match &self.key_prefix {
+            Some(key_prefix) => format!("{key_prefix}{identifier}"),
+            None => format!("{prefix}/{identifier}"),
+        };
+        let mut url = self.bucket_url.clone();
+        for segment in key.split('/') {
+            url.push('/');
+            url.push_str(&encode_identifier(segment)?);
+        }
+        url.parse().map_err(|_| LoaderError::InvalidIdentifier)
+    }
+
+    /// GET `url`, signed, retrying on transient failures. Responses that
+    /// say there is no such object are turned into errors.
+    async fn get(
+        &self,
+        url: &Url,
+        range: Option<&str>,
+        cached: Option<&Validators>,
+    ) -> Result<reqwest::Response> {
+        let mut retry = 0;
+        loop {
+            let signed_headers: Vec<_> =
+                range.map(|range| ("range", range)).into_iter().collect();
+            let mut request = self.client.get(url.clone());
+            for (name, value) in sigv4::sign(
+                &self.credentials,
+                &self.region,
+                "GET",
+                url,
+                &signed_headers,
+                SystemTime::now(),
+            )
+            .into_iter()
+            .chain(signed_headers.iter().map(|&(n, v)| (n, v.to_owned())))
+            {
+                request = request.header(name, value);
+            }
+            if let Some(etag) = cached.and_then(|v| v.etag.as_ref()) {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+
+            let error = match request.send().await {
+                Ok(response) => match response.status() {
+                    StatusCode::OK
+                    | StatusCode::PARTIAL_CONTENT
+                    | StatusCode::NOT_MODIFIED => return Ok(response),
+                    StatusCode::NOT_FOUND => return Err(LoaderError::NotFound),
+                    status => {
+                        let error = LoaderError::Upstream(format!(
+                            "{url} responded with {status}"
+                        ));
+                        if !is_transient_status(status) {
+                            return Err(error);
+                        }
+                        error
+                    }
+                },
+                Err(e) => e.into(),
+            };
+            if !is_transient(&error) || retry >= self.retry.max_retries {
+                return Err(error);
+            }
+            tokio::time::sleep(self.retry.backoff(retry)).await;
+            retry += 1;
+        }
+    }
+
+    /// Load the object at `url` from the cache, or from the bucket if it
+    /// is missing or has not been checked for a while.
+    async fn load(&self, url: &Url) -> Result<DynamicImage> {
+        let cached = self.cached(url).await?.filter(|entry| {
+            cached_img_path(&self.cache_dir, &entry.content_hash).is_file()
+        });
+        if let Some(entry) = &cached
+            && entry.expires_at > SystemTime::now()
+        {
+            return self.decode(entry).await;
+        }
+
+        let result = self.fetch(url, cached.as_ref()).await;
+        match (result, &cached) {
+            // Serve what we have rather than nothing while the bucket is
+            // unavailable
+            (
+                Err(LoaderError::Upstream(_) | LoaderError::Timeout),
+                Some(entry),
+            ) => self.decode(entry).await,
+            (result, _) => result,
+        }
+    }
+
+    /// Fetch the object at `url`, revalidating `cached` if given, and update
+    /// the cache with it.
+    async fn fetch(
+        &self,
+        url: &Url,
+        cached: Option<&IndexEntry>,
+    ) -> Result<DynamicImage> {
+        let validators = cached.map(|entry| &entry.validators);
+        let mut response = self.get(url, None, validators).await?;
+        let expires_at = SystemTime::now() + self.max_age;
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+
+        if let Some(entry) = cached
+            && response.status() == StatusCode::NOT_MODIFIED
+        {
+            let entry = IndexEntry {
+                expires_at,
+                ..entry.clone()
+            };
+            let (uri, stored) = (url.to_string(), entry.clone());
+            self.index
+                .run(move |index| index.insert(&uri, &stored))
+                .await?;
+            return self.decode(&entry).await;
+        }
+
+        let (download, format) =
+            download(&mut response, &self.cache_dir, self.max_download_bytes)
+                .await?;
+        let validators = Validators {
+            etag,
+            last_modified: None,
+        };
+        let key = spawn_store(
+            &self.cache_dir,
+            &self.index,
+            download,
+            url.as_str(),
+            format,
+            validators,
+            expires_at,
+        )
+        .await?;
+        decode_cached(&self.cache_dir, &self.index, &key, format).await
+    }
+
+    /// The index entry of the object at `url`, if it has been cached.
+    async fn cached(&self, url: &Url) -> Result<Option<IndexEntry>> {
+        let uri = url.to_string();
+        Ok(self.index.run(move |index| index.get(&uri)).await?)
+    }
+
+    async fn decode(&self, entry: &IndexEntry) -> Result<DynamicImage> {
+        decode_cached(
+            &self.cache_dir,
+            &self.index,
+            &entry.content_hash,
+            entry.format,
+        )
+        .await
+    }
+
+    /// Read the dimensions of the object at `url` from the start of it,
+    /// fetched with a ranged GET, for formats that keep them there. `None`
+    /// if they are not within the first [`HEADER_BYTES`].
+    async fn read_header_dimensions(
+        &self,
+        url: &Url,
+    ) -> Result<Option<(u32, u32)>> {
+        let range = format!("bytes=0-{}", HEADER_BYTES - 1);
+        let mut response = self.get(url, Some(&range), None).await?;
+        let mut head = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            head.extend_from_slice(&chunk);
+            // Servers that ignore the range send the whole object
+            if head.len() as u64 >= HEADER_BYTES {
+                break;
+            }
+        }
+        let format = detect_format(&head[..head.len().min(SNIFF_LEN)], None);
+        // The header of TIFF and OpenEXR files points to where their
+        // dimensions are, which may be anywhere
+        let Some(format) = format.filter(|format| {
+            format.reading_enabled()
+                && !matches!(format, ImageFormat::Tiff | ImageFormat::OpenExr)
+        }) else {
+            return Ok(None);
+        };
+        Ok(ImageReader::with_format(Cursor::new(head), format)
+            .into_dimensions()
+            .ok())
+    }
+}
+
+impl GenericImageLoader for S3Loader {
+    async fn get_image(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<DynamicImage> {
+        self.load(&self.object_url(prefix, identifier)?).await
+    }
+
+    async fn get_dimensions(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<(u32, u32)> {
+        let url = self.object_url(prefix, identifier)?;
+        let cached = self.cached(&url).await?;
+        let fresh = cached.is_some_and(|entry| {
+            entry.expires_at > SystemTime::now()
+                && cached_img_path(&self.cache_dir, &entry.content_hash)
+                    .is_file()
+        });
+        if !fresh
+            && let Some(dimensions) = self.read_header_dimensions(&url).await?
+        {
+            return Ok(dimensions);
+        }
+        Ok(self.load(&url).await?.dimensions())
+    }
+
+    /// The hash of the content cached for the object, while it is fresh.
+    async fn get_version(
+        &self,
+        prefix: &str,
+        identifier: &str,
+    ) -> Result<Option<String>> {
+        let url = self.object_url(prefix, identifier)?;
+        Ok(self.cached(&url).await?.and_then(|entry| {
+            cached_version(&self.cache_dir, &entry, SystemTime::now())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Router,
+        extract::State,
+        http::{HeaderMap, Uri},
+        response::{IntoResponse, Response},
+        routing::get,
+    };
+    use std::{collections::HashMap, net::SocketAddr, path::Path, sync::Mutex};
+
+    const ACCESS_KEY_ID: &str = "AKIDEXAMPLE";
+    const SECRET_ACCESS_KEY: &str = "s3cret";
+
+    #[derive(Default)]
+    struct Bucket {
+        /// Objects by the path they are served at
+        objects: Mutex<HashMap<String, Vec<u8>>>,
+        /// Path, Range header and status of each request
+        requests: Mutex<Vec<(String, Option<String>, StatusCode)>>,
+    }
+
+    /// A local stand-in for an S3-compatible service, which checks request
+    /// signatures and supports ranged and conditional GETs.
+    async fn stub_s3() -> (SocketAddr, Arc<Bucket>) {
+        async fn object(
+            State(bucket): State<Arc<Bucket>>,
+            uri: Uri,
+            headers: HeaderMap,
+        ) -> Response {
+            let response = respond(&bucket, &uri, &headers);
+            bucket.requests.lock().unwrap().push((
+                uri.path().to_owned(),
+                headers
+                    .get(header::RANGE)
+                    .map(|range| range.to_str().unwrap().to_owned()),
+                response.status(),
+            ));
+            response
+        }
+
+        fn respond(
+            bucket: &Bucket,
+            uri: &Uri,
+            headers: &HeaderMap,
+        ) -> Response {
+            let header = |name: &str| {
+                headers.get(name).and_then(|value| value.to_str().ok())
+            };
+            let Some((_, signed)) = header("authorization")
+                .and_then(|auth| auth.split_once("SignedHeaders="))
+            else {
+                return StatusCode::FORBIDDEN.into_response();
+            };
+            let signed_headers = signed.split(',').next().unwrap();
+            let signed: Vec<_> = signed_headers
+                .split(';')
+                .map(|name| {
+                    (name.to_owned(), header(name).unwrap_or("").to_owned())
+                })
+                .collect();
+            let credentials = Credentials {
+                access_key_id: ACCESS_KEY_ID.into(),
+                secret_access_key: SECRET_ACCESS_KEY.into(),
+                session_token: None,
+            };
+            let url = format!("http://{}{uri}", header("host").unwrap());
+            let expected = sigv4::authorization(
+                &credentials,
+                "us-east-1",
+                "GET",
+                &url.parse().unwrap(),
+                &signed,
+                header("x-amz-date").unwrap_or("00000000T000000Z"),
+            );
+            if header("authorization") != Some(&expected) {
+                return StatusCode::FORBIDDEN.into_response();
+            }
+
+            let objects = bucket.objects.lock().unwrap();
+            let Some(body) = objects.get(uri.path()) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let etag = format!("\"{}\"", body.len());
+            if header("if-none-match") == Some(&etag) {
+                return StatusCode::NOT_MODIFIED.into_response();
+            }
+            let range = header("range")
+                .and_then(|range| range.strip_prefix("bytes=0-"))
+                .map(|end| end.parse::<usize>().unwrap() + 1);
+            match range {
+                Some(end) => (
+                    StatusCode::PARTIAL_CONTENT,
+                    [(header::ETAG, etag)],
+                    body[..end.min(body.len())].to_vec(),
+                )
+                    .into_response(),
+                None => ([(header::ETAG, etag)], body.clone()).into_response(),
+            }
+        }
+
+        let bucket = Arc::new(Bucket::default());
+        let app = Router::new()
+            .route("/{*path}", get(object))
+            .with_state(Arc::clone(&bucket));
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (addr, bucket)
+    }
+
+    fn encode(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let mut data = Cursor::new(vec![]);
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut data, format)
+            .unwrap();
+        data.into_inner()
+    }
+
+    fn config(addr: SocketAddr, dir: &Path, secret: &str) -> S3Config {
+        let secret_file = dir.join("secret");
+        std::fs::write(&secret_file, format!("{secret}\n")).unwrap();
+        S3Config::new(
+            format!("http://{addr}").parse().unwrap(),
+            "masters",
+            SecretSource::File(dir.join("key")),
+            SecretSource::File(secret_file),
+            dir.join("cache"),
+        )
+    }
+
+    fn s3_loader(addr: SocketAddr, dir: &Path) -> S3Loader {
+        std::fs::write(dir.join("key"), ACCESS_KEY_ID).unwrap();
+        S3Loader::new(config(addr, dir, SECRET_ACCESS_KEY)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_s3_loader() {
+        let (addr, bucket) = stub_s3().await;
+        bucket.objects.lock().unwrap().insert(
+            "/masters/books/ms%201/f.png".into(),
+            encode(40, 30, ImageFormat::Png),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let s3 = s3_loader(addr, dir.path());
+        let requests = || bucket.requests.lock().unwrap().clone();
+
+        // The dimensions are read from the start of the object
+        let dimensions = s3.get_dimensions("books", "ms 1/f.png").await;
+        assert_eq!(dimensions.unwrap(), (40, 30));
+        assert_eq!(
+            requests(),
+            [(
+                "/masters/books/ms%201/f.png".into(),
+                Some("bytes=0-65535".into()),
+                StatusCode::PARTIAL_CONTENT
+            )]
+        );
+
+        // The object is downloaded once, then served from the cache
+        let image = s3.get_image("books", "ms 1/f.png").await.unwrap();
+        assert_eq!(image.dimensions(), (40, 30));
+        s3.get_image("books", "ms 1/f.png").await.unwrap();
+        s3.get_dimensions("books", "ms 1/f.png").await.unwrap();
+        assert_eq!(requests().len(), 2);
+        assert_eq!(requests()[1].1, None);
+
+        let result = s3.get_image("books", "missing.png").await;
+        assert!(matches!(result, Err(LoaderError::NotFound)), "{result:?}");
+        for identifier in ["../other/f.png", "a//f.png", "."] {
+            let result = s3.get_image("books", identifier).await;
+            assert!(
+                matches!(result, Err(LoaderError::InvalidIdentifier)),
+                "{identifier}: {result:?}"
+            );
+        }
+        assert_eq!(requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_s3_revalidation() {
+        let (addr, bucket) = stub_s3().await;
+        bucket.objects.lock().unwrap().insert(
+            "/masters/scans/f.tif".into(),
+            encode(20, 10, ImageFormat::Tiff),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("key"), ACCESS_KEY_ID).unwrap();
+        let s3 = S3Loader::new(S3Config {
+            key_prefix: Some("scans/".into()),
+            max_age_secs: 0,
+            ..config(addr, dir.path(), SECRET_ACCESS_KEY)
+        })
+        .unwrap();
+        let statuses = || -> Vec<_> {
+            let requests = bucket.requests.lock().unwrap();
+            requests.iter().map(|(_, _, status)| *status).collect()
+        };
+
+        // TIFF dimensions may be anywhere, so the whole file is fetched
+        let dimensions = s3.get_dimensions("books", "f.tif").await.unwrap();
+        assert_eq!(dimensions, (20, 10));
+        assert_eq!(statuses(), [StatusCode::PARTIAL_CONTENT, StatusCode::OK]);
+
+        // and checked for changes before it is served again
+        let image = s3.get_image("books", "f.tif").await.unwrap();
+        assert_eq!(image.dimensions(), (20, 10));
+        assert_eq!(statuses()[2], StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_s3_credentials() {
+        let (addr, bucket) = stub_s3().await;
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("key"), ACCESS_KEY_ID).unwrap();
+        let s3 =
+            S3Loader::new(config(addr, dir.path(), "wrong secret")).unwrap();
+
+        let result = s3.get_image("books", "f.png").await;
+        assert!(
+            matches!(result, Err(LoaderError::Upstream(_))),
+            "{result:?}"
+        );
+        // Refused requests are not retried
+        assert_eq!(bucket.requests.lock().unwrap().len(), 1);
+
+        let result = S3Loader::new(S3Config {
+            bucket: "a/b".into(),
+            ..config(addr, dir.path(), SECRET_ACCESS_KEY)
+        });
+        assert!(matches!(result, Err(LoaderError::Config(_))));
+    }
+}
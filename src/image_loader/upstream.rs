@@ -0,0 +1,260 @@
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::Deserialize;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use url::{Host, Url};
+
+/// Which upstream URIs a proxy prefix may fetch from.
+///
+/// A URI is allowed if its scheme is listed in `schemes` and it matches
+/// either an entry in `hosts` or one of `url_prefixes`. If both lists are
+/// empty, any host is allowed. Unless `private_addresses` is set, hosts that
+/// are or resolve to loopback, link-local, private or otherwise non-public
+/// addresses are always refused.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamPolicy {
+    pub schemes: Vec<String>,
+    /// Host names, or `*.example.org` to allow any subdomain of
+    /// `example.org`
+    pub hosts: Vec<String>,
+    /// URIs with the same scheme, host and port, and whose path starts with
+    /// the path of one of these
+    pub url_prefixes: Vec<Url>,
+    pub private_addresses: bool,
+}
+
+/// An upstream URI refused by an [`UpstreamPolicy`].
+#[derive(Debug)]
+pub struct UpstreamBlocked(String);
+
+/// A redirect from an upstream that is not followed, however often the
+/// request is retried.
+#[derive(Debug)]
+pub struct RefusedRedirect(pub &'static str);
+
+/// DNS resolver that refuses names resolving to non-public addresses, so
+/// that the check also applies to redirects and cannot be bypassed with a
+/// host name pointing at an internal address.
+#[derive(Debug)]
+pub struct GuardedResolver {
+    pub private_addresses: bool,
+}
+
+impl Default for UpstreamPolicy {
+    fn default() -> Self {
+        Self {
+            schemes: vec!["http".into(), "https".into()],
+            hosts: vec![],
+            url_prefixes: vec![],
+            private_addresses: false,
+        }
+    }
+}
+
+impl UpstreamPolicy {
+    pub fn check(&self, url: &Url) -> Result<(), UpstreamBlocked> {
+        if !self
+            .schemes
+            .iter()
+            .any(|scheme| scheme.eq_ignore_ascii_case(url.scheme()))
+        {
+            return Err(UpstreamBlocked(format!(
+                "scheme {} is not allowed",
+                url.scheme()
+            )));
+        }
+
+        let host = url
+            .host()
+            .ok_or_else(|| UpstreamBlocked("URI has no host".into()))?;
+        let ip = match host {
+            Host::Ipv4(ip) => Some(IpAddr::V4(ip)),
+            Host::Ipv6(ip) => Some(IpAddr::V6(ip)),
+            Host::Domain(_) => None,
+        };
+        if let Some(ip) = ip
+            && !self.private_addresses
+            && !is_public_ip(ip)
+        {
+            return Err(UpstreamBlocked(format!(
+                "{ip} is not a public address"
+            )));
+        }
+
+        if self.hosts.is_empty() && self.url_prefixes.is_empty() {
+            return Ok(());
+        }
+        let host = host.to_string();
+        let host_allowed = self
+            .hosts
+            .iter()
+            .any(|pattern| host_matches(pattern, &host));
+        let prefix_allowed = self.url_prefixes.iter().any(|prefix| {
+            prefix.scheme() == url.scheme()
+                && prefix.host() == url.host()
+                && prefix.port_or_known_default() == url.port_or_known_default()
+                && url.path().starts_with(prefix.path())
+        });
+        if host_allowed || prefix_allowed {
+            Ok(())
+        } else {
+            Err(UpstreamBlocked(format!(
+                "{host} is not an allowed upstream"
+            )))
+        }
+    }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(&domain.to_ascii_lowercase())
+            .is_some_and(|sub| sub.ends_with('.')),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// Whether `ip` is a globally routable unicast address.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ipv4(ip);
+            }
+            let [first, second, ..] = ip.segments();
+            // NAT64 (64:ff9b::/96) embeds an IPv4 address in the last bits
+            if first == 0x64 && second == 0xff9b {
+                let [.., a, b, c, d] = ip.octets();
+                return is_public_ipv4(Ipv4Addr::new(a, b, c, d));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                // Deprecated site-local addresses, fec0::/10
+                || first & 0xffc0 == 0xfec0
+                // Documentation, 2001:db8::/32
+                || (first == 0x2001 && second == 0xdb8)
+                // IPv4-compatible addresses, ::/96
+                || ip.segments()[..6] == Ipv6Addr::UNSPECIFIED.segments()[..6])
+        }
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network", 0.0.0.0/8
+        || a == 0
+        // Shared address space for carrier-grade NAT, 100.64.0.0/10
+        || (a == 100 && b & 0xc0 == 64)
+        // IETF protocol assignments, 192.0.0.0/24
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking, 198.18.0.0/15
+        || (a == 198 && b & 0xfe == 18)
+        // Reserved, 240.0.0.0/4
+        || a >= 240)
+}
+
+impl fmt::Display for UpstreamBlocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for UpstreamBlocked {}
+
+impl fmt::Display for RefusedRedirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for RefusedRedirect {}
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let private_addresses = self.private_addresses;
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((host, 0)).await?.collect();
+            if !private_addresses
+                && let Some(addr) =
+                    addrs.iter().find(|addr| !is_public_ip(addr.ip()))
+            {
+                return Err(Box::new(UpstreamBlocked(format!(
+                    "{host} resolves to non-public address {}",
+                    addr.ip()
+                ))) as _);
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_public_ip() {
+        for ip in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_policy_check() {
+        let policy = UpstreamPolicy {
+            schemes: vec!["https".into()],
+            hosts: vec!["images.example.org".into(), "*.example.net".into()],
+            url_prefixes: vec![
+                "https://cdn.example.com/iiif/".parse().unwrap(),
+            ],
+            private_addresses: false,
+        };
+        let check = |url: &str| policy.check(&url.parse().unwrap()).is_ok();
+
+        assert!(check("https://images.example.org/a.jpg"));
+        assert!(check("https://a.b.example.net/a.jpg"));
+        assert!(check("https://cdn.example.com/iiif/a.jpg"));
+        assert!(!check("http://images.example.org/a.jpg"));
+        assert!(!check("https://example.net/a.jpg"));
+        assert!(!check("https://evilexample.net/a.jpg"));
+        assert!(!check("https://cdn.example.com/private/a.jpg"));
+        assert!(!check("https://cdn.example.com:8443/iiif/a.jpg"));
+        assert!(!check("https://cdn.example.com.evil.org/iiif/a.jpg"));
+        assert!(!check("https://cdn.example.com@evil.org/iiif/a.jpg"));
+        assert!(!check("https://127.0.0.1/a.jpg"));
+    }
+}
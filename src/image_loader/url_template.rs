@@ -0,0 +1,105 @@
+use serde::Deserialize;
+use std::fmt::{self, Write};
+use url::Url;
+
+use super::{LoaderError, Result};
+
+const PLACEHOLDER: &str = "{id}";
+
+/// An upstream URL with `{id}` placeholders that identifiers are substituted
+/// into, such as `https://images.example.org/masters/{id}.jpg`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct UrlTemplate(String);
+
+/// A template that lacks a placeholder or doesn't expand to a valid URL.
+#[derive(Debug)]
+pub struct InvalidTemplate(String);
+
+impl UrlTemplate {
+    /// The upstream URL for `identifier`, which is percent-encoded so that it
+    /// stays within the place of the placeholder: it cannot add path
+    /// segments, a query or a fragment, or change the host.
+    pub fn expand(&self, identifier: &str) -> Result<Url> {
+        self.0
+            .replace(PLACEHOLDER, &encode_identifier(identifier)?)
+            .parse()
+            .map_err(|_| LoaderError::InvalidIdentifier)
+    }
+}
+
+/// Percent-encode `identifier` as a single URL path segment.
+pub fn encode_identifier(identifier: &str) -> Result<String> {
+    // Percent-encoded dot segments are still dot segments
+    if matches!(identifier, "" | "." | "..") {
+        return Err(LoaderError::InvalidIdentifier);
+    }
+    let mut encoded = String::with_capacity(identifier.len());
+    for byte in identifier.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            write!(encoded, "%{byte:02X}").unwrap();
+        }
+    }
+    Ok(encoded)
+}
+
+impl TryFrom<String> for UrlTemplate {
+    type Error = InvalidTemplate;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        if !template.contains(PLACEHOLDER) {
+            return Err(InvalidTemplate(format!(
+                "{template} has no {PLACEHOLDER} placeholder"
+            )));
+        }
+        if let Err(e) = template.replace(PLACEHOLDER, "id").parse::<Url>() {
+            return Err(InvalidTemplate(format!("{template}: {e}")));
+        }
+        Ok(Self(template))
+    }
+}
+
+impl fmt::Display for InvalidTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid URL template {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidTemplate {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let template = UrlTemplate::try_from(
+            "https://images.example.org/masters/{id}.jpg?v=1".to_owned(),
+        )
+        .unwrap();
+        let expand = |id| template.expand(id).map(String::from);
+
+        assert_eq!(
+            expand("ms-12_f.3r~").unwrap(),
+            "https://images.example.org/masters/ms-12_f.3r~.jpg?v=1"
+        );
+        assert_eq!(
+            expand("a/../b c?d#e").unwrap(),
+            "https://images.example.org/masters/a%2F..%2Fb%20c%3Fd%23e.jpg?v=1"
+        );
+        assert_eq!(
+            expand("@evil.org").unwrap(),
+            "https://images.example.org/masters/%40evil.org.jpg?v=1"
+        );
+        for id in ["", ".", ".."] {
+            assert!(matches!(expand(id), Err(LoaderError::InvalidIdentifier)));
+        }
+
+        assert!(
+            UrlTemplate::try_from("https://a.org/x.jpg".to_owned()).is_err()
+        );
+        assert!(UrlTemplate::try_from("{id}.jpg".to_owned()).is_err());
+    }
+}
@@ -0,0 +1,365 @@
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc;
+
+use super::{DerivativeCache, ImageCache, ImageLoader, LocalDir};
+
+/// How many times each file has been seen to change while it was watched.
+/// Changes that leave a file's size and modification time as they were,
+/// such as an in-place copy that keeps the source's times, still change
+/// the versions of its images this way.
+#[derive(Debug, Default)]
+pub struct ChangeCounts {
+    counts: Mutex<HashMap<PathBuf, u64>>,
+}
+
+impl ChangeCounts {
+    pub fn get(&self, path: &Path) -> u64 {
+        self.counts.lock().unwrap().get(path).copied().unwrap_or(0)
+    }
+
+    fn bump(&self, path: &Path) {
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .entry(path.to_owned())
+            .or_default() += 1;
+    }
+}
+
+/// Keeps the caches in line with the images in the directories of
+/// [`LocalLoader`](super::LocalLoader)s for as long as it is kept.
+#[derive(Debug)]
+pub struct SourceWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+/// The directories of a local loader, and the counts its versions include.
+#[derive(Debug)]
+struct Watched {
+    dirs: HashMap<String, LocalDir>,
+    changes: Arc<ChangeCounts>,
+    /// Whether images are cached under the identifiers the loader knows
+    /// them by, rather than under others resolved to those
+    evict: bool,
+}
+
+impl SourceWatcher {
+    /// Watch the directories of every local loader that `loaders` read
+    /// from, including chain tiers and loaders behind resolved identifiers.
+    /// Whenever a file in them changes or is removed, count the change, so
+    /// that its images get a new version, and evict the images from the
+    /// caches. Images served under resolved identifiers are left to the
+    /// version check, as the identifiers they are cached under are not
+    /// known here.
+    pub fn new<'a>(
+        loaders: impl IntoIterator<Item = &'a ImageLoader>,
+        image_cache: Arc<ImageCache>,
+        derivative_cache: Option<Arc<DerivativeCache>>,
+    ) -> notify::Result<Self> {
+        let mut watched = vec![];
+        for loader in loaders {
+            collect(loader, true, &mut watched);
+        }
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(
+            move |event: notify::Result<Event>| {
+                // Nothing is listening any more once the server is done
+                let _ = sender.send(event);
+            },
+        )?;
+        let mut paths: Vec<&Path> = watched
+            .iter()
+            .flat_map(|watched| watched.dirs.values())
+            .map(|dir| dir.path.as_path())
+            .collect();
+        paths.sort();
+        paths.dedup();
+        for path in paths {
+            watcher.watch(path, RecursiveMode::Recursive)?;
+        }
+
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        eprintln!("watching image directories: {e}");
+                        continue;
+                    }
+                };
+                if matches!(event.kind, EventKind::Access(_)) {
+                    continue;
+                }
+                for path in &event.paths {
+                    // Requests from now on see a new version before the old
+                    // one is evicted
+                    let mut evicted = vec![];
+                    for watched in &watched {
+                        let identifiers = identifiers(&watched.dirs, path);
+                        if identifiers.is_empty() {
+                            continue;
+                        }
+                        watched.changes.bump(path);
+                        if watched.evict {
+                            evicted.extend(identifiers);
+                        }
+                    }
+                    for (prefix, identifier) in evicted {
+                        evict(
+                            &image_cache,
+                            derivative_cache.as_ref(),
+                            prefix,
+                            identifier,
+                        )
+                        .await;
+                    }
+                }
+            }
+        });
+        Ok(Self { _watcher: watcher })
+    }
+}
+
+/// Add the local loaders `loader` reads from to `watched`, once each.
+fn collect(loader: &ImageLoader, evict: bool, watched: &mut Vec<Watched>) {
+    match loader {
+        // All local prefixes share a loader
+        ImageLoader::Local(local)
+            if !watched.iter().any(|watched| {
+                Arc::ptr_eq(&watched.changes, &local.changes)
+            }) =>
+        {
+            watched.push(Watched {
+                dirs: local.image_dirs.clone(),
+                changes: Arc::clone(&local.changes),
+                evict,
+            });
+        }
+        ImageLoader::Chain(chain) => {
+            for tier in chain.tiers() {
+                collect(tier, evict, watched);
+            }
+        }
+        ImageLoader::Resolved(resolved) => {
+            collect(resolved.loader(), false, watched);
+        }
+        _ => {}
+    }
+}
+
+/// Evict the image `identifier` of `prefix` from the caches.
+async fn evict(
+    image_cache: &ImageCache,
+    derivative_cache: Option<&Arc<DerivativeCache>>,
+    prefix: &str,
+    identifier: String,
+) {
+    image_cache.remove(prefix, &identifier);
+    let Some(derivative_cache) = derivative_cache else {
+        return;
+    };
+    let derivative_cache = Arc::clone(derivative_cache);
+    let prefix = prefix.to_owned();
+    let removed = tokio::task::spawn_blocking(move || {
+        derivative_cache
+            .remove(&prefix, &identifier)
+            .map_err(|e| format!("{prefix}/{identifier}: evicting: {e}"))
+    })
+    .await
+    .expect("derivative cache eviction panicked");
+    if let Err(e) = removed {
+        eprintln!("{e}");
+    }
+}
+
+/// The prefixes and identifiers the file at `path` is served under: with
+/// its extension and, as identifiers are resolved by trying each extension
+/// in turn, without it.
+fn identifiers<'a>(
+    dirs: &'a HashMap<String, LocalDir>,
+    path: &Path,
+) -> Vec<(&'a str, String)> {
+    let mut identifiers = vec![];
+    for (prefix, dir) in dirs {
+        let Some(relative) =
+            path.strip_prefix(&dir.path).ok().and_then(Path::to_str)
+        else {
+            continue;
+        };
+        let Some((stem, ext)) = relative.rsplit_once('.') else {
+            continue;
+        };
+        if dir.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)) {
+            identifiers.push((prefix.as_str(), relative.to_owned()));
+            identifiers.push((prefix.as_str(), stem.to_owned()));
+        }
+    }
+    identifiers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_loader::{
+        ChainLoader, DerivativeCacheConfig, GenericImageLoader,
+        IdentifierResolver, LocalLoader, ResolverConfig, ResolvingLoader,
+    };
+    use image::{DynamicImage, ImageFormat};
+    use std::time::Duration;
+
+    #[test]
+    fn test_identifiers() {
+        let mut local = LocalLoader::new();
+        local.insert_dir_with_extensions("a", "/srv/a", ["tif", "png"]);
+        local.insert_dir_with_extensions("b", "/srv/a/b", ["jpg"]);
+        let identifiers = |path: &str| {
+            let mut identifiers = identifiers(&local.image_dirs, path.as_ref());
+            identifiers.sort();
+            identifiers
+        };
+
+        assert_eq!(
+            identifiers("/srv/a/books/1.TIF"),
+            [("a", "books/1".into()), ("a", "books/1.TIF".into())]
+        );
+        assert_eq!(
+            identifiers("/srv/a/b/c.jpg"),
+            [("b", "c".into()), ("b", "c.jpg".into())]
+        );
+        assert!(identifiers("/srv/a/.tmp1234").is_empty());
+        assert!(identifiers("/srv/a/notes.txt").is_empty());
+        assert!(identifiers("/srv/c/1.tif").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_watcher() {
+        let (images, cache_dir) =
+            (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let path = images.path().join("a.png");
+        DynamicImage::new_rgb8(3, 2)
+            .save_with_format(&path, ImageFormat::Png)
+            .unwrap();
+        let mut local = LocalLoader::new();
+        local.insert_dir_with_extensions("p", images.path(), ["png"]);
+        let loader = ImageLoader::Local(local);
+        let image_cache = Arc::new(ImageCache::new(1 << 20));
+        let derivative_cache = Arc::new(
+            DerivativeCache::new(DerivativeCacheConfig::new(cache_dir.path()))
+                .unwrap(),
+        );
+        let _watcher = SourceWatcher::new(
+            [&loader],
+            Arc::clone(&image_cache),
+            Some(Arc::clone(&derivative_cache)),
+        )
+        .unwrap();
+
+        let (full, max) = ("full".parse().unwrap(), "max".parse().unwrap());
+        image_cache
+            .get_region(&loader, "p", "a", &full, &max)
+            .await
+            .unwrap();
+        let version = loader.get_version("p", "a").await.unwrap().unwrap();
+        let request = "a/full/max/0/default.png";
+        derivative_cache
+            .insert("p", "a", request, &version, ImageFormat::Png, b"png")
+            .unwrap();
+        assert_eq!(image_cache.stats().entries, 1);
+
+        // Rewriting the file in place evicts what was made from it
+        std::fs::write(&path, png(3, 2)).unwrap();
+        for _ in 0..100 {
+            if image_cache.stats().entries == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(image_cache.stats().entries, 0);
+        let new_version = loader.get_version("p", "a").await.unwrap().unwrap();
+        assert_ne!(new_version, version);
+        // The derivative is evicted after the image
+        let mut cached = None;
+        for _ in 0..100 {
+            cached =
+                derivative_cache.get("p", request, &version).await.unwrap();
+            if cached.is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(cached, None);
+    }
+
+    #[tokio::test]
+    async fn test_watcher_nested() {
+        let images = tempfile::tempdir().unwrap();
+        let path = images.path().join("0001.png");
+        std::fs::write(&path, png(3, 2)).unwrap();
+        let table = images.path().join("identifiers.tsv");
+        std::fs::write(&table, "cat-1\t0001\n").unwrap();
+        let local = |prefix| {
+            let mut local = LocalLoader::new();
+            local.insert_dir_with_extensions(prefix, images.path(), ["png"]);
+            ImageLoader::Local(local)
+        };
+        let chain = ImageLoader::Chain(
+            ChainLoader::new(vec![local("c")], false).unwrap(),
+        );
+        let resolved = ImageLoader::Resolved(ResolvingLoader::new(
+            IdentifierResolver::new(ResolverConfig::new(&table)).unwrap(),
+            local("r"),
+        ));
+        let image_cache = Arc::new(ImageCache::new(1 << 20));
+        let _watcher = SourceWatcher::new(
+            [&chain, &resolved],
+            Arc::clone(&image_cache),
+            None,
+        )
+        .unwrap();
+
+        let (full, max) = ("full".parse().unwrap(), "max".parse().unwrap());
+        for (loader, prefix, identifier) in
+            [(&chain, "c", "0001"), (&resolved, "r", "cat-1")]
+        {
+            image_cache
+                .get_region(loader, prefix, identifier, &full, &max)
+                .await
+                .unwrap();
+        }
+        let resolved_version = || resolved.get_version("r", "cat-1");
+        let version = resolved_version().await.unwrap().unwrap();
+        assert_eq!(image_cache.stats().entries, 2);
+
+        // Images from chain tiers are evicted, and those behind resolved
+        // identifiers get a new version, so the cached one is not served
+        std::fs::write(&path, png(3, 2)).unwrap();
+        for _ in 0..100 {
+            if image_cache.stats().entries == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(image_cache.stats().entries, 1);
+        assert_ne!(resolved_version().await.unwrap().unwrap(), version);
+        let misses = image_cache.stats().misses;
+        image_cache
+            .get_region(&resolved, "r", "cat-1", &full, &max)
+            .await
+            .unwrap();
+        assert_eq!(image_cache.stats().misses, misses + 1);
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = std::io::Cursor::new(vec![]);
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+}
@@ -10,24 +10,76 @@ fn scale_by_pct(int: u32, pct: f32) -> u32 {
     (f64::from(int) * f64::from(pct) / 100.0).round() as u32
 }
 
-pub fn crop_image(mut image: DynamicImage, region: &Region) -> DynamicImage {
+/// The pixel rectangle `(x, y, w, h)` that `region` selects from an image of
+/// `width` by `height` pixels, clipped to the image.
+pub fn region_rect(
+    width: u32,
+    height: u32,
+    region: &Region,
+) -> (u32, u32, u32, u32) {
     let (x, y, w, h) = match *region {
-        Region::Full => return image,
+        Region::Full => (0, 0, width, height),
         Region::Square => {
-            let sq_width = min(image.width(), image.height());
-            let y = sq_width - image.height() / 2;
-            let x = sq_width - image.width() / 2;
-            (x, y, sq_width, sq_width)
+            let side = min(width, height);
+            ((width - side) / 2, (height - side) / 2, side, side)
         }
         Region::Absolute { x, y, w, h } => (x, y, w.into(), h.into()),
-        Region::Percent { x, y, w, h } => {
-            let x_pix = scale_by_pct(image.width(), x);
-            let y_pix = scale_by_pct(image.height(), y);
-            let w_pix = scale_by_pct(image.width(), w);
-            let h_pix = scale_by_pct(image.height(), h);
-            (x_pix, y_pix, w_pix, h_pix)
+        Region::Percent { x, y, w, h } => (
+            scale_by_pct(width, x),
+            scale_by_pct(height, y),
+            scale_by_pct(width, w),
+            scale_by_pct(height, h),
+        ),
+    };
+    let x = min(x, width);
+    let y = min(y, height);
+    (x, y, min(w, width - x), min(h, height - y))
+}
+
+/// The dimensions an image region of `width` by `height` pixels is scaled
+/// to for `size`, or `None` if the region is empty, as it is when it lies
+/// outside the image, or if that would take upscaling that wasn't asked for.
+pub fn output_size(width: u32, height: u32, size: &Size) -> Option<(u32, u32)> {
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let scale = |int: u32, num: u32, den: u32| {
+        (f64::from(int) * f64::from(num) / f64::from(den.max(1))).round() as u32
+    };
+    let (w, h) = match size.kind {
+        // TODO: support upscaling to maxWidth, maxHeight, maxArea, see
+        // https://iiif.io/api/image/3.0/#42-size
+        SizeKind::Max => (width, height),
+        SizeKind::Width(w) => (w.into(), scale(height, w.into(), width)),
+        SizeKind::Height(h) => (scale(width, h.into(), height), h.into()),
+        SizeKind::Percent(pct) => {
+            (scale_by_pct(width, pct), scale_by_pct(height, pct))
         }
+        SizeKind::WidthHeight { w, h } if size.maintain_ratio => {
+            let (w, h) = (u32::from(w), u32::from(h));
+            // Fit within w by h
+            if u64::from(w) * u64::from(height)
+                <= u64::from(h) * u64::from(width)
+            {
+                (w, scale(height, w, width))
+            } else {
+                (scale(width, h, height), h)
+            }
+        }
+        SizeKind::WidthHeight { w, h } => (w.into(), h.into()),
     };
+    if !size.allow_upscale && (w > width || h > height) {
+        None
+    } else {
+        Some((w.max(1), h.max(1)))
+    }
+}
+
+pub fn crop_image(mut image: DynamicImage, region: &Region) -> DynamicImage {
+    if *region == Region::Full {
+        return image;
+    }
+    let (x, y, w, h) = region_rect(image.width(), image.height(), region);
     image.crop(x, y, w, h)
 }
 
@@ -35,26 +87,12 @@ pub fn resize_image(
     image: DynamicImage,
     size_req: &Size,
 ) -> Result<DynamicImage, StatusCode> {
-    let filter = FilterType::Triangle;
-    let (nw, nh) = match size_req.kind {
-        // TODO: support upscaling to maxWidth, maxHeight, maxArea, see
-        // https://iiif.io/api/image/3.0/#42-size
-        SizeKind::Max => return Ok(image),
-        SizeKind::Width(w) => (w.into(), image.height()),
-        SizeKind::Height(h) => (image.width(), h.into()),
-        SizeKind::Percent(pct) => (
-            scale_by_pct(image.width(), pct),
-            scale_by_pct(image.height(), pct),
-        ),
-        SizeKind::WidthHeight { w, h } => (w.into(), h.into()),
-    };
-
-    if !size_req.allow_upscale && nw > image.width() || nh > image.height() {
-        Err(StatusCode::BAD_REQUEST)
-    } else if size_req.maintain_ratio {
-        Ok(image.resize(nw, nh, filter))
+    let (w, h) = output_size(image.width(), image.height(), size_req)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    if (w, h) == (image.width(), image.height()) {
+        Ok(image)
     } else {
-        Ok(image.resize_exact(nw, nh, filter))
+        Ok(image.resize_exact(w, h, FilterType::Triangle))
     }
 }
 
@@ -98,3 +136,86 @@ pub fn rotate_image(image: &mut DynamicImage, rotation: &Rotation) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_rect() {
+        assert_eq!(region_rect(300, 200, &Region::Full), (0, 0, 300, 200));
+        assert_eq!(region_rect(300, 200, &Region::Square), (50, 0, 200, 200));
+        assert_eq!(region_rect(200, 300, &Region::Square), (0, 50, 200, 200));
+        let region = Region::Absolute {
+            x: 250,
+            y: 150,
+            w: 100.try_into().unwrap(),
+            h: 100.try_into().unwrap(),
+        };
+        assert_eq!(region_rect(300, 200, &region), (250, 150, 50, 50));
+        let region = Region::Percent {
+            x: 10.0,
+            y: 50.0,
+            w: 50.0,
+            h: 50.0,
+        };
+        assert_eq!(region_rect(300, 200, &region), (30, 100, 150, 100));
+    }
+
+    #[test]
+    fn test_output_size() {
+        let size = |kind, maintain_ratio, allow_upscale| Size {
+            allow_upscale,
+            maintain_ratio,
+            kind,
+        };
+        let n = |n: u32| n.try_into().unwrap();
+
+        assert_eq!(
+            output_size(300, 200, &size(SizeKind::Max, false, false)),
+            Some((300, 200))
+        );
+        assert_eq!(
+            output_size(300, 200, &size(SizeKind::Width(n(150)), false, false)),
+            Some((150, 100))
+        );
+        assert_eq!(
+            output_size(300, 200, &size(SizeKind::Height(n(50)), false, false)),
+            Some((75, 50))
+        );
+        assert_eq!(
+            output_size(300, 200, &size(SizeKind::Percent(10.0), false, false)),
+            Some((30, 20))
+        );
+        let (w, h) = (n(100), n(100));
+        assert_eq!(
+            output_size(
+                300,
+                200,
+                &size(SizeKind::WidthHeight { w, h }, true, false)
+            ),
+            Some((100, 67))
+        );
+        assert_eq!(
+            output_size(
+                300,
+                200,
+                &size(SizeKind::WidthHeight { w, h }, false, false)
+            ),
+            Some((100, 100))
+        );
+
+        // Upscaling only when asked for
+        assert_eq!(
+            output_size(300, 200, &size(SizeKind::Width(n(600)), false, false)),
+            None
+        );
+        assert_eq!(
+            output_size(300, 200, &size(SizeKind::Width(n(600)), false, true)),
+            Some((600, 400))
+        );
+
+        // Nothing to scale from a region outside the image
+        assert_eq!(output_size(0, 5, &size(SizeKind::Max, false, true)), None);
+    }
+}
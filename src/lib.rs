@@ -0,0 +1,8 @@
+pub mod api;
+pub mod config;
+pub mod image_loader;
+pub mod image_ops;
+pub mod server;
+
+pub const DEFAULT_USER_AGENT: &str =
+    concat!(env!("CARGO_PKG_NAME"), " v", env!("CARGO_PKG_VERSION"));
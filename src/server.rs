@@ -0,0 +1,451 @@
+use axum::Json;
+use axum::http::HeaderValue;
+use axum::http::header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use axum::http::{HeaderMap, status::StatusCode};
+use axum::response::ErrorResponse;
+use axum::{
+    Router,
+    extract::{Path, State},
+    response::Result,
+    routing::{delete, get, post},
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::io::{self, Cursor};
+use std::sync::Arc;
+
+use crate::api::image::{ImageRequest, Rotation};
+use crate::api::info::{ImageInfo, PageList};
+use crate::config::{Config, PrefixConfig};
+use crate::image_loader::{
+    ArchiveLoader, ChainLoader, DerivativeCache, GenericImageLoader,
+    IdentifierResolver, ImageCache, ImageCacheStats, ImageLoader, LoaderError,
+    LoaderRegistry, LocalLoader, ProxyLoader, ResolvingLoader, S3Loader,
+    SourceWatcher,
+};
+use crate::image_ops::rotate_image;
+
+#[derive(Clone)]
+struct AppState {
+    image_loaders: HashMap<String, Arc<ImageLoader>>,
+    image_cache: Arc<ImageCache>,
+    derivative_cache: Option<Arc<DerivativeCache>>,
+}
+
+fn get_loader<'a>(
+    prefix: &str,
+    app_state: &'a AppState,
+) -> Result<&'a ImageLoader, StatusCode> {
+    app_state
+        .image_loaders
+        .get(prefix)
+        .map(AsRef::as_ref)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+fn loader_error_status(
+    prefix: &str,
+    identifier: &str,
+    e: LoaderError,
+) -> StatusCode {
+    let status = match e {
+        LoaderError::NotFound => StatusCode::NOT_FOUND,
+        LoaderError::InvalidIdentifier | LoaderError::InvalidSize => {
+            StatusCode::BAD_REQUEST
+        }
+        LoaderError::Forbidden(_) => StatusCode::FORBIDDEN,
+        LoaderError::Upstream(_) | LoaderError::BadRedirect(_) => {
+            StatusCode::BAD_GATEWAY
+        }
+        LoaderError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        LoaderError::CorruptImage(_)
+        | LoaderError::UnsupportedFormat
+        | LoaderError::Io(_)
+        | LoaderError::CacheIndex(_)
+        | LoaderError::Config(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    if status.is_server_error() {
+        eprintln!("{prefix}/{identifier}: {e}");
+    }
+    status
+}
+
+#[axum::debug_handler]
+async fn get_image(
+    Path((prefix, identifier, region, size, rotation, quality_format)): Path<(
+        String,
+        String,
+        String,
+        String,
+        String,
+        String,
+    )>,
+    State(app_state): State<AppState>,
+    request_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Vec<u8>), ErrorResponse> {
+    let req: ImageRequest =
+        [identifier, region, size, rotation, quality_format]
+            .join("/")
+            .parse()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let loader = get_loader(&prefix, &app_state)?;
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        req.format
+            .to_mime_type()
+            .parse()
+            .expect("failed to parse mime type"),
+    );
+
+    let canonical = req.to_string();
+    let version = loader
+        .get_version(&prefix, &req.identifier)
+        .await
+        .map_err(|e| loader_error_status(&prefix, &req.identifier, e))?;
+    if let Some(version) = &version {
+        let etag = etag(version, &canonical);
+        let not_modified = request_headers
+            .get(IF_NONE_MATCH)
+            .is_some_and(|tags| etag_matches(tags, &etag));
+        headers.insert(ETAG, etag);
+        if not_modified {
+            return Ok((StatusCode::NOT_MODIFIED, headers, vec![]));
+        }
+    }
+
+    // Responses are only cached for images whose changes can be told
+    let derivative = app_state.derivative_cache.as_ref().zip(version);
+    if let Some((cache, version)) = &derivative {
+        match cache.get(&prefix, &canonical, version).await {
+            Ok(Some(bytes)) => return Ok((StatusCode::OK, headers, bytes)),
+            Ok(None) => (),
+            Err(e) => eprintln!("{prefix}/{canonical}: derivative cache: {e}"),
+        }
+    }
+
+    let mut image = app_state
+        .image_cache
+        .get_region(loader, &prefix, &req.identifier, &req.region, &req.size)
+        .await
+        .map_err(|e| loader_error_status(&prefix, &req.identifier, e))?;
+
+    if req.rotation != Rotation::default() {
+        rotate_image(&mut image, &req.rotation);
+    }
+
+    let mut image_data = Cursor::new(vec![]);
+    image
+        .write_to(&mut image_data, req.format)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let image_data = image_data.into_inner();
+
+    // Failing to cache the response only costs the next request the work
+    if let Some((cache, version)) = derivative {
+        let cache = Arc::clone(cache);
+        let bytes = image_data.clone();
+        let (identifier, format) = (req.identifier, req.format);
+        tokio::task::spawn_blocking(move || {
+            let stored = cache.insert(
+                &prefix,
+                &identifier,
+                &canonical,
+                &version,
+                format,
+                &bytes,
+            );
+            if let Err(e) = stored {
+                eprintln!("{prefix}/{canonical}: derivative cache: {e}");
+            }
+        });
+    }
+
+    Ok((StatusCode::OK, headers, image_data))
+}
+
+/// The entity tag of the response to `request`, made from `version` of its
+/// image.
+fn etag(version: &str, request: &str) -> HeaderValue {
+    let digest = Sha256::new()
+        .chain_update(version)
+        .chain_update([0])
+        .chain_update(request)
+        .finalize();
+    format!("\"{digest:x}\"")
+        .parse()
+        .expect("failed to parse entity tag")
+}
+
+/// Whether an If-None-Match header lists `etag`, compared weakly.
+fn etag_matches(tags: &HeaderValue, etag: &HeaderValue) -> bool {
+    let Ok(tags) = tags.to_str() else {
+        return false;
+    };
+    let etag = etag.to_str().expect("entity tags are ASCII");
+    tags.split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+async fn get_info(
+    Path((prefix, identifier)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+) -> Result<(axum::http::HeaderMap, Json<ImageInfo>), ErrorResponse> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/ld+json;profile=\"http://iiif.io/api/image/3/context.json\""));
+    let loader = get_loader(&prefix, &app_state)?;
+    let (width, height) = app_state
+        .image_cache
+        .get_dimensions(loader, &prefix, &identifier)
+        .await
+        .map_err(|e| loader_error_status(&prefix, &identifier, e))?;
+    let info = ImageInfo::new(&prefix, &identifier, width, height);
+
+    Ok((headers, Json(info)))
+}
+
+async fn get_pages(
+    Path((prefix, identifier)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+) -> Result<Json<PageList>, ErrorResponse> {
+    let pages = get_loader(&prefix, &app_state)?
+        .get_pages(&prefix, &identifier)
+        .await
+        .map_err(|e| loader_error_status(&prefix, &identifier, e))?;
+
+    Ok(Json(PageList::new(&prefix, &identifier, &pages)))
+}
+
+#[derive(Serialize)]
+struct Purged {
+    purged: usize,
+}
+
+/// Forget the upstream failures remembered by a proxy prefix, for one
+/// identifier or all of them, so that they are fetched again.
+async fn purge_failures(
+    prefix: &str,
+    identifier: Option<&str>,
+    app_state: &AppState,
+) -> Result<Json<Purged>, StatusCode> {
+    let Ok(ImageLoader::Proxy(proxy)) = get_loader(prefix, app_state) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let purged =
+        proxy
+            .purge_failures(identifier)
+            .await
+            .map_err(|e| match e {
+                LoaderError::InvalidIdentifier => StatusCode::BAD_REQUEST,
+                e => {
+                    eprintln!("{prefix}: purging failures: {e}");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            })?;
+    Ok(Json(Purged { purged }))
+}
+
+async fn purge_prefix_failures(
+    Path(prefix): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<Json<Purged>, StatusCode> {
+    purge_failures(&prefix, None, &app_state).await
+}
+
+async fn purge_identifier_failures(
+    Path((prefix, identifier)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+) -> Result<Json<Purged>, StatusCode> {
+    purge_failures(&prefix, Some(&identifier), &app_state).await
+}
+
+async fn image_cache_stats(
+    State(app_state): State<AppState>,
+) -> Json<ImageCacheStats> {
+    Json(app_state.image_cache.stats())
+}
+
+/// Read the identifier table of a prefix that resolves identifiers again.
+async fn reload_identifiers(
+    Path(prefix): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<StatusCode, StatusCode> {
+    let Ok(ImageLoader::Resolved(resolved)) = get_loader(&prefix, &app_state)
+    else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    resolved
+        .resolver()
+        .run(IdentifierResolver::reload)
+        .await
+        .map_err(|e| {
+            eprintln!("{prefix}: reloading identifiers: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Set up the loader for `prefix`, or for one tier of it.
+fn build_loader(
+    prefix: &str,
+    prefix_config: PrefixConfig,
+    registry: &LoaderRegistry,
+) -> Result<ImageLoader, LoaderError> {
+    let loader = match prefix_config {
+        PrefixConfig::Local { dir, extensions } => {
+            let mut local = LocalLoader::new();
+            local.insert_dir_with_extensions(prefix, dir, extensions);
+            ImageLoader::Local(local)
+        }
+        PrefixConfig::Proxy(proxy_config) => {
+            let proxy = ProxyLoader::new(*proxy_config)?;
+            proxy.spawn_sweeper();
+            ImageLoader::Proxy(proxy)
+        }
+        PrefixConfig::S3(s3_config) => {
+            let s3 = S3Loader::new(*s3_config)?;
+            s3.spawn_sweeper();
+            ImageLoader::S3(s3)
+        }
+        PrefixConfig::Archive { dir, extensions } => {
+            ImageLoader::Archive(ArchiveLoader::new(dir, extensions))
+        }
+        PrefixConfig::Chain { tiers, promote } => {
+            let tiers = tiers
+                .into_iter()
+                .map(|tier| build_loader(prefix, tier, registry))
+                .collect::<Result<_, _>>()?;
+            ImageLoader::Chain(ChainLoader::new(tiers, promote)?)
+        }
+        PrefixConfig::Resolved { resolver, loader } => {
+            let resolver = IdentifierResolver::new(resolver)?;
+            let loader = build_loader(prefix, *loader, registry)?;
+            ImageLoader::Resolved(ResolvingLoader::new(resolver, loader))
+        }
+        PrefixConfig::Plugin { loader, options } => {
+            ImageLoader::Plugin(registry.build(&loader, prefix, options)?)
+        }
+    };
+    Ok(loader)
+}
+
+/// Set up the loaders for the prefixes in `config`, which may use those in
+/// `registry`.
+pub fn build_loaders(
+    config: Config,
+    registry: &LoaderRegistry,
+) -> io::Result<HashMap<String, Arc<ImageLoader>>> {
+    let mut local = LocalLoader::new();
+    let mut local_prefixes = vec![];
+    let mut image_loaders = HashMap::new();
+    for (prefix, prefix_config) in config.prefixes {
+        match prefix_config {
+            PrefixConfig::Local { dir, extensions } => {
+                local.insert_dir_with_extensions(&prefix, dir, extensions);
+                local_prefixes.push(prefix);
+            }
+            prefix_config => {
+                let loader = build_loader(&prefix, prefix_config, registry)
+                    .map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("failed to set up prefix {prefix}: {e}"),
+                        )
+                    })?;
+                image_loaders.insert(prefix, Arc::new(loader));
+            }
+        }
+    }
+    // All local prefixes are served by the same loader
+    let local = Arc::new(ImageLoader::Local(local));
+    for prefix in local_prefixes {
+        image_loaders.insert(prefix, Arc::clone(&local));
+    }
+    Ok(image_loaders)
+}
+
+/// The IIIF routes and the admin routes, serving images from
+/// `image_loaders` through `image_cache`, and keeping the responses in
+/// `derivative_cache` if given. The admin routes are not authenticated, so
+/// they belong on a listener the public cannot reach.
+pub fn routers(
+    image_loaders: HashMap<String, Arc<ImageLoader>>,
+    image_cache: Arc<ImageCache>,
+    derivative_cache: Option<Arc<DerivativeCache>>,
+) -> (Router, Router) {
+    let state = AppState {
+        image_loaders,
+        image_cache,
+        derivative_cache,
+    };
+    let iiif = Router::new()
+        .route("/iiif/{prefix}/{identifier}/info.json", get(get_info))
+        .route("/iiif/{prefix}/{identifier}/pages.json", get(get_pages))
+        .route("/iiif/{prefix}/{identifier}/{region}/{size}/{rotation}/{quality_format}", get(get_image))
+        .with_state(state.clone());
+    let admin = Router::new()
+        .route("/admin/{prefix}/failures", delete(purge_prefix_failures))
+        .route(
+            "/admin/{prefix}/failures/{identifier}",
+            delete(purge_identifier_failures),
+        )
+        .route("/admin/{prefix}/identifiers", post(reload_identifiers))
+        .route("/admin/image-cache", get(image_cache_stats))
+        .with_state(state);
+    (iiif, admin)
+}
+
+/// Serve the prefixes in `config` on the address it configures, and the
+/// admin routes on the admin address if it configures one. Crates with
+/// loaders of their own register them in `registry` and start the server
+/// through this.
+pub async fn serve(
+    config: Config,
+    registry: &LoaderRegistry,
+) -> io::Result<()> {
+    let (listen, admin_listen) = (config.listen, config.admin_listen);
+    let image_cache = Arc::new(ImageCache::new(config.image_cache_bytes));
+    let derivative_cache = match config.derivative_cache.clone() {
+        Some(cache_config) => {
+            let cache = DerivativeCache::new(cache_config).map_err(|e| {
+                io::Error::other(format!(
+                    "failed to set up the derivative cache: {e}"
+                ))
+            })?;
+            cache.spawn_sweeper();
+            Some(Arc::new(cache))
+        }
+        None => None,
+    };
+    let watch_local_dirs = config.watch_local_dirs;
+    let image_loaders = build_loaders(config, registry)?;
+
+    let _watcher = watch_local_dirs
+        .then(|| {
+            SourceWatcher::new(
+                image_loaders.values().map(AsRef::as_ref),
+                Arc::clone(&image_cache),
+                derivative_cache.clone(),
+            )
+            .inspect_err(|e| eprintln!("not watching image directories: {e}"))
+            .ok()
+        })
+        .flatten();
+
+    let (iiif, admin) = routers(image_loaders, image_cache, derivative_cache);
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    let Some(admin_listen) = admin_listen else {
+        return axum::serve(listener, iiif).await;
+    };
+    let admin_listener = tokio::net::TcpListener::bind(admin_listen).await?;
+    tokio::try_join!(
+        axum::serve(listener, iiif).into_future(),
+        axum::serve(admin_listener, admin).into_future(),
+    )?;
+    Ok(())
+}